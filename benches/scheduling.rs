@@ -0,0 +1,128 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use pusu::{
+    consumer::{Consumer, ConsumerConfig, ConsumerHandle, Scheduling, consumer},
+    producer::producer,
+};
+use serde::{Deserialize, Serialize};
+
+const WORKERS: usize = 4;
+const MESSAGES: usize = 1000;
+
+#[derive(Serialize, Deserialize)]
+struct Work {
+    // How long the handler blocks, as it would on a database or another service
+    micros: u64,
+}
+
+#[consumer]
+struct Bench {
+    #[state("done")]
+    #[topic("work")]
+    work: Work,
+    done: Arc<AtomicUsize>,
+}
+
+fn work(done: Arc<AtomicUsize>, work: Work) {
+    if work.micros > 0 {
+        thread::sleep(Duration::from_micros(work.micros));
+    }
+    done.fetch_add(1, Ordering::Relaxed);
+}
+
+#[producer]
+struct Sender {
+    work: Work,
+}
+
+struct Setup {
+    handle: ConsumerHandle,
+    done: Arc<AtomicUsize>,
+    senders: Vec<Sender>,
+}
+
+fn setup(scheduling: Scheduling, connections: usize) -> Setup {
+    let done = Arc::new(AtomicUsize::new(0));
+    let bound = Bench { done: done.clone() }
+        .bind(ConsumerConfig {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            workers: WORKERS,
+            scheduling,
+            handle_signals: false,
+            ..Default::default()
+        })
+        .unwrap();
+    let addr = bound.local_addr().unwrap().to_string();
+    let handle = bound.spawn().unwrap();
+
+    let senders = (0..connections)
+        .map(|_| {
+            let mut sender = Sender::new();
+            sender.work.add_receiver(1, &addr);
+            sender
+        })
+        .collect();
+    Setup {
+        handle,
+        done,
+        senders,
+    }
+}
+
+// Sends MESSAGES split across the connections and waits until all of them are handled
+fn run(setup: &mut Setup, micros: u64, iters: u64) -> Duration {
+    let per_sender = MESSAGES / setup.senders.len();
+    let started = Instant::now();
+    for _ in 0..iters {
+        let target = setup.done.load(Ordering::Relaxed) + per_sender * setup.senders.len();
+        thread::scope(|scope| {
+            for sender in &mut setup.senders {
+                scope.spawn(move || {
+                    for _ in 0..per_sender {
+                        sender.produce_work(Work { micros }).unwrap();
+                    }
+                });
+            }
+        });
+        while setup.done.load(Ordering::Relaxed) < target {
+            thread::yield_now();
+        }
+    }
+    started.elapsed()
+}
+
+fn bench(c: &mut Criterion, name: &str, connections: usize, micros: u64) {
+    let mut group = c.benchmark_group(name);
+    group.sample_size(10);
+    for scheduling in [Scheduling::PerConnection, Scheduling::WorkStealing] {
+        let mut setup = setup(scheduling, connections);
+        group.bench_function(
+            BenchmarkId::from_parameter(format!("{:?}", scheduling)),
+            |b| b.iter_custom(|iters| run(&mut setup, micros, iters)),
+        );
+        setup.handle.shutdown();
+        setup.handle.join().unwrap();
+    }
+    group.finish();
+}
+
+// A single producer whose handlers block: per connection it runs on one worker, the others idle
+fn hot_connection(c: &mut Criterion) {
+    bench(c, "hot_connection", 1, 200);
+}
+
+// As many producers as workers with trivial handlers: the overhead of scheduling per message
+fn uniform_load(c: &mut Criterion) {
+    bench(c, "uniform_load", WORKERS, 0);
+}
+
+criterion_group!(benches, hot_connection, uniform_load);
+criterion_main!(benches);
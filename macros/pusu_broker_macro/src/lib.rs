@@ -22,6 +22,7 @@ pub fn broker(_attrs: TokenStream, input: TokenStream) -> TokenStream {
 
     let mut fields_declaration = vec![];
     let mut init_fields = vec![];
+    let mut topic_arms = vec![];
 
     for field in fields.iter() {
         let name = &field.ident;
@@ -34,6 +35,10 @@ pub fn broker(_attrs: TokenStream, input: TokenStream) -> TokenStream {
         fields_declaration.push(quote! {
             #name: pusu::broker::Topic<#ty>
         });
+
+        topic_arms.push(quote! {
+            id if id == pusu::topic::id(stringify!(#name)) => Some(&mut self.#name),
+        });
     }
 
     let expanded = quote! {
@@ -48,6 +53,15 @@ pub fn broker(_attrs: TokenStream, input: TokenStream) -> TokenStream {
                 }
             }
         }
+
+        impl pusu::broker::Broker for #struct_name {
+            fn topic(&mut self, id: u16) -> Option<&mut dyn pusu::broker::TopicLog> {
+                match id {
+                    #(#topic_arms)*
+                    _ => None,
+                }
+            }
+        }
     };
 
     TokenStream::from(expanded)
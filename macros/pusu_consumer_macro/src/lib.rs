@@ -5,14 +5,18 @@ use proc_macro::TokenStream;
 use proc_macro2::Span;
 use quote::quote;
 use syn::{
-    Fields, FieldsNamed, Ident, ItemStruct, LitStr, Meta, Type, TypeTuple, Variant, Visibility,
+    Attribute, Expr, ExprLit, Fields, FieldsNamed, Ident, ItemStruct, Lifetime, Lit, LitInt,
+    LitStr, Meta, MetaList, MetaNameValue, Type, TypeTuple, Variant, Visibility,
+    parse::{Parse, ParseStream},
     parse_macro_input, parse_quote, parse2,
     punctuated::Punctuated,
     token::{Comma, Enum},
+    visit::Visit,
 };
 
 #[proc_macro_attribute]
-pub fn consumer(_attr: TokenStream, item: TokenStream) -> TokenStream {
+pub fn consumer(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let is_async = is_async(attr);
     let input = parse_macro_input!(item as ItemStruct);
     let struct_name = &input.ident;
 
@@ -26,21 +30,186 @@ pub fn consumer(_attr: TokenStream, item: TokenStream) -> TokenStream {
     let mut cleaned_fields = Punctuated::new();
     let mut deserialize_switch = Vec::new();
     let mut enum_variants = Punctuated::<Variant, Comma>::new();
+    let mut topics = Vec::new();
+    let mut retry_arms = Vec::new();
+    let mut has_retry = false;
+    let mut key_arms = Vec::new();
+    let mut keyed_topics = Vec::new();
+    let mut has_key = false;
+    let mut batch_arms = Vec::new();
+    let mut batch_dispatch_arms = Vec::new();
+    let mut limit_arms = Vec::new();
+
+    // #[consumer(async)] takes async handlers and implements AsyncConsumer, from the tokio feature
+    let (asyncness, awaited, consumer_trait, handler_trait) = if is_async {
+        (
+            quote! { async },
+            quote! { .await },
+            quote! { AsyncConsumer },
+            quote! { AsyncHandler },
+        )
+    } else {
+        (
+            quote! {},
+            quote! {},
+            quote! { Consumer },
+            quote! { Handler },
+        )
+    };
 
     let enum_name = format!("{}Topic", struct_name);
     let enum_ident = Ident::new(&enum_name, Span::call_site());
 
+    // #[middleware] fields, their hooks run in declaration order
+    let middlewares = fields
+        .iter()
+        .filter(|field| {
+            field
+                .attrs
+                .iter()
+                .any(|attr| attr.path().is_ident("middleware"))
+        })
+        .map(|field| field.ident.clone().unwrap())
+        .collect::<Vec<_>>();
+    let before_decode = quote! {
+        #(
+            if let Err(err) = pusu::consumer::Middleware::<#enum_ident>::before_decode(
+                &self.#middlewares,
+                topic,
+                payload_bytes,
+                context,
+            ) {
+                break 'dispatch Err(err);
+            }
+        )*
+    };
+    let after_decode = quote! {
+        #(
+            if let Err(err) = pusu::consumer::Middleware::<#enum_ident>::after_decode(
+                &self.#middlewares,
+                topic,
+                context,
+            ) {
+                break 'dispatch Err(err);
+            }
+        )*
+    };
+
+    // #[dedup] field, the store of acked messages
+    let dedup_fields = fields
+        .iter()
+        .filter(|field| field.attrs.iter().any(|attr| attr.path().is_ident("dedup")))
+        .map(|field| field.ident.clone().unwrap())
+        .collect::<Vec<_>>();
+    let dedup_store = match dedup_fields.as_slice() {
+        [] => quote! {},
+        [store] => quote! {
+            fn dedup_store(&self) -> Option<&dyn pusu::consumer::DedupStore> {
+                Some(&self.#store)
+            }
+        },
+        _ => panic!("Only one #[dedup] field is allowed"),
+    };
+
+    // #[fallback("handler")] on the struct, given the topic and payload of the messages of unknown
+    // topics and of those that don't decode
+    let fallbacks = input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("fallback"))
+        .map(|attr| {
+            let lit = attr
+                .parse_args::<LitStr>()
+                .unwrap_or_else(|err| panic!("Invalid fallback attribute: {}", err));
+            Ident::new(&lit.value(), lit.span())
+        })
+        .collect::<Vec<_>>();
+    let (fallback, on_decode_error) = match fallbacks.as_slice() {
+        [] => (quote! {}, quote! {}),
+        [handler] => (
+            quote! {
+                fn has_fallback(&self) -> bool {
+                    true
+                }
+
+                #asyncness fn fallback(&self, topic: &str, payload_bytes: &[u8]) -> anyhow::Result<()> {
+                    pusu::consumer::HandlerResult::into_result(#handler(topic, payload_bytes)#awaited)
+                }
+            },
+            quote! {
+                let result = match result {
+                    Err(pusu::consumer::DispatchError::Decode(_)) => self
+                        .fallback(pusu::topic::TopicEnum::name(&topic), payload_bytes)
+                        #awaited
+                        .map_err(pusu::consumer::DispatchError::Handler),
+                    result => result,
+                };
+            },
+        ),
+        _ => panic!("Only one #[fallback] attribute is allowed"),
+    };
+
+    // #[dead_letter("handler")] on the struct, given the messages nacked for good with their context
+    // and error
+    let dead_letters = input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("dead_letter"))
+        .map(|attr| {
+            let lit = attr
+                .parse_args::<LitStr>()
+                .unwrap_or_else(|err| panic!("Invalid dead_letter attribute: {}", err));
+            Ident::new(&lit.value(), lit.span())
+        })
+        .collect::<Vec<_>>();
+    let dead_letter = match dead_letters.as_slice() {
+        [] => quote! {},
+        [handler] => quote! {
+            #asyncness fn dead_letter(
+                &self,
+                topic: #enum_ident,
+                payload_bytes: &[u8],
+                context: &pusu::consumer::MessageContext,
+                err: &anyhow::Error,
+            ) -> anyhow::Result<()> {
+                pusu::consumer::HandlerResult::into_result(
+                    #handler(pusu::topic::TopicEnum::name(&topic), payload_bytes, context, err)#awaited
+                )
+            }
+        },
+        _ => panic!("Only one #[dead_letter] attribute is allowed"),
+    };
+
     for field in fields {
         let name = field.ident.as_ref().unwrap();
-        let mut handler_ident = None;
+        let mut handlers = Vec::new();
+        let mut parallel = false;
         let mut state_ident = None;
+        let mut archived = false;
+        let mut retry = None;
+        let mut key_source = None;
+        let mut batch = None;
+        let mut per_sec = None;
+        let mut max_concurrency = None;
 
         for attr in &field.attrs {
-            if attr.path().is_ident("topic")
-                && let Meta::List(meta) = &attr.meta
-                && let Ok(lit) = parse2::<LitStr>(meta.tokens.clone())
-            {
-                handler_ident = Some((Ident::new(&lit.value(), lit.span()), &field.ty));
+            // #[topic("a", "b")] and repeated #[topic(...)] attributes add handlers to the topic
+            if attr.path().is_ident("topic") {
+                let args = attr
+                    .parse_args_with(Punctuated::<TopicArg, Comma>::parse_terminated)
+                    .unwrap_or_else(|err| panic!("Invalid topic attribute: {}", err));
+                for arg in args {
+                    match arg {
+                        TopicArg::Handler(lit) => {
+                            handlers.push(Ident::new(&lit.value(), lit.span()))
+                        }
+                        TopicArg::Batch(list) => batch = Some(batch_policy(list.as_ref())),
+                    }
+                }
+            }
+
+            if attr.path().is_ident("parallel") {
+                parallel = true;
             }
 
             if attr.path().is_ident("state")
@@ -49,35 +218,157 @@ pub fn consumer(_attr: TokenStream, item: TokenStream) -> TokenStream {
             {
                 state_ident = Some(Ident::new(&lit.value(), lit.span()));
             }
+
+            if attr.path().is_ident("key") {
+                key_source = Some(
+                    attr.parse_args::<KeyArg>()
+                        .unwrap_or_else(|err| panic!("Invalid key attribute: {}", err)),
+                );
+            }
+
+            if attr.path().is_ident("archived") {
+                archived = true;
+            }
+
+            if attr.path().is_ident("retry") {
+                retry = Some(retry_policy(attr));
+            }
+
+            if attr.path().is_ident("rate_limit") {
+                per_sec = Some(rate_limit(attr));
+            }
+
+            if attr.path().is_ident("max_concurrency") {
+                let max = attr
+                    .parse_args::<LitInt>()
+                    .and_then(|int| int.base10_parse::<usize>())
+                    .unwrap_or_else(|err| panic!("Invalid max_concurrency attribute: {}", err));
+                if max == 0 {
+                    panic!("max_concurrency must be at least 1");
+                }
+                max_concurrency = Some(max);
+            }
         }
 
-        if let Some((handler, ty)) = handler_ident {
+        if !handlers.is_empty() {
+            let ty = &field.ty;
             let consume_name = Ident::new(&format!("consume_{}", name), name.span());
 
             let is_unit_type = is_unit(ty);
 
-            let params = if is_unit_type {
-                quote! { &self }
+            if batch.is_some() {
+                if is_async {
+                    panic!("Batch handlers are not supported by async consumers");
+                }
+                if is_unit_type || archived {
+                    panic!(
+                        "Batch handlers take a Vec of decoded values, not unit or archived ones"
+                    );
+                }
+                if handlers.len() > 1 {
+                    panic!("A batch topic takes a single handler");
+                }
+            }
+
+            let params = if batch.is_some() {
+                quote! { &self, values: Vec<#ty> }
+            } else if is_unit_type {
+                quote! { &self, context: &pusu::consumer::MessageContext }
+            } else if archived {
+                quote! { &self, value: &rkyv::Archived<#ty>, context: &pusu::consumer::MessageContext }
             } else {
-                quote! { &self, value: #ty }
+                quote! { &self, value: #ty, context: &pusu::consumer::MessageContext }
             };
 
-            let call = match state_ident {
-                Some(state) => {
+            // Borrowed payload types like User<'a> get their lifetimes declared on the method
+            let lifetimes = lifetimes(ty);
+
+            // Every handler but the last one gets a clone of the value, archived values are
+            // references and are simply copied
+            let values = (0..handlers.len())
+                .map(|i| Ident::new(&format!("value_{}", i), Span::call_site()))
+                .collect::<Vec<_>>();
+            let last = values.len() - 1;
+            let bindings = values.iter().enumerate().map(|(i, binding)| {
+                if is_unit_type {
+                    quote! {}
+                } else if i == last || archived {
+                    quote! { let #binding = value; }
+                } else {
+                    quote! { let #binding = value.clone(); }
+                }
+            });
+
+            // Handler arguments, &MessageContext is passed as well if the handler takes it
+            let calls = handlers
+                .iter()
+                .zip(&values)
+                .map(|(handler, binding)| {
+                    let mut args = Vec::new();
+                    if let Some(state) = &state_ident {
+                        args.push(quote! { self.#state.clone() });
+                    }
                     if !is_unit_type {
-                        quote! { self.#state.clone(), value }
-                    } else {
-                        quote! { self.#state.clone() }
+                        args.push(quote! { #binding });
                     }
+                    quote! { pusu::consumer::#handler_trait::call(&#handler, (#(#args,)*), context) }
+                })
+                .collect::<Vec<_>>();
+
+            let body = if batch.is_some() {
+                // Batch handlers are called directly, there is no single context to give them
+                let handler = &handlers[0];
+                let state = state_ident.iter();
+                quote! {
+                    pusu::consumer::HandlerResult::into_result(#handler(#(self.#state.clone(),)* values))
+                }
+            } else if let [call] = calls.as_slice() {
+                quote! {
+                    #(#bindings)*
+                    pusu::consumer::HandlerResult::into_result(#call #awaited)
+                }
+            } else if !parallel {
+                quote! {
+                    #(#bindings)*
+                    pusu::consumer::join_results([
+                        #(pusu::consumer::HandlerResult::into_result(#calls #awaited)),*
+                    ])
+                }
+            } else if is_async {
+                quote! {
+                    #(#bindings)*
+                    let (#(#values,)*) = tokio::join!(#(#calls),*);
+                    pusu::consumer::join_results([
+                        #(pusu::consumer::HandlerResult::into_result(#values)),*
+                    ])
+                }
+            } else {
+                // The first handler runs on the worker while the others get a scoped thread each
+                let first = &calls[0];
+                let others = &calls[1..];
+                quote! {
+                    #(#bindings)*
+                    std::thread::scope(|scope| {
+                        let others = [
+                            #(scope.spawn(move || pusu::consumer::HandlerResult::into_result(#others))),*
+                        ];
+                        let first = pusu::consumer::HandlerResult::into_result(#first);
+                        pusu::consumer::join_results(
+                            std::iter::once(first).chain(others.into_iter().map(|handle| {
+                                handle
+                                    .join()
+                                    .unwrap_or_else(|_| Err(anyhow::anyhow!("Handler panicked")))
+                            })),
+                        )
+                    })
                 }
-                None => quote! { value },
             };
 
             let method = {
                 quote! {
                     #[inline]
-                    fn #consume_name(#params) {
-                        #handler(#call);
+                    #asyncness fn #consume_name<#(#lifetimes),*>(#params) -> anyhow::Result<()> {
+                        #body
                     }
                 }
             };
@@ -90,19 +381,171 @@ pub fn consumer(_attr: TokenStream, item: TokenStream) -> TokenStream {
                 attrs: Vec::new(),
                 ident: variant_ident.clone(),
                 fields: Fields::Unit,
-                discriminant: None,
+                discriminant: Some((parse_quote!(=), parse_quote!(pusu::topic::id(#topic_str)))),
             };
 
             enum_variants.push(enum_variant);
+            topics.push((variant_ident.clone(), topic_str));
 
-            let switch_stmt = if !is_unit_type {
+            let decode = if archived {
+                quote! { rkyv::access::<rkyv::Archived<#ty>, rkyv::rancor::Error>(payload_bytes) }
+            } else {
+                quote! { postcard::from_bytes(payload_bytes) }
+            };
+
+            let switch_stmt = if batch.is_some() {
                 quote! {
-                    self.#consume_name(postcard::from_bytes(payload_bytes)?);
+                    let value = match #decode {
+                        Ok(value) => value,
+                        Err(err) => break 'dispatch Err(pusu::consumer::DispatchError::decode(err)),
+                    };
+                    #after_decode
+                    self.#consume_name(vec![value])
+                        .map_err(pusu::consumer::DispatchError::Handler)
+                }
+            } else if is_unit_type {
+                quote! {
+                    #after_decode
+                    self.#consume_name(context)#awaited
+                        .map_err(pusu::consumer::DispatchError::Handler)
                 }
             } else {
-                quote! { self.#consume_name(); }
+                quote! {
+                    let value = match #decode {
+                        Ok(value) => value,
+                        Err(err) => break 'dispatch Err(pusu::consumer::DispatchError::decode(err)),
+                    };
+                    #after_decode
+                    self.#consume_name(value, context)#awaited
+                        .map_err(pusu::consumer::DispatchError::Handler)
+                }
             };
 
+            retry_arms.push(match retry {
+                Some(policy) => {
+                    has_retry = true;
+                    quote! { #enum_ident::#variant_ident => Some(#policy), }
+                }
+                None => quote! { #enum_ident::#variant_ident => None, },
+            });
+
+            if key_source.is_some() {
+                keyed_topics.push(quote! { #enum_ident::#variant_ident });
+            }
+            // The key function takes the decoded value, so its type is inferred from the function
+            key_arms.push(match key_source {
+                Some(_) if is_async => {
+                    panic!("Routing by key is not supported by async consumers")
+                }
+                Some(KeyArg::Header(name)) => {
+                    has_key = true;
+                    quote! {
+                        #enum_ident::#variant_ident => context
+                            .header(#name)
+                            .map(pusu::consumer::key_hash),
+                    }
+                }
+                Some(KeyArg::Function(_)) if is_unit_type => {
+                    panic!("Unit topics have no payload to take a key from, use #[key(header = \"...\")]")
+                }
+                Some(KeyArg::Function(key)) => {
+                    has_key = true;
+                    // Archived values are already references
+                    let value = if archived {
+                        quote! { value }
+                    } else {
+                        quote! { &value }
+                    };
+                    quote! {
+                        #enum_ident::#variant_ident => #decode
+                            .ok()
+                            .map(|value| pusu::consumer::key_hash(&#key(#value))),
+                    }
+                }
+                None => quote! { #enum_ident::#variant_ident => None, },
+            });
+
+            if per_sec.is_some() || max_concurrency.is_some() {
+                if is_async {
+                    panic!("Rate limits and concurrency caps are not supported by async consumers");
+                }
+                if batch.is_some() && max_concurrency.is_some() {
+                    panic!("Concurrency caps are not supported on batch topics");
+                }
+                let per_sec = match per_sec {
+                    Some(per_sec) => quote! { Some(#per_sec) },
+                    None => quote! { None },
+                };
+                let max_concurrency = match max_concurrency {
+                    Some(max) => quote! { Some(#max) },
+                    None => quote! { None },
+                };
+                limit_arms.push(quote! {
+                    #enum_ident::#variant_ident => Some(pusu::consumer::TopicLimits {
+                        per_sec: #per_sec,
+                        max_concurrency: #max_concurrency,
+                    }),
+                });
+            }
+
+            // Each message goes through the middlewares, the decoded ones are handled at once and
+            // share the outcome of the handler
+            if let Some(policy) = batch {
+                batch_arms.push(quote! { #enum_ident::#variant_ident => Some(#policy), });
+                batch_dispatch_arms.push(quote! {
+                    #enum_ident::#variant_ident => {
+                        let started = std::time::Instant::now();
+                        let mut results = Vec::with_capacity(payloads.len());
+                        let mut values = Vec::with_capacity(payloads.len());
+                        let mut decoded = Vec::with_capacity(payloads.len());
+                        for (i, (payload_bytes, context)) in payloads.iter().copied().zip(contexts).enumerate() {
+                            let value = 'dispatch: {
+                                #before_decode
+                                let value = match #decode {
+                                    Ok(value) => value,
+                                    Err(err) => break 'dispatch Err(pusu::consumer::DispatchError::decode(err)),
+                                };
+                                #after_decode
+                                Ok(value)
+                            };
+                            let result = match value {
+                                Ok(value) => {
+                                    values.push(value);
+                                    decoded.push(i);
+                                    Ok(())
+                                }
+                                Err(err) => Err(err),
+                            };
+                            #on_decode_error
+                            results.push(result);
+                        }
+                        if !values.is_empty()
+                            && let Err(err) = self.#consume_name(values)
+                        {
+                            let err = format!("{:#}", err);
+                            for i in decoded {
+                                results[i] = Err(pusu::consumer::DispatchError::Handler(
+                                    anyhow::anyhow!("{}", err),
+                                ));
+                            }
+                        }
+                        let elapsed = started.elapsed();
+                        #(
+                            for (result, context) in results.iter().zip(contexts) {
+                                pusu::consumer::Middleware::<#enum_ident>::after_handler(
+                                    &self.#middlewares,
+                                    topic,
+                                    result,
+                                    elapsed,
+                                    context,
+                                );
+                            }
+                        )*
+                        results
+                    }
+                });
+            }
+
             deserialize_switch.push(quote! {
                 #enum_ident::#variant_ident => {
                     #switch_stmt
@@ -111,15 +554,26 @@ pub fn consumer(_attr: TokenStream, item: TokenStream) -> TokenStream {
             consume_methods.push(method);
         }
 
-        if !field.attrs.iter().any(|attr| attr.path().is_ident("topic")) {
+        if field
+            .attrs
+            .iter()
+            .any(|attr| attr.path().is_ident("middleware") || attr.path().is_ident("dedup"))
+        {
+            let mut field = field.clone();
+            field.attrs.retain(|attr| {
+                !attr.path().is_ident("middleware") && !attr.path().is_ident("dedup")
+            });
+            cleaned_fields.push(field);
+        } else if !field.attrs.iter().any(|attr| attr.path().is_ident("topic")) {
             cleaned_fields.push(field.clone());
         }
     }
 
     let attrs = vec![
         parse_quote! {
-        #[derive(strum::EnumString)]},
+        #[derive(Clone, Copy, strum::EnumString)]},
         parse_quote! {#[strum(serialize_all = "snake_case")]},
+        parse_quote! {#[repr(u16)]},
     ];
 
     let dispatcher_enum = syn::ItemEnum {
@@ -137,7 +591,13 @@ pub fn consumer(_attr: TokenStream, item: TokenStream) -> TokenStream {
     };
 
     let output_struct = ItemStruct {
-        attrs: input.attrs,
+        attrs: input
+            .attrs
+            .into_iter()
+            .filter(|attr| {
+                !attr.path().is_ident("fallback") && !attr.path().is_ident("dead_letter")
+            })
+            .collect(),
         vis: input.vis,
         struct_token: input.struct_token,
         ident: input.ident.clone(),
@@ -149,20 +609,157 @@ pub fn consumer(_attr: TokenStream, item: TokenStream) -> TokenStream {
         semi_token: input.semi_token,
     };
 
-    let dispatcher = quote! {
-        impl pusu::consumer::Consumer<#enum_ident> for #struct_name {
-            fn dispatch(&self, topic: #enum_ident, payload_bytes: &[u8]) -> anyhow::Result<()> {
+    let retry_policy = if has_retry {
+        quote! {
+            fn retry_policy(&self, topic: #enum_ident) -> Option<pusu::consumer::RetryPolicy> {
+                match topic {
+                    #(#retry_arms)*
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    let route_key = if has_key {
+        quote! {
+            fn routes_by_key(&self, topic: #enum_ident) -> bool {
+                matches!(topic, #(#keyed_topics)|*)
+            }
+
+            #[allow(unused_variables)]
+            fn route_key(
+                &self,
+                topic: #enum_ident,
+                payload_bytes: &[u8],
+                context: &pusu::consumer::MessageContext,
+            ) -> Option<u64> {
                 match topic {
-                    #(#deserialize_switch)*
+                    #(#key_arms)*
                 }
-                Ok(())
             }
         }
+    } else {
+        quote! {}
     };
 
+    let batch = if batch_arms.is_empty() {
+        quote! {}
+    } else {
+        quote! {
+            fn batch_policy(&self, topic: #enum_ident) -> Option<pusu::consumer::BatchPolicy> {
+                #[allow(unreachable_patterns)]
+                match topic {
+                    #(#batch_arms)*
+                    _ => None,
+                }
+            }
+
+            fn dispatch_batch(
+                &self,
+                topic: #enum_ident,
+                payloads: &[&[u8]],
+                contexts: &[pusu::consumer::MessageContext],
+            ) -> Vec<Result<(), pusu::consumer::DispatchError>> {
+                #[allow(unreachable_patterns)]
+                match topic {
+                    #(#batch_dispatch_arms)*
+                    _ => payloads
+                        .iter()
+                        .zip(contexts)
+                        .map(|(payload, context)| self.dispatch(topic, payload, context))
+                        .collect(),
+                }
+            }
+        }
+    };
+
+    let topic_limits = if limit_arms.is_empty() {
+        quote! {}
+    } else {
+        quote! {
+            fn topic_limits(&self, topic: #enum_ident) -> Option<pusu::consumer::TopicLimits> {
+                #[allow(unreachable_patterns)]
+                match topic {
+                    #(#limit_arms)*
+                    _ => None,
+                }
+            }
+        }
+    };
+
+    let dispatch = quote! {
+        match topic {
+            #(#deserialize_switch)*
+        }
+    };
+
+    // Middlewares run their hooks around the match, the arms break out of it on a failed decode
+    let dispatch_body = if middlewares.is_empty() {
+        quote! {
+            #[allow(unused_labels)]
+            let result: Result<(), pusu::consumer::DispatchError> = 'dispatch: {
+                #dispatch
+            };
+            #on_decode_error
+            result
+        }
+    } else {
+        quote! {
+            let started = std::time::Instant::now();
+            let result: Result<(), pusu::consumer::DispatchError> = 'dispatch: {
+                #before_decode
+                #dispatch
+            };
+            #on_decode_error
+            let elapsed = started.elapsed();
+            #(
+                pusu::consumer::Middleware::<#enum_ident>::after_handler(
+                    &self.#middlewares,
+                    topic,
+                    &result,
+                    elapsed,
+                    context,
+                );
+            )*
+            result
+        }
+    };
+
+    let dispatcher = quote! {
+        impl pusu::consumer::#consumer_trait<#enum_ident> for #struct_name {
+            #retry_policy
+
+            #route_key
+
+            #dedup_store
+
+            #fallback
+
+            #dead_letter
+
+            #batch
+
+            #topic_limits
+
+            #asyncness fn dispatch(
+                &self,
+                topic: #enum_ident,
+                payload_bytes: &[u8],
+                context: &pusu::consumer::MessageContext,
+            ) -> Result<(), pusu::consumer::DispatchError> {
+                #dispatch_body
+            }
+        }
+    };
+
+    let topic_enum_impl = topic_enum_impl(&enum_ident, &topics);
+
     let expanded = quote! {
         #dispatcher_enum
 
+        #topic_enum_impl
+
         #output_struct
 
         #dispatcher
@@ -175,9 +772,223 @@ pub fn consumer(_attr: TokenStream, item: TokenStream) -> TokenStream {
     TokenStream::from(expanded)
 }
 
+fn is_async(attr: TokenStream) -> bool {
+    match attr.to_string().as_str() {
+        "" => false,
+        "async" => true,
+        other => panic!("Unknown consumer argument {}, expected async", other),
+    }
+}
+
 fn is_unit(ty: &Type) -> bool {
     match ty {
         Type::Tuple(TypeTuple { elems, .. }) => elems.is_empty(),
         _ => false,
     }
 }
+
+// #[key("function")] takes the key from the decoded value, #[key(header = "name")] from a header
+enum KeyArg {
+    Function(Ident),
+    Header(LitStr),
+}
+
+impl Parse for KeyArg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.peek(LitStr) {
+            let lit = input.parse::<LitStr>()?;
+            return Ok(KeyArg::Function(Ident::new(&lit.value(), lit.span())));
+        }
+        let arg = input.parse::<MetaNameValue>()?;
+        match &arg.value {
+            Expr::Lit(ExprLit {
+                lit: Lit::Str(name),
+                ..
+            }) if arg.path.is_ident("header") => Ok(KeyArg::Header(name.clone())),
+            _ => Err(syn::Error::new_spanned(
+                arg,
+                "Expected a function name or header = \"name\"",
+            )),
+        }
+    }
+}
+
+// Argument of #[topic(...)]: a handler name, or batch(max = 500, linger_ms = 20) with optional
+// arguments
+enum TopicArg {
+    Handler(LitStr),
+    Batch(Option<MetaList>),
+}
+
+impl Parse for TopicArg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.peek(LitStr) {
+            return Ok(TopicArg::Handler(input.parse()?));
+        }
+        match input.parse::<Meta>()? {
+            Meta::Path(path) if path.is_ident("batch") => Ok(TopicArg::Batch(None)),
+            Meta::List(list) if list.path.is_ident("batch") => Ok(TopicArg::Batch(Some(list))),
+            meta => Err(syn::Error::new_spanned(
+                meta,
+                "Expected a handler name or batch(...)",
+            )),
+        }
+    }
+}
+
+fn batch_policy(list: Option<&MetaList>) -> proc_macro2::TokenStream {
+    let mut max = 100usize;
+    let mut linger_ms = 10u64;
+
+    if let Some(list) = list {
+        let args = list
+            .parse_args_with(Punctuated::<MetaNameValue, Comma>::parse_terminated)
+            .unwrap_or_else(|err| panic!("Invalid batch arguments: {}", err));
+
+        for arg in args {
+            let Expr::Lit(ExprLit {
+                lit: Lit::Int(int), ..
+            }) = &arg.value
+            else {
+                panic!("Batch arguments must be integers");
+            };
+            if arg.path.is_ident("max") {
+                max = int.base10_parse().expect("Invalid batch max");
+            } else if arg.path.is_ident("linger_ms") {
+                linger_ms = int.base10_parse().expect("Invalid batch linger_ms");
+            } else {
+                panic!("Unknown batch argument, expected max or linger_ms");
+            }
+        }
+    }
+    if max == 0 {
+        panic!("Batch max must be at least 1");
+    }
+
+    quote! {
+        pusu::consumer::BatchPolicy {
+            max: #max,
+            linger: std::time::Duration::from_millis(#linger_ms),
+        }
+    }
+}
+
+// #[rate_limit(per_sec = 100)]
+fn rate_limit(attr: &Attribute) -> u32 {
+    let arg = attr
+        .parse_args::<MetaNameValue>()
+        .unwrap_or_else(|err| panic!("Invalid rate_limit attribute: {}", err));
+    let Expr::Lit(ExprLit {
+        lit: Lit::Int(int), ..
+    }) = &arg.value
+    else {
+        panic!("Rate limit must be an integer");
+    };
+    if !arg.path.is_ident("per_sec") {
+        panic!("Unknown rate_limit argument, expected per_sec");
+    }
+    let per_sec = int.base10_parse().expect("Invalid rate_limit per_sec");
+    if per_sec == 0 {
+        panic!("Rate limit must be at least 1 per second");
+    }
+    per_sec
+}
+
+// #[retry(max = 5, backoff = "exp", base_ms = 100)], every argument is optional
+fn retry_policy(attr: &Attribute) -> proc_macro2::TokenStream {
+    let mut max_retries = 3u32;
+    let mut backoff = String::from("exp");
+    let mut base_ms = 100u64;
+
+    if let Meta::List(_) = &attr.meta {
+        let args = attr
+            .parse_args_with(Punctuated::<MetaNameValue, Comma>::parse_terminated)
+            .unwrap_or_else(|err| panic!("Invalid retry attribute: {}", err));
+
+        for arg in args {
+            let Expr::Lit(ExprLit { lit, .. }) = &arg.value else {
+                panic!("Retry arguments must be literals");
+            };
+
+            match lit {
+                Lit::Int(int) if arg.path.is_ident("max") => {
+                    max_retries = int.base10_parse().expect("Invalid retry max");
+                }
+                Lit::Int(int) if arg.path.is_ident("base_ms") => {
+                    base_ms = int.base10_parse().expect("Invalid retry base_ms");
+                }
+                Lit::Str(str) if arg.path.is_ident("backoff") => backoff = str.value(),
+                _ => panic!("Unknown retry argument, expected max, backoff or base_ms"),
+            }
+        }
+    }
+
+    let backoff = match backoff.as_str() {
+        "fixed" => quote! { Fixed },
+        "linear" => quote! { Linear },
+        "exp" | "exponential" => quote! { Exponential },
+        other => panic!("Unknown backoff {}, expected fixed, linear or exp", other),
+    };
+
+    quote! {
+        pusu::consumer::RetryPolicy {
+            max_retries: #max_retries,
+            backoff: pusu::consumer::Backoff::#backoff,
+            base: std::time::Duration::from_millis(#base_ms),
+        }
+    }
+}
+
+fn topic_enum_impl(enum_ident: &Ident, topics: &[(Ident, String)]) -> proc_macro2::TokenStream {
+    let variants = topics
+        .iter()
+        .map(|(variant, _)| variant)
+        .collect::<Vec<_>>();
+    let names = topics.iter().map(|(_, name)| name).collect::<Vec<_>>();
+    let consts = topics
+        .iter()
+        .map(|(_, name)| Ident::new(&name.to_case(Case::UpperSnake), Span::call_site()))
+        .collect::<Vec<_>>();
+
+    quote! {
+        impl pusu::topic::TopicEnum for #enum_ident {
+            const TOPICS: &'static [(&'static str, u16)] = &[
+                #((#names, #enum_ident::#variants as u16)),*
+            ];
+
+            fn id(&self) -> u16 {
+                *self as u16
+            }
+
+            fn name(&self) -> &'static str {
+                match self {
+                    #(#enum_ident::#variants => #names,)*
+                }
+            }
+
+            fn from_id(id: u16) -> Option<Self> {
+                #(const #consts: u16 = #enum_ident::#variants as u16;)*
+                match id {
+                    #(#consts => Some(#enum_ident::#variants),)*
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+fn lifetimes(ty: &Type) -> Vec<Lifetime> {
+    struct LifetimeVisitor(Vec<Lifetime>);
+
+    impl<'ast> Visit<'ast> for LifetimeVisitor {
+        fn visit_lifetime(&mut self, lifetime: &'ast Lifetime) {
+            if lifetime.ident != "static" && lifetime.ident != "_" && !self.0.contains(lifetime) {
+                self.0.push(lifetime.clone());
+            }
+        }
+    }
+
+    let mut visitor = LifetimeVisitor(Vec::new());
+    visitor.visit_type(ty);
+    visitor.0
+}
@@ -1,6 +1,6 @@
 extern crate proc_macro;
 
-use convert_case::Casing;
+use convert_case::{Case, Casing};
 use proc_macro::TokenStream;
 use proc_macro2::Span;
 use quote::{ToTokens, quote};
@@ -12,7 +12,8 @@ use syn::{
 };
 
 #[proc_macro_attribute]
-pub fn producer(_attr: TokenStream, item: TokenStream) -> TokenStream {
+pub fn producer(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let is_async = is_async(attr);
     let input = parse_macro_input!(item as ItemStruct);
     let struct_name = &input.ident;
 
@@ -26,6 +27,24 @@ pub fn producer(_attr: TokenStream, item: TokenStream) -> TokenStream {
     let mut map_fields = Punctuated::new();
     let mut dispatcher_switches = Vec::new();
     let mut enum_variants = Punctuated::<Variant, Comma>::new();
+    let mut topics = Vec::new();
+
+    // #[producer(async)] generates async produce_* methods, from the tokio feature
+    let (asyncness, awaited, send, send_raw) = if is_async {
+        (
+            quote! { async },
+            quote! { .await },
+            quote! { send_with_headers_async },
+            quote! { send_raw_with_headers_async },
+        )
+    } else {
+        (
+            quote! {},
+            quote! {},
+            quote! { send_with_headers },
+            quote! { send_raw_with_headers },
+        )
+    };
 
     let enum_name = format!("{}Topic", struct_name);
     let enum_ident = Ident::new(&enum_name, Span::call_site());
@@ -35,34 +54,64 @@ pub fn producer(_attr: TokenStream, item: TokenStream) -> TokenStream {
         let ty = &field.ty;
 
         let produce_name = Ident::new(&format!("produce_{}", name), name.span());
+        let produce_with_headers_name =
+            Ident::new(&format!("produce_{}_with_headers", name), name.span());
 
         let topic_lit = LitStr::new(&name.to_string(), name.span());
         let topic_str = topic_lit.value();
 
-        let (params_tokens, value_tokens) = if is_unit(ty) {
-            (quote! { #topic_str, &() }, quote! {&mut self})
+        let variant_ident = Ident::new(&topic_str.to_case(Case::Pascal), Span::call_site());
+
+        let archived = field
+            .attrs
+            .iter()
+            .any(|attr| attr.path().is_ident("archived"));
+
+        let (send_tokens, value_tokens, value) = if is_unit(ty) {
+            (
+                quote! { #send(#topic_str, #enum_ident::#variant_ident as u16, &(), headers) },
+                quote! {&mut self},
+                quote! {},
+            )
+        } else if archived {
+            (
+                quote! {
+                    #send_raw(
+                        #topic_str,
+                        #enum_ident::#variant_ident as u16,
+                        &rkyv::to_bytes::<rkyv::rancor::Error>(&value)?,
+                        headers,
+                    )
+                },
+                quote! {&mut self, value: #ty},
+                quote! { value, },
+            )
         } else {
             (
-                quote! { #topic_str, &value },
+                quote! { #send(#topic_str, #enum_ident::#variant_ident as u16, &value, headers) },
                 quote! {&mut self, value: #ty},
+                quote! { value, },
             )
         };
 
         let produce_method = quote! {
-            fn #produce_name(#value_tokens) -> anyhow::Result<()> {
-                self.#name.send(#params_tokens)
+            #asyncness fn #produce_name(#value_tokens) -> anyhow::Result<()> {
+                self.#produce_with_headers_name(#value &pusu::producer::Headers::new())#awaited
+            }
+
+            #asyncness fn #produce_with_headers_name(
+                #value_tokens,
+                headers: &pusu::producer::Headers,
+            ) -> anyhow::Result<()> {
+                self.#name.#send_tokens #awaited
             }
         };
 
-        let variant_ident = Ident::new(
-            &topic_str.to_case(convert_case::Case::Pascal),
-            Span::call_site(),
-        );
         let enum_variant = Variant {
             attrs: Vec::new(),
             ident: variant_ident.clone(),
             fields: Fields::Unit,
-            discriminant: None,
+            discriminant: Some((parse_quote!(=), parse_quote!(pusu::topic::id(#topic_str)))),
         };
 
         let dispatcher_switch = quote! {
@@ -72,6 +121,7 @@ pub fn producer(_attr: TokenStream, item: TokenStream) -> TokenStream {
         produce_methods.push(produce_method);
         dispatcher_switches.push(dispatcher_switch);
         enum_variants.push(enum_variant);
+        topics.push((variant_ident, topic_str));
 
         let brokers_type: Type = syn::parse_str(&format!(
             "pusu::producer::Receivers<{}>",
@@ -97,8 +147,9 @@ pub fn producer(_attr: TokenStream, item: TokenStream) -> TokenStream {
 
     let enum_attrs = vec![
         parse_quote! {
-        #[derive(strum::EnumString)]},
+        #[derive(Clone, Copy, strum::EnumString)]},
         parse_quote! {#[strum(serialize_all = "snake_case")]},
+        parse_quote! {#[repr(u16)]},
     ];
 
     let dispatcher_enum = syn::ItemEnum {
@@ -128,10 +179,18 @@ pub fn producer(_attr: TokenStream, item: TokenStream) -> TokenStream {
         semi_token: input.semi_token,
     };
 
+    let field_names = fields.iter().map(|field| &field.ident);
+
     let new_method = quote! {
         pub fn new() -> Self {
             Self::default()
         }
+
+        pub fn with_config(config: pusu::producer::ProducerConfig) -> Self {
+            Self {
+                #(#field_names: pusu::producer::Receivers::with_config(config.clone()),)*
+            }
+        }
     };
 
     let receiver_dispatch_trait = quote! {
@@ -144,9 +203,13 @@ pub fn producer(_attr: TokenStream, item: TokenStream) -> TokenStream {
         }
     };
 
+    let topic_enum_impl = topic_enum_impl(&enum_ident, &topics);
+
     let expanded = quote! {
         #dispatcher_enum
 
+        #topic_enum_impl
+
         #output_struct
 
         impl #struct_name {
@@ -161,9 +224,55 @@ pub fn producer(_attr: TokenStream, item: TokenStream) -> TokenStream {
     TokenStream::from(expanded)
 }
 
+fn is_async(attr: TokenStream) -> bool {
+    match attr.to_string().as_str() {
+        "" => false,
+        "async" => true,
+        other => panic!("Unknown producer argument {}, expected async", other),
+    }
+}
+
 fn is_unit(ty: &Type) -> bool {
     match ty {
         Type::Tuple(TypeTuple { elems, .. }) => elems.is_empty(),
         _ => false,
     }
 }
+
+fn topic_enum_impl(enum_ident: &Ident, topics: &[(Ident, String)]) -> proc_macro2::TokenStream {
+    let variants = topics
+        .iter()
+        .map(|(variant, _)| variant)
+        .collect::<Vec<_>>();
+    let names = topics.iter().map(|(_, name)| name).collect::<Vec<_>>();
+    let consts = topics
+        .iter()
+        .map(|(_, name)| Ident::new(&name.to_case(Case::UpperSnake), Span::call_site()))
+        .collect::<Vec<_>>();
+
+    quote! {
+        impl pusu::topic::TopicEnum for #enum_ident {
+            const TOPICS: &'static [(&'static str, u16)] = &[
+                #((#names, #enum_ident::#variants as u16)),*
+            ];
+
+            fn id(&self) -> u16 {
+                *self as u16
+            }
+
+            fn name(&self) -> &'static str {
+                match self {
+                    #(#enum_ident::#variants => #names,)*
+                }
+            }
+
+            fn from_id(id: u16) -> Option<Self> {
+                #(const #consts: u16 = #enum_ident::#variants as u16;)*
+                match id {
+                    #(#consts => Some(#enum_ident::#variants),)*
+                    _ => None,
+                }
+            }
+        }
+    }
+}
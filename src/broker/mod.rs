@@ -1,6 +1,8 @@
 mod message;
+mod server;
 mod topic;
 
 pub use message::Message;
 pub use pusu_broker_macro::broker;
+pub use server::{Broker, TopicLog, serve};
 pub use topic::Topic;
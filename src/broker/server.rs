@@ -0,0 +1,189 @@
+use std::{
+    io::{BufReader, BufWriter, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use anyhow::{Result, anyhow, bail};
+
+use super::Topic;
+use crate::frame::{self, Batch, Fetch, FrameKind};
+
+const MAX_FETCH_FRAME_SIZE: usize = 64 * 1024;
+// Upper bound of the postcard encoding of the Batch fields and of each message length
+const BATCH_OVERHEAD: usize = 20;
+const MESSAGE_OVERHEAD: usize = 10;
+
+// Topic of a broker seen without its message type, which pull consumers don't need
+pub trait TopicLog {
+    fn fetch_batch(&mut self, fetch: &Fetch) -> Batch;
+}
+
+impl<T> TopicLog for Topic<T> {
+    // Stops before the message that would make the Batch frame exceed the consumer's frame size.
+    // A message that can never fit is sent empty, otherwise the consumer would fetch it forever.
+    fn fetch_batch(&mut self, fetch: &Fetch) -> Batch {
+        if let Some(offset) = fetch.commit {
+            self.commit(&fetch.group, offset as usize);
+        }
+        let budget = (fetch.max_frame_size as usize)
+            .saturating_sub(frame::HEADER_LEN + frame::CRC_LEN + BATCH_OVERHEAD);
+
+        let mut batch = Batch {
+            first: 0,
+            messages: Vec::new(),
+        };
+        let mut size = 0;
+        for message in self.fetch(&fetch.group, fetch.max_messages as usize) {
+            if batch.messages.is_empty() {
+                batch.first = message.id as u64;
+            }
+            let message_size = message.payload.len() + MESSAGE_OVERHEAD;
+            if message_size > budget && batch.messages.is_empty() {
+                eprintln!(
+                    "Message {} of topic {} doesn't fit in a {} bytes frame, sending it empty",
+                    message.id, self.name, fetch.max_frame_size
+                );
+                batch.messages.push(Vec::new());
+                break;
+            }
+            if size + message_size > budget {
+                break;
+            }
+            size += message_size;
+            batch.messages.push(message.payload.clone());
+        }
+        batch
+    }
+}
+
+// Implemented by #[broker] to find topics by id
+pub trait Broker: Send + 'static {
+    fn topic(&mut self, id: u16) -> Option<&mut dyn TopicLog>;
+}
+
+// Answers the Fetch requests of pull consumers, one thread per connection. The broker stays
+// usable through the mutex to publish messages.
+pub fn serve<B: Broker, A: ToSocketAddrs>(broker: Arc<Mutex<B>>, addr: A) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    println!("Broker listening on {}", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Error accepting connection: {}", err);
+                continue;
+            }
+        };
+
+        let broker = broker.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr();
+            if let Err(err) = handle(&broker, stream) {
+                match peer {
+                    Ok(peer) => eprintln!("Closing connection from {}: {}", peer, err),
+                    Err(_) => eprintln!("Closing connection: {}", err),
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle<B: Broker>(broker: &Mutex<B>, stream: TcpStream) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    while let Some(buf) = frame::read(&mut reader, MAX_FETCH_FRAME_SIZE)? {
+        let frame = frame::decode(&buf)?;
+        if frame.kind != FrameKind::Fetch {
+            bail!("Unexpected {:?} frame", frame.kind);
+        }
+        let fetch: Fetch = postcard::from_bytes(frame.payload)?;
+
+        let batch = broker
+            .lock()
+            .map_err(|_| anyhow!("Broker lock poisoned"))?
+            .topic(frame.topic)
+            .map(|topic| topic.fetch_batch(&fetch));
+        let Some(batch) = batch else {
+            let reason = format!("Unknown topic id {}", frame.topic);
+            frame::write(
+                &mut writer,
+                FrameKind::Reject,
+                frame.topic,
+                reason.as_bytes(),
+            )?;
+            writer.flush()?;
+            bail!(reason);
+        };
+
+        let batch = postcard::to_stdvec(&batch)?;
+        frame::write(&mut writer, FrameKind::Batch, frame.topic, &batch)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fetch(commit: Option<u64>, max_messages: u32, max_frame_size: u32) -> Fetch {
+        Fetch {
+            group: "readers".to_string(),
+            commit,
+            max_messages,
+            max_frame_size,
+        }
+    }
+
+    fn topic(sizes: &[usize]) -> Topic<Vec<u8>> {
+        let mut topic = Topic::new("blob");
+        for size in sizes {
+            topic.publish(vec![7; *size]).unwrap();
+        }
+        topic
+    }
+
+    fn frame_size(batch: &Batch) -> usize {
+        frame::encode(FrameKind::Batch, 1, &postcard::to_stdvec(batch).unwrap()).len()
+    }
+
+    #[test]
+    fn fetches_commit_the_offset_they_carry() {
+        let mut topic = topic(&[1, 2, 3, 4]);
+        let batch = topic.fetch_batch(&fetch(None, 2, 1024));
+        assert_eq!((batch.first, batch.messages.len()), (0, 2));
+
+        let batch = topic.fetch_batch(&fetch(Some(2), 10, 1024));
+        assert_eq!((batch.first, batch.messages.len()), (2, 2));
+        assert_eq!(topic.committed["readers"], 2);
+    }
+
+    #[test]
+    fn batches_stay_within_the_frame_size() {
+        const MAX_FRAME_SIZE: u32 = 4096;
+        let mut topic = topic(&[1000, 1000, 1000, 1000, 1000]);
+        let batch = topic.fetch_batch(&fetch(None, 10, MAX_FRAME_SIZE));
+        assert_eq!(batch.messages.len(), 3);
+        assert!(frame_size(&batch) <= MAX_FRAME_SIZE as usize);
+
+        let batch = topic.fetch_batch(&fetch(Some(3), 10, MAX_FRAME_SIZE));
+        assert_eq!((batch.first, batch.messages.len()), (3, 2));
+    }
+
+    #[test]
+    fn messages_that_never_fit_are_sent_empty() {
+        let mut topic = topic(&[10_000, 10]);
+        let batch = topic.fetch_batch(&fetch(None, 10, 4096));
+        assert_eq!(batch.first, 0);
+        assert_eq!(batch.messages, [Vec::<u8>::new()]);
+
+        let batch = topic.fetch_batch(&fetch(Some(1), 10, 4096));
+        assert_eq!((batch.first, batch.messages.len()), (1, 1));
+    }
+}
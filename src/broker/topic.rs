@@ -1,11 +1,25 @@
-use std::collections::VecDeque;
+use std::{
+    collections::{HashMap, VecDeque},
+    marker::PhantomData,
+};
+
+use anyhow::{Result, anyhow};
+use serde::{Serialize, de::DeserializeOwned};
 
 use super::Message;
+use crate::{
+    frame::{self, Envelope, FrameKind, Headers},
+    topic,
+};
 
+// Messages are stored as encoded frames so their checksum can be verified on read
 pub struct Topic<T> {
     pub name: String,
-    pub queue: VecDeque<Message<T>>,
+    pub queue: VecDeque<Message<Vec<u8>>>,
     pub next_id: usize,
+    // Offset of the next message each pull consumer group has to process
+    pub committed: HashMap<String, usize>,
+    _phantom: PhantomData<T>,
 }
 
 impl<T> Topic<T> {
@@ -14,16 +28,116 @@ impl<T> Topic<T> {
             name: name.to_string(),
             queue: VecDeque::new(),
             next_id: 0,
+            committed: HashMap::new(),
+            _phantom: PhantomData,
         }
     }
 
-    pub fn publish(&mut self, payload: T) {
+    // Offsets are message ids, so they stay valid when messages are consumed from the queue
+    pub fn commit(&mut self, group: &str, offset: usize) {
+        self.committed.insert(group.to_string(), offset);
+    }
+
+    // Messages from the group's committed offset, or from the oldest one still queued
+    pub fn fetch(
+        &self,
+        group: &str,
+        max_messages: usize,
+    ) -> impl Iterator<Item = &Message<Vec<u8>>> {
+        let offset = self.committed.get(group).copied().unwrap_or(0);
+        let skip = match self.queue.front() {
+            Some(first) => offset.saturating_sub(first.id),
+            None => 0,
+        };
+        self.queue.iter().skip(skip).take(max_messages)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> Topic<T> {
+    pub fn publish(&mut self, payload: T) -> Result<()> {
         let id = self.next_id;
+        let payload_bytes = postcard::to_stdvec(&payload)?;
+        // Stored messages are told apart by their offset alone, with no producer
+        let message = Envelope::encode(id as u64, 0, &Headers::new(), &payload_bytes)?;
         self.next_id += 1;
-        self.queue.push_back(Message { id, payload });
+        self.queue.push_back(Message {
+            id,
+            payload: frame::encode(FrameKind::Message, topic::id(&self.name), &message),
+        });
+        Ok(())
+    }
+
+    pub fn consume(&mut self) -> Result<Option<Message<T>>> {
+        let Some(message) = self.queue.pop_front() else {
+            return Ok(None);
+        };
+
+        let frame = frame::decode(&message.payload).map_err(|err| {
+            anyhow!(
+                "Corrupted message {} in topic {}: {}",
+                message.id,
+                self.name,
+                err
+            )
+        })?;
+
+        Ok(Some(Message {
+            id: message.id,
+            payload: postcard::from_bytes(Envelope::decode(frame.payload)?.body)?,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids<'a>(messages: impl Iterator<Item = &'a Message<Vec<u8>>>) -> Vec<usize> {
+        messages.map(|message| message.id).collect()
+    }
+
+    #[test]
+    fn groups_fetch_from_their_committed_offset() {
+        let mut topic = Topic::<u32>::new("users");
+        for i in 0..5 {
+            topic.publish(i).unwrap();
+        }
+        assert_eq!(ids(topic.fetch("a", 2)), [0, 1]);
+        topic.commit("a", 3);
+        assert_eq!(ids(topic.fetch("a", 10)), [3, 4]);
+        // Each group has its own offset
+        assert_eq!(ids(topic.fetch("b", 10)), [0, 1, 2, 3, 4]);
+        topic.commit("a", 5);
+        assert_eq!(ids(topic.fetch("a", 10)), []);
+    }
+
+    #[test]
+    fn offsets_stay_valid_when_messages_are_consumed() {
+        let mut topic = Topic::<u32>::new("users");
+        for i in 0..5 {
+            topic.publish(i * 10).unwrap();
+        }
+        topic.commit("a", 3);
+        topic.commit("b", 1);
+        assert_eq!(topic.consume().unwrap().unwrap().payload, 0);
+        assert_eq!(topic.consume().unwrap().unwrap().payload, 10);
+
+        assert_eq!(ids(topic.fetch("a", 10)), [3, 4]);
+        // Behind the oldest message still queued
+        assert_eq!(ids(topic.fetch("b", 10)), [2, 3, 4]);
     }
 
-    pub fn consume(&mut self) -> Option<Message<T>> {
-        self.queue.pop_front()
+    #[test]
+    fn corrupted_messages_fail_to_consume() {
+        let mut topic = Topic::<u32>::new("users");
+        topic.publish(1).unwrap();
+        topic.queue[0].payload[frame::HEADER_LEN] ^= 0xff;
+        let err = topic.consume().err().unwrap();
+        assert!(
+            err.to_string().starts_with("Corrupted message 0"),
+            "{}",
+            err
+        );
+        assert!(topic.consume().unwrap().is_none());
     }
 }
@@ -0,0 +1,654 @@
+use std::{
+    collections::HashMap,
+    future::{self, Future},
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Result, anyhow, bail};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        TcpListener, TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    sync::{mpsc, watch},
+    task::JoinSet,
+    time::{self, Instant},
+};
+
+use super::{
+    ConsumerConfig, DedupKey, DedupStore, DispatchError, DrainSummary, MessageContext, RetryPolicy,
+    connection::ConnectionTracker, credits::Credits, dedup, drain::Drain, handler, handshake,
+    handshake::TopicLookup, listener, retry,
+};
+use crate::{
+    frame::{self, Envelope, FrameKind},
+    topic::TopicEnum,
+};
+
+// Counterpart of Consumer for the tokio feature, generated by #[consumer] when handlers are async.
+// Handlers run on the caller's runtime, one connection processes its messages in order.
+pub trait AsyncConsumer<T: TopicEnum>: Sync + Send + Sized + 'static {
+    fn run(self, port: u16) -> impl Future<Output = Result<DrainSummary>> + Send {
+        self.run_with_config(ConsumerConfig::with_port(port))
+    }
+
+    fn run_with_config(
+        self,
+        config: ConsumerConfig,
+    ) -> impl Future<Output = Result<DrainSummary>> + Send {
+        async move {
+            let listener = listener::bind(&config)?;
+            listener.set_nonblocking(true)?;
+            let listener = TcpListener::from_std(listener)?;
+
+            if config.handle_signals {
+                self.serve(listener, config, signal()).await
+            } else {
+                self.serve(listener, config, future::pending()).await
+            }
+        }
+    }
+
+    // Serves connections until `shutdown` completes, then lets them finish their queued messages
+    // and retries until the drain deadline
+    fn serve(
+        self,
+        listener: TcpListener,
+        config: ConsumerConfig,
+        shutdown: impl Future<Output = ()> + Send,
+    ) -> impl Future<Output = Result<DrainSummary>> + Send {
+        serve(self, listener, config, shutdown)
+    }
+
+    fn retry_policy(&self, _topic: T) -> Option<RetryPolicy> {
+        None
+    }
+
+    fn has_fallback(&self) -> bool {
+        false
+    }
+
+    fn fallback(&self, _topic: &str, _payload: &[u8]) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    fn dead_letter(
+        &self,
+        _topic: T,
+        _payload: &[u8],
+        _context: &MessageContext,
+        _err: &anyhow::Error,
+    ) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    fn dedup_store(&self) -> Option<&dyn DedupStore> {
+        None
+    }
+
+    fn dispatch(
+        &self,
+        topic: T,
+        payload: &[u8],
+        context: &MessageContext,
+    ) -> impl Future<Output = Result<(), DispatchError>> + Send;
+}
+
+async fn signal() {
+    let Ok(mut terminate) =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+    else {
+        return tokio::signal::ctrl_c().await.unwrap_or(());
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+async fn serve<C: AsyncConsumer<T>, T: TopicEnum>(
+    consumer: C,
+    listener: TcpListener,
+    config: ConsumerConfig,
+    shutdown: impl Future<Output = ()> + Send,
+) -> Result<DrainSummary> {
+    println!("Listening on {}", listener.local_addr()?);
+
+    let consumer = Arc::new(consumer);
+    let config = Arc::new(config);
+    let tracker = Arc::new(Mutex::new(ConnectionTracker::default()));
+    let drain = Arc::new(Drain::default());
+    let (closing, closed) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            _ = &mut shutdown => break,
+            accepted = listener.accept() => {
+                let (stream, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        eprintln!("Error accepting connection: {}", err);
+                        continue;
+                    }
+                };
+
+                if !tracker
+                    .lock()
+                    .unwrap()
+                    .track(peer.ip(), config.max_connections_per_ip)
+                {
+                    eprintln!("Connection limit reached for {}", peer.ip());
+                    continue;
+                }
+
+                let consumer = consumer.clone();
+                let config = config.clone();
+                let tracker = tracker.clone();
+                let drain = drain.clone();
+                let closed = closed.clone();
+                connections.spawn(async move {
+                    let result = connection(consumer, stream, peer, &config, drain, closed).await;
+                    if let Err(err) = result {
+                        eprintln!("Closing connection from {}: {}", peer, err);
+                    }
+                    tracker.lock().unwrap().release(peer.ip());
+                });
+            }
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
+    }
+
+    drain.start(config.drain_timeout);
+    let _ = closing.send(true);
+    let finished = async { while connections.join_next().await.is_some() {} };
+    match drain.cutoff() {
+        Some(cutoff) => {
+            // Connections still running a handler are left to finish it in the background
+            let _ = time::timeout_at(Instant::from_std(cutoff), finished).await;
+            connections.detach_all();
+        }
+        None => finished.await,
+    }
+    Ok(drain.summary())
+}
+
+// A message of a topic the consumer doesn't know is handed to its fallback handler once
+enum Pending<T> {
+    Known(Delivery<T>),
+    Unknown(Arc<str>, Vec<u8>),
+}
+
+struct Delivery<T> {
+    topic: T,
+    frame: Vec<u8>,
+    peer: SocketAddr,
+    received_at: SystemTime,
+    attempt: u32,
+}
+
+impl<T: TopicEnum> Delivery<T> {
+    async fn dispatch<C: AsyncConsumer<T>>(&self, consumer: &C) -> Result<(), DispatchError> {
+        let payload = &self.frame[frame::HEADER_LEN..self.frame.len() - frame::CRC_LEN];
+        let (context, body) = MessageContext::open(
+            self.topic,
+            payload,
+            self.peer,
+            self.received_at,
+            self.attempt,
+        )?;
+        let key = DedupKey::new(self.topic, &context);
+        let store = consumer.dedup_store();
+        if dedup::seen(store, &key) {
+            return Ok(());
+        }
+        let result =
+            handler::catch_panic_async(consumer.dispatch(self.topic, body, &context)).await;
+        if result.is_ok() {
+            dedup::record(store, key, context.topic);
+        }
+        result
+    }
+}
+
+async fn connection<C: AsyncConsumer<T>, T: TopicEnum>(
+    consumer: Arc<C>,
+    stream: TcpStream,
+    peer: SocketAddr,
+    config: &ConsumerConfig,
+    drain: Arc<Drain>,
+    mut closed: watch::Receiver<bool>,
+) -> Result<()> {
+    listener::configure_stream(&stream, &config.socket)?;
+    let (reader, mut writer) = stream.into_split();
+
+    // Reads run in their own task as they cannot be cancelled halfway through a frame
+    let (frames_sender, mut frames) = mpsc::channel(1);
+    let mut reader_task = JoinSet::new();
+    reader_task.spawn(read_frames(reader, config.clone(), frames_sender));
+
+    let silence = silence_timeout(config);
+    let hello = match silence {
+        Some(timeout) => time::timeout(timeout, frames.recv())
+            .await
+            .map_err(|_| anyhow!("No handshake within {:?}", timeout))?,
+        None => frames.recv().await,
+    };
+    let Some(hello) = hello.transpose()? else {
+        return Ok(());
+    };
+    let lookup = TopicLookup {
+        topic_id: T::id_of,
+        is_known: |id| T::from_id(id).is_some(),
+        accept_unknown: consumer.has_fallback(),
+    };
+    let unknown_topics = match handshake::accept_hello(&hello, config, lookup) {
+        Ok(unknown_topics) => unknown_topics
+            .into_iter()
+            .map(|(name, id)| (id, Arc::from(name)))
+            .collect::<HashMap<u16, Arc<str>>>(),
+        Err(err) => {
+            write_frame(&mut writer, FrameKind::Reject, err.to_string().as_bytes()).await?;
+            return Err(err);
+        }
+    };
+    write_frame(&mut writer, FrameKind::Welcome, &[]).await?;
+
+    let load = Arc::new(AtomicUsize::new(0));
+    // Each connection has its own capacity, so there are no idle credits to revoke
+    let wanting = Arc::new(AtomicUsize::new(0));
+    let mut credits = Credits::new(
+        load.clone(),
+        wanting,
+        config.worker_queue_size,
+        config.credit_window,
+    );
+    let (completions_sender, mut completions) = mpsc::unbounded_channel();
+    let (deliveries, pending) = mpsc::unbounded_channel();
+    let place = format!("connection from {}", peer);
+    let dispatcher = tokio::spawn(deliver(
+        consumer,
+        pending,
+        Released {
+            load,
+            drain: drain.clone(),
+            completions: completions_sender,
+        },
+        closed.clone(),
+        place,
+    ));
+
+    let mut last_received = Instant::now();
+    let mut last_message = last_received;
+    let mut last_sent = Instant::now();
+
+    let result: Result<()> = async {
+        loop {
+            let granted = credits.grant();
+            if granted > 0 {
+                write_frame(&mut writer, FrameKind::Credit, &granted.to_be_bytes()).await?;
+            }
+
+            let heartbeat_deadline = config
+                .heartbeat_interval
+                .map(|interval| last_sent + interval);
+            let silence_deadline = silence_deadline(last_received, last_message, config);
+            let deadline = [heartbeat_deadline, silence_deadline]
+                .into_iter()
+                .flatten()
+                .min();
+
+            tokio::select! {
+                buf = frames.recv() => {
+                    let Some(buf) = buf.transpose()? else {
+                        return Ok(());
+                    };
+                    last_received = Instant::now();
+
+                    let frame = frame::decode(&buf)?;
+                    match frame.kind {
+                        FrameKind::Heartbeat => {}
+                        FrameKind::Demand => credits.demand(),
+                        FrameKind::Message => {
+                            last_message = last_received;
+                            let delivery = match T::from_id(frame.topic) {
+                                Some(topic) => Pending::Known(Delivery {
+                                    topic,
+                                    frame: buf,
+                                    peer,
+                                    received_at: SystemTime::now(),
+                                    attempt: 1,
+                                }),
+                                None => Pending::Unknown(
+                                    unknown_topics
+                                        .get(&frame.topic)
+                                        .cloned()
+                                        .ok_or_else(|| {
+                                            anyhow!("Unknown topic id {}", frame.topic)
+                                        })?,
+                                    buf,
+                                ),
+                            };
+                            credits.consume()?;
+                            drain.hold();
+                            deliveries
+                                .send(delivery)
+                                .map_err(|_| anyhow!("Dispatcher stopped"))?;
+                        }
+                        kind => bail!("Unexpected {:?} frame after handshake", kind),
+                    }
+                }
+                Some(()) = completions.recv() => {}
+                _ = sleep_until(deadline) => {
+                    let now = Instant::now();
+                    check_silence(now, last_received, last_message, config)?;
+                    if heartbeat_deadline.is_some_and(|deadline| now >= deadline) {
+                        write_frame(&mut writer, FrameKind::Heartbeat, &[]).await?;
+                        last_sent = now;
+                    }
+                }
+                _ = closed.changed() => return Ok(()),
+            }
+        }
+    }
+    .await;
+
+    // Queued messages and pending retries are still processed until the drain deadline
+    drop(deliveries);
+    let _ = dispatcher.await;
+    result
+}
+
+async fn read_frames(
+    mut reader: OwnedReadHalf,
+    config: ConsumerConfig,
+    frames: mpsc::Sender<Result<Vec<u8>>>,
+) {
+    loop {
+        let result = read_frame(&mut reader, &config).await;
+        let frame = match result {
+            Ok(Some(buf)) => Ok(buf),
+            Ok(None) => return,
+            Err(err) => Err(err),
+        };
+        let failed = frame.is_err();
+        if frames.send(frame).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+// Waits as long as needed for the first byte of a frame, then reads it within the read timeout
+async fn read_frame(
+    reader: &mut OwnedReadHalf,
+    config: &ConsumerConfig,
+) -> Result<Option<Vec<u8>>> {
+    let mut first = [0u8; 1];
+    if reader.read(&mut first).await? == 0 {
+        return Ok(None);
+    }
+
+    let mut chained = (&first[..]).chain(reader);
+    let read = frame::read_async(&mut chained, config.max_frame_size);
+    let buf = match config.read_timeout {
+        Some(timeout) => time::timeout(timeout, read)
+            .await
+            .map_err(|_| anyhow!("Frame not received within {:?}", timeout))??,
+        None => read.await?,
+    };
+    buf.map(Some)
+        .ok_or_else(|| anyhow!("Connection closed in the middle of a frame"))
+}
+
+// Runs the handlers of one connection in order, retries are scheduled on their own task. Past the
+// drain deadline, queued messages and pending retries are nacked.
+async fn deliver<C: AsyncConsumer<T>, T: TopicEnum>(
+    consumer: Arc<C>,
+    mut pending: mpsc::UnboundedReceiver<Pending<T>>,
+    released: Released,
+    closed: watch::Receiver<bool>,
+    place: String,
+) {
+    let released = Arc::new(released);
+    let mut retries = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            delivery = pending.recv() => {
+                let delivery = match delivery {
+                    Some(Pending::Known(delivery)) => delivery,
+                    Some(Pending::Unknown(topic, frame)) => {
+                        if is_past_deadline(&released.drain) {
+                            eprintln!("Nacking {} message left on {} at the drain deadline", topic, place);
+                            released.drain.nacked(1);
+                        } else {
+                            fallback(&*consumer, &topic, &frame, &place).await;
+                        }
+                        released.release();
+                        continue;
+                    }
+                    None => break,
+                };
+                if is_past_deadline(&released.drain) {
+                    nack(&*consumer, &delivery, &released.drain, &place).await;
+                    released.release();
+                    continue;
+                }
+                if let Some((delivery, delay)) = attempt(&*consumer, delivery, &place).await {
+                    let consumer = consumer.clone();
+                    let released = released.clone();
+                    let closed = closed.clone();
+                    let place = place.clone();
+                    retries.spawn(async move {
+                        let mut next = Some((delivery, delay));
+                        while let Some((delivery, delay)) = next {
+                            if !wait_for_retry(delay, &released.drain, closed.clone()).await {
+                                nack(&*consumer, &delivery, &released.drain, &place).await;
+                                break;
+                            }
+                            next = attempt(&*consumer, delivery, &place).await;
+                        }
+                        released.release();
+                    });
+                } else {
+                    released.release();
+                }
+            }
+            Some(_) = retries.join_next(), if !retries.is_empty() => {}
+        }
+    }
+    while retries.join_next().await.is_some() {}
+}
+
+fn is_past_deadline(drain: &Drain) -> bool {
+    drain
+        .deadline()
+        .is_some_and(|deadline| std::time::Instant::now() >= deadline)
+}
+
+// Returns false once the drain deadline comes before the retry is due
+async fn wait_for_retry(delay: Duration, drain: &Drain, mut closed: watch::Receiver<bool>) -> bool {
+    let due = Instant::now() + delay;
+    if !*closed.borrow_and_update() {
+        tokio::select! {
+            _ = time::sleep_until(due) => return true,
+            _ = closed.changed() => {}
+        }
+    }
+    match drain.deadline().map(Instant::from_std) {
+        Some(deadline) if deadline < due => {
+            time::sleep_until(deadline).await;
+            false
+        }
+        _ => {
+            time::sleep_until(due).await;
+            true
+        }
+    }
+}
+
+// Hands a message left at the drain deadline to the dead letter handler, counted before its
+// credit is released so it is never seen as completed
+async fn nack<C: AsyncConsumer<T>, T: TopicEnum>(
+    consumer: &C,
+    delivery: &Delivery<T>,
+    drain: &Drain,
+    place: &str,
+) {
+    eprintln!(
+        "Nacking {} message left on {} at the drain deadline",
+        delivery.topic.name(),
+        place
+    );
+    drain.nacked(1);
+    let err = anyhow!("Not processed by the drain deadline");
+    dead_letter(consumer, delivery, &err, place).await;
+}
+
+// Returns the delivery with the delay before its next attempt if it has to be retried
+async fn attempt<C: AsyncConsumer<T>, T: TopicEnum>(
+    consumer: &C,
+    mut delivery: Delivery<T>,
+    place: &str,
+) -> Option<(Delivery<T>, Duration)> {
+    let err = delivery.dispatch(consumer).await.err()?;
+    let policy = consumer.retry_policy(delivery.topic);
+    match retry::next_attempt(delivery.topic, delivery.attempt, err, policy, &place) {
+        Ok(delay) => {
+            delivery.attempt += 1;
+            Some((delivery, delay))
+        }
+        Err(err) => {
+            dead_letter(consumer, &delivery, &err, place).await;
+            None
+        }
+    }
+}
+
+async fn dead_letter<C: AsyncConsumer<T>, T: TopicEnum>(
+    consumer: &C,
+    delivery: &Delivery<T>,
+    err: &anyhow::Error,
+    place: &str,
+) {
+    let payload = &delivery.frame[frame::HEADER_LEN..delivery.frame.len() - frame::CRC_LEN];
+    let (context, body) = MessageContext::open_or_raw(
+        delivery.topic,
+        payload,
+        delivery.peer,
+        delivery.received_at,
+        delivery.attempt,
+    );
+    let result = handler::catch_panic_async(async {
+        consumer
+            .dead_letter(delivery.topic, body, &context, err)
+            .await
+            .map_err(DispatchError::Handler)
+    })
+    .await;
+    if let Err(err) = result {
+        eprintln!(
+            "Dead letter handler for {} message failed on {}: {}",
+            delivery.topic.name(),
+            place,
+            err
+        );
+    }
+}
+
+async fn fallback<C: AsyncConsumer<T>, T: TopicEnum>(
+    consumer: &C,
+    topic: &str,
+    frame: &[u8],
+    place: &str,
+) {
+    let payload = &frame[frame::HEADER_LEN..frame.len() - frame::CRC_LEN];
+    let body = Envelope::decode(payload).map_or(payload, |message| message.body);
+    let result = handler::catch_panic_async(async {
+        consumer
+            .fallback(topic, body)
+            .await
+            .map_err(DispatchError::Handler)
+    })
+    .await;
+    if let Err(err) = result {
+        eprintln!(
+            "Fallback for {} message failed on {}: {}",
+            topic, place, err
+        );
+    }
+}
+
+// Releases the credit of each message once it is done with
+struct Released {
+    load: Arc<AtomicUsize>,
+    drain: Arc<Drain>,
+    completions: mpsc::UnboundedSender<()>,
+}
+
+impl Released {
+    fn release(&self) {
+        self.load.fetch_sub(1, Ordering::Relaxed);
+        self.drain.release();
+        let _ = self.completions.send(());
+    }
+}
+
+async fn write_frame(writer: &mut OwnedWriteHalf, kind: FrameKind, payload: &[u8]) -> Result<()> {
+    writer.write_all(&frame::encode(kind, 0, payload)).await?;
+    Ok(())
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline).await,
+        None => future::pending().await,
+    }
+}
+
+// Longest a peer can stay silent, whichever of the idle timeout and heartbeat misses is shorter
+fn silence_timeout(config: &ConsumerConfig) -> Option<Duration> {
+    let dead = config
+        .heartbeat_interval
+        .map(|interval| interval * config.heartbeat_misses);
+    [config.idle_timeout, dead].into_iter().flatten().min()
+}
+
+// Heartbeats keep a connection alive, only messages keep it from being idle
+fn silence_deadline(
+    last_received: Instant,
+    last_message: Instant,
+    config: &ConsumerConfig,
+) -> Option<Instant> {
+    let dead = config
+        .heartbeat_interval
+        .map(|interval| last_received + interval * config.heartbeat_misses);
+    let idle = config.idle_timeout.map(|timeout| last_message + timeout);
+    [dead, idle].into_iter().flatten().min()
+}
+
+fn check_silence(
+    now: Instant,
+    last_received: Instant,
+    last_message: Instant,
+    config: &ConsumerConfig,
+) -> Result<()> {
+    if let Some(interval) = config.heartbeat_interval
+        && now >= last_received + interval * config.heartbeat_misses
+    {
+        bail!("Missed {} heartbeats", config.heartbeat_misses);
+    }
+    if let Some(timeout) = config.idle_timeout
+        && now >= last_message + timeout
+    {
+        bail!("Idle for more than {:?}", timeout);
+    }
+    Ok(())
+}
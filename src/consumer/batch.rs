@@ -0,0 +1,117 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+// Set per topic with #[topic("handler", batch(max = 500, linger_ms = 20))]
+#[derive(Clone, Copy, Debug)]
+pub struct BatchPolicy {
+    // Messages handed to the handler at once
+    pub max: usize,
+    // Maximum time the first message of a batch waits for the others
+    pub linger: Duration,
+}
+
+// Messages gathered per topic by a worker until their batch is full or its linger time is over
+pub struct Batches<J> {
+    pending: HashMap<u16, Pending<J>>,
+}
+
+struct Pending<J> {
+    jobs: Vec<J>,
+    due: Instant,
+}
+
+impl<J> Batches<J> {
+    pub fn new() -> Self {
+        Self {
+            pending: HashMap::new(),
+        }
+    }
+
+    // Returns the batch of the topic once it is full
+    pub fn push(&mut self, topic: u16, job: J, policy: BatchPolicy) -> Option<Vec<J>> {
+        let pending = self.pending.entry(topic).or_insert_with(|| Pending {
+            jobs: Vec::with_capacity(policy.max),
+            due: Instant::now() + policy.linger,
+        });
+        pending.jobs.push(job);
+
+        if pending.jobs.len() >= policy.max {
+            self.pending.remove(&topic).map(|pending| pending.jobs)
+        } else {
+            None
+        }
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.pending.values().map(|pending| pending.due).min()
+    }
+
+    pub fn take_due(&mut self, now: Instant) -> Vec<Vec<J>> {
+        let due = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.due <= now)
+            .map(|(topic, _)| *topic)
+            .collect::<Vec<_>>();
+        due.into_iter()
+            .filter_map(|topic| self.pending.remove(&topic))
+            .map(|pending| pending.jobs)
+            .collect()
+    }
+
+    pub fn take_all(&mut self) -> Vec<Vec<J>> {
+        self.pending
+            .drain()
+            .map(|(_, pending)| pending.jobs)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: BatchPolicy = BatchPolicy {
+        max: 3,
+        linger: Duration::from_millis(20),
+    };
+
+    #[test]
+    fn a_full_batch_is_returned_at_once() {
+        let mut batches = Batches::new();
+        assert_eq!(batches.push(1, "a", POLICY), None);
+        assert_eq!(batches.push(2, "x", POLICY), None);
+        assert_eq!(batches.push(1, "b", POLICY), None);
+        assert_eq!(batches.push(1, "c", POLICY), Some(vec!["a", "b", "c"]));
+        // The next message starts a new batch
+        assert_eq!(batches.push(1, "d", POLICY), None);
+        assert_eq!(batches.take_all().len(), 2);
+        assert_eq!(batches.next_due(), None);
+    }
+
+    #[test]
+    fn batches_are_flushed_after_their_linger_time() {
+        let mut batches = Batches::new();
+        let start = Instant::now();
+        batches.push(1, "a", POLICY);
+        batches.push(1, "b", POLICY);
+        let due = batches.next_due().unwrap();
+        assert!(due >= start + POLICY.linger);
+
+        assert!(batches.take_due(start).is_empty());
+        assert_eq!(batches.take_due(due), [vec!["a", "b"]]);
+        assert_eq!(batches.next_due(), None);
+    }
+
+    #[test]
+    fn the_linger_time_starts_with_the_first_message() {
+        let mut batches = Batches::new();
+        batches.push(1, "a", POLICY);
+        let due = batches.next_due().unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        batches.push(1, "b", POLICY);
+        assert_eq!(batches.next_due(), Some(due));
+    }
+}
@@ -0,0 +1,377 @@
+use std::{collections::HashMap, time::Instant};
+
+use anyhow::{Result, anyhow, bail};
+use serde::de::DeserializeOwned;
+
+use super::{
+    Consumer, DedupStore, DispatchError, Handler, HandlerResult, MessageContext, Middleware,
+    RetryPolicy, TopicLimits,
+};
+use crate::topic::{DynamicTopic, TopicEnum};
+
+// Decodes the payload, runs the after_decode hooks it is given, then calls the handler
+type TopicHandler = Box<
+    dyn Fn(
+            &[u8],
+            &MessageContext,
+            &dyn Fn() -> Result<(), DispatchError>,
+        ) -> Result<(), DispatchError>
+        + Send
+        + Sync,
+>;
+
+type FallbackHandler = Box<dyn Fn(&str, &[u8]) -> Result<()> + Send + Sync>;
+
+type DeadLetterHandler =
+    Box<dyn Fn(&str, &[u8], &MessageContext, &anyhow::Error) -> Result<()> + Send + Sync>;
+
+struct Entry {
+    topic: DynamicTopic,
+    handler: TopicHandler,
+    retry: Option<RetryPolicy>,
+    limits: Option<TopicLimits>,
+}
+
+// Registers topics and their handlers at runtime, for services whose topics are not known at
+// compile time. The consumer it builds speaks the same protocol as #[consumer] structs.
+#[derive(Default)]
+pub struct ConsumerBuilder {
+    entries: Vec<Entry>,
+    retries: Vec<(String, RetryPolicy)>,
+    limits: Vec<(String, TopicLimits)>,
+    middlewares: Vec<Box<dyn Middleware<DynamicTopic>>>,
+    dedup: Option<Box<dyn DedupStore>>,
+    fallback: Option<FallbackHandler>,
+    dead_letter: Option<DeadLetterHandler>,
+}
+
+impl ConsumerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Handlers take the decoded value, optionally followed by &MessageContext, and return () or a
+    // Result like the handlers of #[consumer]
+    pub fn topic<V, M>(
+        mut self,
+        name: &str,
+        handler: impl Handler<(V,), M, Output: HandlerResult> + Send + Sync + 'static,
+    ) -> Self
+    where
+        V: DeserializeOwned + 'static,
+        M: 'static,
+    {
+        let handler: TopicHandler = Box::new(move |payload, context, after_decode| {
+            let value = postcard::from_bytes::<V>(payload).map_err(DispatchError::decode)?;
+            after_decode()?;
+            handler
+                .call((value,), context)
+                .into_result()
+                .map_err(DispatchError::Handler)
+        });
+        self.entries.push(Entry {
+            topic: DynamicTopic::new(name),
+            handler,
+            retry: None,
+            limits: None,
+        });
+        self
+    }
+
+    // Policy for messages of the topic nacked by its handler
+    pub fn retry(mut self, name: &str, policy: RetryPolicy) -> Self {
+        self.retries.push((name.to_string(), policy));
+        self
+    }
+
+    // Rate limit and concurrency cap of the topic, enforced by the workers
+    pub fn limits(mut self, name: &str, limits: TopicLimits) -> Self {
+        self.limits.push((name.to_string(), limits));
+        self
+    }
+
+    // Hooks run around every dispatch, in the order they are added
+    pub fn middleware(mut self, middleware: impl Middleware<DynamicTopic> + 'static) -> Self {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    // Store of acked messages, whose duplicates are acked without running
+    pub fn dedup(mut self, store: impl DedupStore + 'static) -> Self {
+        self.dedup = Some(Box::new(store));
+        self
+    }
+
+    // Handler given the topic and payload of the messages of unregistered topics and of those that
+    // don't decode
+    pub fn fallback<R: HandlerResult>(
+        mut self,
+        handler: impl Fn(&str, &[u8]) -> R + Send + Sync + 'static,
+    ) -> Self {
+        self.fallback = Some(Box::new(move |topic, payload| {
+            handler(topic, payload).into_result()
+        }));
+        self
+    }
+
+    // Handler given the messages nacked after their last attempt and those that don't decode, with
+    // the error they were given up with
+    pub fn dead_letter<R: HandlerResult>(
+        mut self,
+        handler: impl Fn(&str, &[u8], &MessageContext, &anyhow::Error) -> R + Send + Sync + 'static,
+    ) -> Self {
+        self.dead_letter = Some(Box::new(move |topic, payload, context, err| {
+            handler(topic, payload, context, err).into_result()
+        }));
+        self
+    }
+
+    // Fails on topics registered twice or whose ids collide, which #[consumer] rejects at compile
+    // time
+    pub fn build(self) -> Result<DynamicConsumer> {
+        let mut topics = HashMap::new();
+        for entry in self.entries {
+            if let Some(other) = topics
+                .get(&entry.topic.id())
+                .map(|other: &Entry| other.topic)
+            {
+                if other.name() == entry.topic.name() {
+                    bail!("Topic {} is registered twice", other.name());
+                }
+                bail!(
+                    "Topics {} and {} have the same id {}",
+                    other.name(),
+                    entry.topic.name(),
+                    other.id()
+                );
+            }
+            topics.insert(entry.topic.id(), entry);
+        }
+
+        if topics.is_empty() {
+            bail!("No topic registered");
+        }
+        for (name, policy) in self.retries {
+            let entry = topics
+                .values_mut()
+                .find(|entry| entry.topic.name() == name)
+                .ok_or_else(|| anyhow!("Retry policy set for unknown topic {}", name))?;
+            entry.retry = Some(policy);
+        }
+        for (name, limits) in self.limits {
+            let entry = topics
+                .values_mut()
+                .find(|entry| entry.topic.name() == name)
+                .ok_or_else(|| anyhow!("Limits set for unknown topic {}", name))?;
+            entry.limits = Some(limits);
+        }
+        Ok(DynamicConsumer {
+            topics,
+            middlewares: self.middlewares,
+            dedup: self.dedup,
+            fallback: self.fallback,
+            dead_letter: self.dead_letter,
+        })
+    }
+}
+
+pub struct DynamicConsumer {
+    topics: HashMap<u16, Entry>,
+    middlewares: Vec<Box<dyn Middleware<DynamicTopic>>>,
+    dedup: Option<Box<dyn DedupStore>>,
+    fallback: Option<FallbackHandler>,
+    dead_letter: Option<DeadLetterHandler>,
+}
+
+impl DynamicConsumer {
+    fn run_handler(
+        &self,
+        topic: DynamicTopic,
+        payload: &[u8],
+        context: &MessageContext,
+    ) -> Result<(), DispatchError> {
+        for middleware in &self.middlewares {
+            middleware.before_decode(topic, payload, context)?;
+        }
+        let Some(entry) = self.topics.get(&topic.id()) else {
+            return Err(DispatchError::decode(anyhow!(
+                "Unknown topic {}",
+                topic.name()
+            )));
+        };
+        (entry.handler)(payload, context, &|| {
+            self.middlewares
+                .iter()
+                .try_for_each(|middleware| middleware.after_decode(topic, context))
+        })
+    }
+}
+
+impl Consumer<DynamicTopic> for DynamicConsumer {
+    fn topics(&self) -> Vec<DynamicTopic> {
+        self.topics.values().map(|entry| entry.topic).collect()
+    }
+
+    fn topic(&self, id: u16) -> Option<DynamicTopic> {
+        self.topics.get(&id).map(|entry| entry.topic)
+    }
+
+    fn topic_id(&self, name: &str) -> Option<u16> {
+        self.topics
+            .values()
+            .find(|entry| entry.topic.name() == name)
+            .map(|entry| entry.topic.id())
+    }
+
+    fn retry_policy(&self, topic: DynamicTopic) -> Option<RetryPolicy> {
+        self.topics.get(&topic.id()).and_then(|entry| entry.retry)
+    }
+
+    fn topic_limits(&self, topic: DynamicTopic) -> Option<TopicLimits> {
+        self.topics.get(&topic.id()).and_then(|entry| entry.limits)
+    }
+
+    fn has_fallback(&self) -> bool {
+        self.fallback.is_some()
+    }
+
+    fn fallback(&self, topic: &str, payload: &[u8]) -> Result<()> {
+        match &self.fallback {
+            Some(fallback) => fallback(topic, payload),
+            None => Ok(()),
+        }
+    }
+
+    fn dead_letter(
+        &self,
+        topic: DynamicTopic,
+        payload: &[u8],
+        context: &MessageContext,
+        err: &anyhow::Error,
+    ) -> Result<()> {
+        match &self.dead_letter {
+            Some(dead_letter) => dead_letter(topic.name(), payload, context, err),
+            None => Ok(()),
+        }
+    }
+
+    fn dedup_store(&self) -> Option<&dyn DedupStore> {
+        self.dedup.as_deref()
+    }
+
+    fn dispatch(
+        &self,
+        topic: DynamicTopic,
+        payload: &[u8],
+        context: &MessageContext,
+    ) -> Result<(), DispatchError> {
+        let started = Instant::now();
+        let result = match self.run_handler(topic, payload, context) {
+            Err(DispatchError::Decode(_)) if self.fallback.is_some() => self
+                .fallback(topic.name(), payload)
+                .map_err(DispatchError::Handler),
+            result => result,
+        };
+        let elapsed = started.elapsed();
+        for middleware in &self.middlewares {
+            middleware.after_handler(topic, &result, elapsed, context);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::{consumer::Backoff, topic};
+
+    // Two topic names whose ids collide, there must be one among 65537 names
+    fn colliding_names() -> (String, String) {
+        let mut seen = HashMap::new();
+        for i in 0.. {
+            let name = format!("topic{}", i);
+            if let Some(other) = seen.insert(topic::id(&name), name.clone()) {
+                return (other, name);
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn colliding_ids_are_rejected() {
+        let (first, second) = colliding_names();
+        let err = ConsumerBuilder::new()
+            .topic(&first, |_: u32| {})
+            .topic(&second, |_: u32| {})
+            .build()
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("have the same id"), "{}", err);
+    }
+
+    #[test]
+    fn topics_cannot_be_registered_twice() {
+        let err = ConsumerBuilder::new()
+            .topic("users", |_: u32| {})
+            .topic("users", |_: String| {})
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "Topic users is registered twice");
+    }
+
+    #[test]
+    fn policies_need_a_registered_topic() {
+        assert!(ConsumerBuilder::new().build().is_err());
+
+        let policy = RetryPolicy {
+            max_retries: 1,
+            backoff: Backoff::Fixed,
+            base: Duration::ZERO,
+        };
+        let err = ConsumerBuilder::new()
+            .topic("users", |_: u32| {})
+            .retry("orders", policy)
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "Retry policy set for unknown topic orders");
+    }
+
+    #[test]
+    fn payloads_are_dispatched_to_their_topic() {
+        let consumer = ConsumerBuilder::new()
+            .topic("users", |id: u32| -> Result<()> {
+                if id == 0 {
+                    bail!("No user 0");
+                }
+                Ok(())
+            })
+            .build()
+            .unwrap();
+        let users = consumer.topic(topic::id("users")).unwrap();
+        let context = MessageContext {
+            topic: "users",
+            id: 1,
+            producer: 1,
+            headers: Vec::new(),
+            peer: "127.0.0.1:1".parse().unwrap(),
+            received_at: SystemTime::now(),
+            attempt: 1,
+        };
+
+        let payload = postcard::to_stdvec(&1u32).unwrap();
+        assert!(consumer.dispatch(users, &payload, &context).is_ok());
+        let payload = postcard::to_stdvec(&0u32).unwrap();
+        assert!(matches!(
+            consumer.dispatch(users, &payload, &context),
+            Err(DispatchError::Handler(_))
+        ));
+        assert!(matches!(
+            consumer.dispatch(users, &[], &context),
+            Err(DispatchError::Decode(_))
+        ));
+    }
+}
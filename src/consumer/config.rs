@@ -0,0 +1,106 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+#[derive(Clone, Debug)]
+pub struct ConsumerConfig {
+    // Port 0 binds an ephemeral port, see BoundConsumer::local_addr
+    pub addr: SocketAddr,
+    pub workers: usize,
+    // Messages a worker can have queued, in process or granted as credits
+    pub worker_queue_size: usize,
+    pub socket: SocketOptions,
+    // Stop on SIGINT and SIGTERM, disable to only stop through a ConsumerHandle
+    pub handle_signals: bool,
+    pub max_topic_len: usize,
+    pub max_frame_size: usize,
+    // Maximum time to receive a whole frame once its first byte arrived
+    pub read_timeout: Option<Duration>,
+    // Maximum time a connection can stay open without sending a message, heartbeats don't count
+    pub idle_timeout: Option<Duration>,
+    pub max_connections_per_ip: usize,
+    // Heartbeats are sent every interval, a peer silent for `heartbeat_misses` intervals is dropped
+    pub heartbeat_interval: Option<Duration>,
+    pub heartbeat_misses: u32,
+    // Maximum credits a single connection holds at once
+    pub credit_window: u32,
+    // Credits of a connection that sent no message for this long are taken back, so that the
+    // other connections of its worker can use them
+    pub credit_idle_timeout: Option<Duration>,
+    // Whether messages stay on the worker of their connection or can be stolen by idle workers
+    pub scheduling: Scheduling,
+    // Time given to the workers after a shutdown to finish the messages they hold, what is left is
+    // then nacked to the dead letter handlers. None waits for every message and its retries.
+    pub drain_timeout: Option<Duration>,
+}
+
+impl ConsumerConfig {
+    pub fn with_port(port: u16) -> Self {
+        Self {
+            addr: SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for ConsumerConfig {
+    fn default() -> Self {
+        Self {
+            addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            workers: 4,
+            worker_queue_size: 256,
+            socket: SocketOptions::default(),
+            handle_signals: true,
+            max_topic_len: 256,
+            max_frame_size: 4 * 1024 * 1024,
+            read_timeout: Some(Duration::from_secs(5)),
+            idle_timeout: Some(Duration::from_secs(30)),
+            max_connections_per_ip: 256,
+            heartbeat_interval: Some(Duration::from_secs(5)),
+            heartbeat_misses: 3,
+            credit_window: 64,
+            credit_idle_timeout: Some(Duration::from_secs(1)),
+            scheduling: Scheduling::PerConnection,
+            drain_timeout: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+// How messages are spread over the workers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Scheduling {
+    // Each connection is served by one worker, which runs its messages in order
+    #[default]
+    PerConnection,
+    // Messages are queued on the worker of their connection and idle workers steal them, so a busy
+    // connection is spread over every worker. Its messages can then run in parallel and out of
+    // order, those of topics routed by key keep their order.
+    WorkStealing,
+}
+
+#[derive(Clone, Debug)]
+pub struct SocketOptions {
+    pub backlog: i32,
+    pub reuse_address: bool,
+    // Only applies to IPv6 addresses, None keeps the system default
+    pub only_v6: Option<bool>,
+    pub nodelay: bool,
+    pub keepalive: Option<Duration>,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            backlog: 1024,
+            reuse_address: true,
+            only_v6: None,
+            nodelay: true,
+            keepalive: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+        }
+    }
+}
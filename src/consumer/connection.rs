@@ -0,0 +1,345 @@
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Write},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, atomic::AtomicUsize},
+    time::Instant,
+};
+
+use anyhow::{Result, bail};
+use mio::net::TcpStream;
+
+use super::{ConsumerConfig, credits::Credits};
+use crate::frame::{self, FrameKind};
+
+// Open connections per peer address
+#[derive(Default)]
+pub struct ConnectionTracker {
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl ConnectionTracker {
+    // Returns false if the peer already holds `max` connections
+    pub fn track(&mut self, ip: IpAddr, max: usize) -> bool {
+        let count = self.per_ip.entry(ip).or_insert(0);
+        if *count >= max {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    pub fn release(&mut self, ip: IpAddr) {
+        if let Some(count) = self.per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+// State of a connection owned by the reactor: buffered non blocking reads and writes, timers and
+// the credits of the worker it was assigned to once the handshake succeeded
+pub struct Connection {
+    pub stream: TcpStream,
+    peer: SocketAddr,
+    read_buf: Vec<u8>,
+    // Set while read_buf holds the beginning of a frame
+    partial_since: Option<Instant>,
+    write_buf: Vec<u8>,
+    last_received: Instant,
+    // Heartbeats only keep the connection from being dropped as dead, it is idle without messages
+    last_message: Instant,
+    last_sent: Instant,
+    worker: Option<usize>,
+    credits: Option<Credits>,
+    // Topics the consumer doesn't know, accepted for its fallback handler
+    unknown_topics: HashMap<u16, Arc<str>>,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream, peer: SocketAddr) -> Self {
+        let now = Instant::now();
+        Self {
+            stream,
+            peer,
+            read_buf: Vec::new(),
+            partial_since: None,
+            write_buf: Vec::new(),
+            last_received: now,
+            last_message: now,
+            last_sent: now,
+            worker: None,
+            credits: None,
+            unknown_topics: HashMap::new(),
+        }
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    // Worker serving this connection, None until the handshake succeeded
+    pub fn worker(&self) -> Option<usize> {
+        self.worker
+    }
+
+    // Count of the connection's messages held back by a limit, set on their credits
+    pub fn parked(&self) -> Arc<AtomicUsize> {
+        self.credits
+            .as_ref()
+            .map_or_else(Arc::default, Credits::parked)
+    }
+
+    // Reads until the socket would block, pushing every complete frame but heartbeats to `frames`.
+    // Returns false once the peer closed the connection.
+    pub fn read(
+        &mut self,
+        config: &ConsumerConfig,
+        scratch: &mut [u8],
+        frames: &mut Vec<Vec<u8>>,
+    ) -> Result<bool> {
+        loop {
+            match self.stream.read(scratch) {
+                Ok(0) => {
+                    if !self.read_buf.is_empty() {
+                        bail!("Connection closed in the middle of a frame");
+                    }
+                    return Ok(false);
+                }
+                Ok(n) => {
+                    self.read_buf.extend_from_slice(&scratch[..n]);
+                    self.split_frames(config, frames)?;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn split_frames(&mut self, config: &ConsumerConfig, frames: &mut Vec<Vec<u8>>) -> Result<()> {
+        let now = Instant::now();
+        let mut start = 0;
+
+        while self.read_buf.len() - start >= frame::HEADER_LEN {
+            let frame_size = frame::size(&self.read_buf[start..], config.max_frame_size)?;
+            if self.read_buf.len() - start < frame_size {
+                break;
+            }
+
+            let buf = self.read_buf[start..start + frame_size].to_vec();
+            start += frame_size;
+            self.last_received = now;
+
+            if buf[0] == FrameKind::Heartbeat as u8 {
+                frame::decode(&buf)?;
+                continue;
+            }
+            if buf[0] == FrameKind::Message as u8 {
+                self.last_message = now;
+            }
+            frames.push(buf);
+        }
+
+        self.read_buf.drain(..start);
+        if self.read_buf.is_empty() {
+            self.partial_since = None;
+        } else if start > 0 || self.partial_since.is_none() {
+            self.partial_since = Some(now);
+        }
+        Ok(())
+    }
+
+    pub fn send(&mut self, kind: FrameKind, topic: u16, payload: &[u8]) -> Result<()> {
+        self.write_buf.extend(frame::encode(kind, topic, payload));
+        self.last_sent = Instant::now();
+        self.flush()
+    }
+
+    // Writes the buffered frames until the socket would block, the rest goes on the next
+    // writable event
+    pub fn flush(&mut self) -> Result<()> {
+        while !self.write_buf.is_empty() {
+            match self.stream.write(&self.write_buf) {
+                Ok(0) => bail!("Connection closed while writing"),
+                Ok(n) => {
+                    self.write_buf.drain(..n);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+
+    // Completes the handshake, credits are then granted out of `load`, the load of `worker`, and
+    // shared with the other connections of `wanting`
+    pub fn welcome(
+        &mut self,
+        worker: usize,
+        load: Arc<AtomicUsize>,
+        wanting: Arc<AtomicUsize>,
+        unknown_topics: Vec<(String, u16)>,
+        config: &ConsumerConfig,
+    ) -> Result<()> {
+        self.send(FrameKind::Welcome, 0, &[])?;
+        self.unknown_topics = unknown_topics
+            .into_iter()
+            .map(|(name, id)| (id, name.into()))
+            .collect();
+        self.worker = Some(worker);
+        self.credits = Some(Credits::new(
+            load,
+            wanting,
+            config.worker_queue_size,
+            config.credit_window,
+        ));
+        Ok(())
+    }
+
+    pub fn grant_credits(&mut self) -> Result<()> {
+        let granted = match &mut self.credits {
+            Some(credits) => credits.grant(),
+            None => 0,
+        };
+        if granted > 0 {
+            self.send(FrameKind::Credit, 0, &granted.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn demand_credits(&mut self) {
+        if let Some(credits) = &mut self.credits {
+            credits.demand();
+        }
+    }
+
+    // Takes back the credits of a connection holding more than its share while another one of its
+    // worker starves
+    pub fn revoke_excess_credits(&mut self) -> Result<()> {
+        if self
+            .credits
+            .as_ref()
+            .is_some_and(|credits| credits.is_over_share())
+        {
+            self.revoke_credits()?;
+        }
+        Ok(())
+    }
+
+    fn revoke_credits(&mut self) -> Result<()> {
+        if self
+            .credits
+            .as_mut()
+            .is_some_and(|credits| credits.revoke())
+        {
+            self.send(FrameKind::Revoke, 0, &[])?;
+        }
+        Ok(())
+    }
+
+    // Payload of a Return frame, the credits given back after a Revoke
+    pub fn return_credits(&mut self, payload: &[u8]) -> Result<()> {
+        let count = u32::from_be_bytes(payload.try_into()?);
+        match &mut self.credits {
+            Some(credits) => credits.returned(count),
+            None => bail!("Credits returned before the handshake"),
+        }
+    }
+
+    // Name of a topic accepted for the fallback handler
+    pub fn unknown_topic(&self, id: u16) -> Option<Arc<str>> {
+        self.unknown_topics.get(&id).cloned()
+    }
+
+    // True if the producer asked for credits and cannot send anything until more are granted
+    pub fn is_starved(&self) -> bool {
+        self.credits
+            .as_ref()
+            .is_some_and(|credits| credits.is_starved())
+    }
+
+    pub fn consume_credit(&mut self) -> Result<()> {
+        match &mut self.credits {
+            Some(credits) => credits.consume(),
+            None => Ok(()),
+        }
+    }
+
+    // Earliest instant at which poll_timers has something to do
+    pub fn deadline(&self, config: &ConsumerConfig) -> Option<Instant> {
+        let idle_deadline = config
+            .idle_timeout
+            .map(|timeout| self.last_message + timeout);
+        let dead_deadline = config
+            .heartbeat_interval
+            .map(|interval| self.last_received + interval * config.heartbeat_misses);
+        // Heartbeats only start once the producer got its Welcome
+        let heartbeat_deadline = config
+            .heartbeat_interval
+            .filter(|_| self.worker.is_some())
+            .map(|interval| self.last_sent + interval);
+        let read_deadline = self
+            .partial_since
+            .zip(config.read_timeout)
+            .map(|(since, timeout)| since + timeout);
+        let revoke_deadline = config
+            .credit_idle_timeout
+            .filter(|_| {
+                self.credits
+                    .as_ref()
+                    .is_some_and(|credits| credits.is_revocable())
+            })
+            .map(|timeout| self.last_message + timeout);
+
+        [
+            idle_deadline,
+            dead_deadline,
+            heartbeat_deadline,
+            read_deadline,
+            revoke_deadline,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    // Sends a heartbeat when due, revokes the credits left unused for `credit_idle_timeout` and
+    // fails if the peer stayed silent or is dripping a frame, returns the next deadline
+    pub fn poll_timers(
+        &mut self,
+        now: Instant,
+        config: &ConsumerConfig,
+    ) -> Result<Option<Instant>> {
+        if let Some(timeout) = config.idle_timeout
+            && now >= self.last_message + timeout
+        {
+            bail!("Idle for more than {:?}", timeout);
+        }
+        if let Some(interval) = config.heartbeat_interval
+            && now >= self.last_received + interval * config.heartbeat_misses
+        {
+            bail!("Missed {} heartbeats", config.heartbeat_misses);
+        }
+        if let Some((since, timeout)) = self.partial_since.zip(config.read_timeout)
+            && now >= since + timeout
+        {
+            bail!("Frame not received within {:?}", timeout);
+        }
+        if let Some(interval) = config.heartbeat_interval
+            && self.worker.is_some()
+            && now >= self.last_sent + interval
+        {
+            self.send(FrameKind::Heartbeat, 0, &[])?;
+        }
+        if let Some(timeout) = config.credit_idle_timeout
+            && now >= self.last_message + timeout
+        {
+            self.revoke_credits()?;
+        }
+        Ok(self.deadline(config))
+    }
+}
@@ -0,0 +1,77 @@
+use std::{net::SocketAddr, time::SystemTime};
+
+use super::DispatchError;
+use crate::{
+    frame::{Envelope, Headers},
+    topic::TopicEnum,
+};
+
+// Metadata of the message being handled, given to handlers taking &MessageContext as their last
+// parameter
+#[derive(Debug, Clone)]
+pub struct MessageContext {
+    pub topic: &'static str,
+    // Set by the producer, the same across retries and redeliveries to another consumer
+    pub id: u64,
+    // Random id of the producer process, 0 for messages pulled from a broker
+    pub producer: u64,
+    pub headers: Headers,
+    pub peer: SocketAddr,
+    pub received_at: SystemTime,
+    // Deliveries to the handler so far, including this one
+    pub attempt: u32,
+}
+
+impl MessageContext {
+    // Splits the payload of a Message frame into its context and the encoded value
+    pub(crate) fn open<T: TopicEnum>(
+        topic: T,
+        payload: &[u8],
+        peer: SocketAddr,
+        received_at: SystemTime,
+        attempt: u32,
+    ) -> Result<(Self, &[u8]), DispatchError> {
+        let message = Envelope::decode(payload).map_err(DispatchError::decode)?;
+        let context = Self {
+            topic: topic.name(),
+            id: message.id,
+            producer: message.producer,
+            headers: message.headers().map_err(DispatchError::decode)?,
+            peer,
+            received_at,
+            attempt,
+        };
+        Ok((context, message.body))
+    }
+
+    // Context and body of a message given up on. One whose envelope doesn't decode is given with
+    // its whole payload as body, no headers and an id and producer of 0.
+    pub(crate) fn open_or_raw<T: TopicEnum>(
+        topic: T,
+        payload: &[u8],
+        peer: SocketAddr,
+        received_at: SystemTime,
+        attempt: u32,
+    ) -> (Self, &[u8]) {
+        Self::open(topic, payload, peer, received_at, attempt).unwrap_or_else(|_| {
+            let context = Self {
+                topic: topic.name(),
+                id: 0,
+                producer: 0,
+                headers: Headers::new(),
+                peer,
+                received_at,
+                attempt,
+            };
+            (context, payload)
+        })
+    }
+
+    // Value of the first header called `name`
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
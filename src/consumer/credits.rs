@@ -0,0 +1,284 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{Result, bail};
+
+// Credits of one connection. Granted credits are counted in the load of the worker serving the
+// connection until the matching message is processed, so a producer can never have more
+// messages in flight than the worker has free queue slots. The worker releases its load itself.
+//
+// Credits are only granted once the producer sent a Demand, and each connection wanting some gets
+// at most its share of the worker's capacity. Those of a connection gone idle are revoked, as well
+// as those over its share when another connection of the worker starves, so a few quiet
+// connections can't hold the whole capacity. Messages held back by a topic limit leave the load
+// of the worker and count in the window of their connection instead.
+pub struct Credits {
+    load: Arc<AtomicUsize>,
+    // Connections of the same worker wanting credits, which share its capacity
+    wanting: Arc<AtomicUsize>,
+    capacity: usize,
+    window: u32,
+    outstanding: u32,
+    // Messages of the connection held back by a limit
+    parked: Arc<AtomicUsize>,
+    wanted: bool,
+    // Set from the Revoke until the producer returns its credits
+    revoking: bool,
+}
+
+impl Credits {
+    pub fn new(
+        load: Arc<AtomicUsize>,
+        wanting: Arc<AtomicUsize>,
+        capacity: usize,
+        window: u32,
+    ) -> Self {
+        Self {
+            load,
+            wanting,
+            capacity,
+            window,
+            outstanding: 0,
+            parked: Arc::new(AtomicUsize::new(0)),
+            wanted: false,
+            revoking: false,
+        }
+    }
+
+    pub fn parked(&self) -> Arc<AtomicUsize> {
+        self.parked.clone()
+    }
+
+    pub fn demand(&mut self) {
+        if !self.wanted {
+            self.wanted = true;
+            self.wanting.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Reserves free worker capacity once half of the window is used, returns the credits to send
+    pub fn grant(&mut self) -> u32 {
+        if !self.wanted || self.revoking {
+            return 0;
+        }
+        let window = self.window.min(self.share() as u32);
+        let used = self.outstanding + self.parked.load(Ordering::Relaxed) as u32;
+        if used > window / 2 {
+            return 0;
+        }
+
+        let wanted = (window - used) as usize;
+        let mut load = self.load.load(Ordering::Relaxed);
+        loop {
+            let granted = wanted.min(self.capacity.saturating_sub(load));
+            if granted == 0 {
+                return 0;
+            }
+            match self.load.compare_exchange_weak(
+                load,
+                load + granted,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    self.outstanding += granted as u32;
+                    return granted as u32;
+                }
+                Err(current) => load = current,
+            }
+        }
+    }
+
+    // True if the producer asked for credits and cannot send anything until more are granted
+    pub fn is_starved(&self) -> bool {
+        self.wanted && !self.revoking && self.outstanding == 0
+    }
+
+    pub fn consume(&mut self) -> Result<()> {
+        if self.outstanding == 0 {
+            bail!("Producer sent a message without credit");
+        }
+        self.outstanding -= 1;
+        Ok(())
+    }
+
+    // True if the connection holds credits it could give back
+    pub fn is_revocable(&self) -> bool {
+        self.wanted && !self.revoking && self.outstanding > 0
+    }
+
+    // True if the connection holds more than its share, which is taken back when another one of
+    // the worker starves
+    pub fn is_over_share(&self) -> bool {
+        self.is_revocable() && self.outstanding as usize > self.share()
+    }
+
+    fn share(&self) -> usize {
+        (self.capacity / self.wanting.load(Ordering::Relaxed).max(1)).max(1)
+    }
+
+    // Returns true if a Revoke must be sent, the connection stops sharing the capacity until its
+    // next Demand
+    pub fn revoke(&mut self) -> bool {
+        if !self.is_revocable() {
+            return false;
+        }
+        self.wanted = false;
+        self.wanting.fetch_sub(1, Ordering::Relaxed);
+        self.revoking = true;
+        true
+    }
+
+    // Credits given up by the producer after a Revoke, messages it sent meanwhile consumed theirs
+    pub fn returned(&mut self, count: u32) -> Result<()> {
+        if !self.revoking {
+            bail!("Producer returned credits that were not revoked");
+        }
+        if count > self.outstanding {
+            bail!(
+                "Producer returned {} credits, only {} were outstanding",
+                count,
+                self.outstanding
+            );
+        }
+        self.outstanding -= count;
+        self.load.fetch_sub(count as usize, Ordering::Relaxed);
+        self.revoking = false;
+        Ok(())
+    }
+}
+
+impl Drop for Credits {
+    fn drop(&mut self) {
+        self.load
+            .fetch_sub(self.outstanding as usize, Ordering::Relaxed);
+        if self.wanted {
+            self.wanting.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn worker() -> (Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)))
+    }
+
+    #[test]
+    fn credits_are_only_granted_on_demand() {
+        let (load, wanting) = worker();
+        let mut credits = Credits::new(load.clone(), wanting.clone(), 100, 10);
+        assert_eq!(credits.grant(), 0);
+        assert!(!credits.is_starved());
+
+        credits.demand();
+        assert!(credits.is_starved());
+        assert_eq!(credits.grant(), 10);
+        assert_eq!(load.load(Ordering::Relaxed), 10);
+        assert_eq!(wanting.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn the_window_is_refilled_once_half_used() {
+        let (load, wanting) = worker();
+        let mut credits = Credits::new(load.clone(), wanting, 100, 10);
+        credits.demand();
+        assert_eq!(credits.grant(), 10);
+
+        for _ in 0..4 {
+            credits.consume().unwrap();
+        }
+        assert_eq!(credits.grant(), 0);
+        credits.consume().unwrap();
+        assert_eq!(credits.grant(), 5);
+        // Consumed messages stay in the load until the worker processes them
+        assert_eq!(load.load(Ordering::Relaxed), 15);
+    }
+
+    #[test]
+    fn parked_messages_count_in_the_window() {
+        let (load, wanting) = worker();
+        let mut credits = Credits::new(load.clone(), wanting, 100, 10);
+        let parked = credits.parked();
+        credits.demand();
+        assert_eq!(credits.grant(), 10);
+        for _ in 0..10 {
+            credits.consume().unwrap();
+        }
+
+        // Held back by a limit, the messages leave the load of the worker but not the window
+        parked.store(10, Ordering::Relaxed);
+        load.fetch_sub(10, Ordering::Relaxed);
+        assert_eq!(credits.grant(), 0);
+        parked.store(4, Ordering::Relaxed);
+        assert_eq!(credits.grant(), 6);
+        assert_eq!(load.load(Ordering::Relaxed), 6);
+    }
+
+    #[test]
+    fn messages_need_a_credit() {
+        let (load, wanting) = worker();
+        let mut credits = Credits::new(load, wanting, 100, 10);
+        assert!(credits.consume().is_err());
+    }
+
+    #[test]
+    fn connections_share_the_capacity_of_their_worker() {
+        let (load, wanting) = worker();
+        let mut first = Credits::new(load.clone(), wanting.clone(), 8, 10);
+        let mut second = Credits::new(load.clone(), wanting.clone(), 8, 10);
+        first.demand();
+        second.demand();
+        assert_eq!(first.grant(), 4);
+        assert_eq!(second.grant(), 4);
+        assert_eq!(load.load(Ordering::Relaxed), 8);
+
+        // A third one finds the worker full, the others hold more than their share
+        let mut third = Credits::new(load.clone(), wanting.clone(), 8, 10);
+        third.demand();
+        assert_eq!(third.grant(), 0);
+        assert!(third.is_starved());
+        assert!(first.is_over_share());
+        assert!(second.is_over_share());
+    }
+
+    #[test]
+    fn revoked_credits_return_to_the_worker() {
+        let (load, wanting) = worker();
+        let mut credits = Credits::new(load.clone(), wanting.clone(), 100, 10);
+        assert!(!credits.revoke());
+        assert!(credits.returned(0).is_err());
+
+        credits.demand();
+        credits.grant();
+        credits.consume().unwrap();
+        assert!(credits.revoke());
+        assert!(!credits.revoke());
+        assert_eq!(wanting.load(Ordering::Relaxed), 0);
+        // No more credits until the producer returned the others and asks again
+        assert_eq!(credits.grant(), 0);
+        assert!(!credits.is_starved());
+
+        assert!(credits.returned(10).is_err());
+        credits.returned(9).unwrap();
+        assert_eq!(load.load(Ordering::Relaxed), 1);
+        credits.demand();
+        assert_eq!(credits.grant(), 10);
+    }
+
+    #[test]
+    fn dropped_connections_release_their_credits() {
+        let (load, wanting) = worker();
+        let mut credits = Credits::new(load.clone(), wanting.clone(), 100, 10);
+        credits.demand();
+        credits.grant();
+        credits.consume().unwrap();
+        drop(credits);
+        assert_eq!(load.load(Ordering::Relaxed), 1);
+        assert_eq!(wanting.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+
+use super::MessageContext;
+use crate::topic::TopicEnum;
+
+// Identifies a message across redeliveries, whichever connection or consumer it comes from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DedupKey {
+    pub topic: u16,
+    pub producer: u64,
+    pub id: u64,
+}
+
+impl DedupKey {
+    pub fn new<T: TopicEnum>(topic: T, context: &MessageContext) -> Self {
+        Self {
+            topic: topic.id(),
+            producer: context.producer,
+            id: context.id,
+        }
+    }
+}
+
+// Messages acked by a consumer, set with a #[dedup] field. Messages already in the store are acked
+// without calling their handler. Duplicates delivered at the same time on two connections can
+// still both run.
+pub trait DedupStore: Send + Sync {
+    fn contains(&self, key: &DedupKey) -> bool;
+
+    fn insert(&self, key: DedupKey) -> Result<()>;
+}
+
+// Lets consumers share a store
+impl<S: DedupStore + ?Sized> DedupStore for Arc<S> {
+    fn contains(&self, key: &DedupKey) -> bool {
+        (**self).contains(key)
+    }
+
+    fn insert(&self, key: DedupKey) -> Result<()> {
+        (**self).insert(key)
+    }
+}
+
+// Remembers the last `capacity` acked messages
+pub struct MemoryDedup {
+    window: Mutex<Window>,
+}
+
+impl MemoryDedup {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            window: Mutex::new(Window::new(capacity)),
+        }
+    }
+}
+
+impl DedupStore for MemoryDedup {
+    fn contains(&self, key: &DedupKey) -> bool {
+        self.window.lock().unwrap().contains(key)
+    }
+
+    fn insert(&self, key: DedupKey) -> Result<()> {
+        self.window.lock().unwrap().insert(key);
+        Ok(())
+    }
+}
+
+// Remembers the last `capacity` acked messages across restarts. Keys are appended to the file,
+// which is rewritten with the window alone once it holds twice as many.
+pub struct FileDedup {
+    path: PathBuf,
+    state: Mutex<FileState>,
+}
+
+struct FileState {
+    window: Window,
+    file: File,
+    records: usize,
+}
+
+// Record layout (big endian): topic u16 | producer u64 | id u64
+const RECORD_LEN: usize = 18;
+
+impl FileDedup {
+    pub fn open(path: impl AsRef<Path>, capacity: usize) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut window = Window::new(capacity);
+
+        match File::open(&path) {
+            Ok(mut file) => {
+                let mut buf = Vec::new();
+                file.read_to_end(&mut buf)?;
+                // A record cut short by a crash is dropped
+                for record in buf.chunks_exact(RECORD_LEN) {
+                    window.insert(decode_record(record));
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        // Compacted right away, so the file only holds the window
+        let file = rewrite(&path, &window)?;
+        let records = window.order.len();
+        Ok(Self {
+            path,
+            state: Mutex::new(FileState {
+                window,
+                file,
+                records,
+            }),
+        })
+    }
+}
+
+impl DedupStore for FileDedup {
+    fn contains(&self, key: &DedupKey) -> bool {
+        self.state.lock().unwrap().window.contains(key)
+    }
+
+    fn insert(&self, key: DedupKey) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.window.insert(key) {
+            return Ok(());
+        }
+
+        if state.records >= 2 * state.window.capacity {
+            state.file = rewrite(&self.path, &state.window)?;
+            state.records = state.window.order.len();
+        } else {
+            state.file.write_all(&encode_record(&key))?;
+            state.records += 1;
+        }
+        Ok(())
+    }
+}
+
+// Writes the window to a temporary file then renames it over the store, returns the file opened
+// for appends
+fn rewrite(path: &Path, window: &Window) -> Result<File> {
+    let tmp = path.with_extension("tmp");
+    let mut file = BufWriter::new(File::create(&tmp)?);
+    for key in &window.order {
+        file.write_all(&encode_record(key))?;
+    }
+    file.into_inner()?.sync_all()?;
+    fs::rename(&tmp, path)?;
+
+    Ok(OpenOptions::new().append(true).open(path)?)
+}
+
+fn encode_record(key: &DedupKey) -> [u8; RECORD_LEN] {
+    let mut record = [0u8; RECORD_LEN];
+    record[..2].copy_from_slice(&key.topic.to_be_bytes());
+    record[2..10].copy_from_slice(&key.producer.to_be_bytes());
+    record[10..].copy_from_slice(&key.id.to_be_bytes());
+    record
+}
+
+fn decode_record(record: &[u8]) -> DedupKey {
+    let (topic, rest) = record.split_at(2);
+    let (producer, id) = rest.split_at(8);
+    DedupKey {
+        topic: u16::from_be_bytes(topic.try_into().unwrap()),
+        producer: u64::from_be_bytes(producer.try_into().unwrap()),
+        id: u64::from_be_bytes(id.try_into().unwrap()),
+    }
+}
+
+// Last `capacity` keys, the oldest one is forgotten first
+struct Window {
+    capacity: usize,
+    keys: HashSet<DedupKey>,
+    order: VecDeque<DedupKey>,
+}
+
+impl Window {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            keys: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn contains(&self, key: &DedupKey) -> bool {
+        self.keys.contains(key)
+    }
+
+    // Returns false if the key was already there
+    fn insert(&mut self, key: DedupKey) -> bool {
+        if !self.keys.insert(key) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > self.capacity
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.keys.remove(&oldest);
+        }
+        true
+    }
+}
+
+// Whether the message was already acked, in which case it is acked again without running it
+pub(crate) fn seen(store: Option<&dyn DedupStore>, key: &DedupKey) -> bool {
+    store.is_some_and(|store| store.contains(key))
+}
+
+// Records an acked message, a store that fails to record it only lets a later duplicate through
+pub(crate) fn record(store: Option<&dyn DedupStore>, key: DedupKey, topic: &str) {
+    if let Some(store) = store
+        && let Err(err) = store.insert(key)
+    {
+        eprintln!(
+            "Failed to record {} message {} for deduplication: {}",
+            topic, key.id, err
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn key(id: u64) -> DedupKey {
+        DedupKey {
+            topic: 1,
+            producer: 2,
+            id,
+        }
+    }
+
+    // A store path of its own for each test, removed with its temporary file when dropped
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("pusu-dedup-{}-{}", process::id(), name));
+            let _ = fs::remove_file(&path);
+            Self(path)
+        }
+
+        fn records(&self) -> usize {
+            fs::metadata(&self.0).unwrap().len() as usize / RECORD_LEN
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+            let _ = fs::remove_file(self.0.with_extension("tmp"));
+        }
+    }
+
+    #[test]
+    fn memory_store_forgets_the_oldest_keys() {
+        let store = MemoryDedup::new(3);
+        for id in 0..4 {
+            store.insert(key(id)).unwrap();
+        }
+        assert!(!store.contains(&key(0)));
+        assert!((1..4).all(|id| store.contains(&key(id))));
+
+        // A key inserted again doesn't push another one out
+        store.insert(key(3)).unwrap();
+        assert!(store.contains(&key(1)));
+    }
+
+    #[test]
+    fn keys_differ_by_topic_and_producer() {
+        let store = MemoryDedup::new(10);
+        store.insert(key(1)).unwrap();
+        assert!(!store.contains(&DedupKey { topic: 9, ..key(1) }));
+        assert!(!store.contains(&DedupKey {
+            producer: 9,
+            ..key(1)
+        }));
+    }
+
+    #[test]
+    fn file_store_survives_a_restart() {
+        let path = TempPath::new("restart");
+        let store = FileDedup::open(&path.0, 3).unwrap();
+        for id in 0..4 {
+            store.insert(key(id)).unwrap();
+        }
+        drop(store);
+
+        let store = FileDedup::open(&path.0, 3).unwrap();
+        assert!(!store.contains(&key(0)));
+        assert!((1..4).all(|id| store.contains(&key(id))));
+        // Compacted to the window when opened
+        assert_eq!(path.records(), 3);
+    }
+
+    #[test]
+    fn file_store_is_compacted_at_twice_its_capacity() {
+        let path = TempPath::new("compaction");
+        let store = FileDedup::open(&path.0, 2).unwrap();
+        for id in 0..4 {
+            store.insert(key(id)).unwrap();
+        }
+        assert_eq!(path.records(), 4);
+        store.insert(key(4)).unwrap();
+        assert_eq!(path.records(), 2);
+        // Duplicates are not appended
+        store.insert(key(4)).unwrap();
+        assert_eq!(path.records(), 2);
+        assert!(store.contains(&key(3)) && store.contains(&key(4)));
+    }
+
+    #[test]
+    fn records_cut_short_are_dropped() {
+        let path = TempPath::new("truncated");
+        let mut buf = encode_record(&key(1)).to_vec();
+        buf.extend(&encode_record(&key(2))[..RECORD_LEN - 1]);
+        fs::write(&path.0, buf).unwrap();
+
+        let store = FileDedup::open(&path.0, 10).unwrap();
+        assert!(store.contains(&key(1)));
+        assert!(!store.contains(&key(2)));
+        assert_eq!(path.records(), 1);
+    }
+}
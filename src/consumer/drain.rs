@@ -0,0 +1,101 @@
+use std::{
+    sync::{
+        OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// How often the reactor checks whether the workers are done draining, also given to them past the
+// deadline
+const DRAIN_POLL: Duration = Duration::from_millis(10);
+
+// What happened to the messages still pending when the consumer was asked to stop, returned by
+// Consumer::run, ConsumerHandle::join and AsyncConsumer::serve
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DrainSummary {
+    // Messages done with since the shutdown: acked, or nacked for good by their handler
+    pub completed: usize,
+    // Messages nacked at the deadline: still queued, waiting for a retry, gathered in a batch or
+    // held back by a limit. They are handed to the dead letter handler of their topic, those of
+    // unknown topics are only counted.
+    pub nacked: usize,
+    // Messages of the workers still running a handler at the deadline, which are left to finish
+    // it in the background and nack the others afterwards
+    pub unfinished: usize,
+}
+
+// Shared by the reactor and its workers once a shutdown is requested
+#[derive(Default)]
+pub struct Drain {
+    // None until the drain starts, then its deadline if there is one
+    deadline: OnceLock<Option<Instant>>,
+    // Messages handed to the workers and not done with yet
+    held: AtomicUsize,
+    // Credits released since the drain started, including those of nacked messages
+    released: AtomicUsize,
+    nacked: AtomicUsize,
+}
+
+impl Drain {
+    pub fn start(&self, timeout: Option<Duration>) {
+        let _ = self
+            .deadline
+            .set(timeout.map(|timeout| Instant::now() + timeout));
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline.get().copied().flatten()
+    }
+
+    // Idle workers wake up at the deadline itself to nack what they hold, they are given a poll to
+    // do so before being taken for stuck in a handler
+    pub fn cutoff(&self) -> Option<Instant> {
+        self.deadline().map(|deadline| deadline + DRAIN_POLL)
+    }
+
+    pub fn hold(&self) {
+        self.held.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn release(&self) {
+        self.held.fetch_sub(1, Ordering::Relaxed);
+        if self.deadline.get().is_some() {
+            self.released.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn nacked(&self, count: usize) {
+        self.nacked.fetch_add(count, Ordering::Relaxed);
+    }
+
+    // Waits for the worker threads until the deadline, those still running are detached
+    pub fn wait(&self, threads: Vec<JoinHandle<()>>) -> DrainSummary {
+        let mut running = threads;
+        if let Some(deadline) = self.cutoff() {
+            while !running.is_empty() && Instant::now() < deadline {
+                running.retain(|thread| !thread.is_finished());
+                thread::sleep(DRAIN_POLL.min(deadline.saturating_duration_since(Instant::now())));
+            }
+        }
+
+        // Without a deadline every worker is waited for
+        for thread in running {
+            if self.deadline().is_none() || thread.is_finished() {
+                let _ = thread.join();
+            }
+        }
+
+        self.summary()
+    }
+
+    pub fn summary(&self) -> DrainSummary {
+        let nacked = self.nacked.load(Ordering::Relaxed);
+        DrainSummary {
+            completed: self.released.load(Ordering::Relaxed).saturating_sub(nacked),
+            nacked,
+            unfinished: self.held.load(Ordering::Relaxed),
+        }
+    }
+}
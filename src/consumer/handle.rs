@@ -0,0 +1,78 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::JoinHandle,
+};
+
+use anyhow::{Result, anyhow};
+use mio::Waker;
+
+use super::DrainSummary;
+
+// Stop request shared by the reactor, the signal handler and ConsumerHandle. Requesting it wakes
+// up the reactor if it is waiting for events.
+#[derive(Default)]
+pub struct Shutdown {
+    requested: AtomicBool,
+    waker: OnceLock<Arc<Waker>>,
+}
+
+impl Shutdown {
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        if let Some(waker) = self.waker.get() {
+            let _ = waker.wake();
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_waker(&self, waker: Arc<Waker>) {
+        let _ = self.waker.set(waker);
+    }
+}
+
+pub struct ConsumerHandle {
+    local_addr: SocketAddr,
+    shutdown: Arc<Shutdown>,
+    thread: JoinHandle<Result<DrainSummary>>,
+}
+
+impl ConsumerHandle {
+    pub(crate) fn new(
+        local_addr: SocketAddr,
+        shutdown: Arc<Shutdown>,
+        thread: JoinHandle<Result<DrainSummary>>,
+    ) -> Self {
+        Self {
+            local_addr,
+            shutdown,
+            thread,
+        }
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // Stops accepting connections and closes the open ones, join waits for the workers to finish
+    // the messages already queued, until the drain deadline
+    pub fn shutdown(&self) {
+        self.shutdown.request();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    pub fn join(self) -> Result<DrainSummary> {
+        self.thread
+            .join()
+            .map_err(|_| anyhow!("Consumer thread panicked"))?
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    any::Any,
+    fmt,
+    panic::{self, AssertUnwindSafe},
+};
+
+use anyhow::{Result, anyhow};
+
+use super::MessageContext;
+
+// Return types accepted for handlers: () always acks the message, a Result nacks it on Err
+pub trait HandlerResult {
+    fn into_result(self) -> Result<()>;
+}
+
+impl HandlerResult for () {
+    fn into_result(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<E: Into<anyhow::Error>> HandlerResult for Result<(), E> {
+    fn into_result(self) -> Result<()> {
+        self.map_err(Into::into)
+    }
+}
+
+// Outcome of a topic with several handlers, which all run even if one of them fails. The message
+// is nacked with the first error if any failed.
+pub fn join_results(results: impl IntoIterator<Item = Result<()>>) -> Result<()> {
+    let mut errors = results
+        .into_iter()
+        .filter_map(Result::err)
+        .collect::<Vec<_>>();
+
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.remove(0)),
+        failed => Err(anyhow!(
+            "{} handlers failed, first: {:#}",
+            failed,
+            errors[0]
+        )),
+    }
+}
+
+// Handlers take their state and value, optionally followed by &MessageContext. Both forms are
+// told apart by the marker, so #[consumer] doesn't need to see the handler's signature.
+pub struct WithContext;
+pub struct WithoutContext;
+
+pub trait Handler<Args, Marker> {
+    type Output;
+
+    fn call(&self, args: Args, context: &MessageContext) -> Self::Output;
+}
+
+// Async handlers of #[consumer(async)], the context stays borrowed while the handler runs
+pub trait AsyncHandler<Args, Marker> {
+    type Output;
+
+    fn call(&self, args: Args, context: &MessageContext) -> impl Future<Output = Self::Output>;
+}
+
+macro_rules! handler_impls {
+    ($($arg:ident),*) => {
+        #[allow(non_snake_case)]
+        impl<F, R, $($arg),*> Handler<($($arg,)*), WithoutContext> for F
+        where
+            F: Fn($($arg),*) -> R,
+        {
+            type Output = R;
+
+            fn call(&self, ($($arg,)*): ($($arg,)*), _context: &MessageContext) -> R {
+                self($($arg),*)
+            }
+        }
+
+        #[allow(non_snake_case)]
+        impl<F, R, $($arg),*> Handler<($($arg,)*), WithContext> for F
+        where
+            F: Fn($($arg,)* &MessageContext) -> R,
+        {
+            type Output = R;
+
+            fn call(&self, ($($arg,)*): ($($arg,)*), context: &MessageContext) -> R {
+                self($($arg,)* context)
+            }
+        }
+
+        #[allow(non_snake_case)]
+        impl<F, R, $($arg),*> AsyncHandler<($($arg,)*), WithoutContext> for F
+        where
+            F: AsyncFn($($arg),*) -> R,
+        {
+            type Output = R;
+
+            async fn call(&self, ($($arg,)*): ($($arg,)*), _context: &MessageContext) -> R {
+                self($($arg),*).await
+            }
+        }
+
+        #[allow(non_snake_case)]
+        impl<F, R, $($arg),*> AsyncHandler<($($arg,)*), WithContext> for F
+        where
+            F: AsyncFn($($arg,)* &MessageContext) -> R,
+        {
+            type Output = R;
+
+            async fn call(&self, ($($arg,)*): ($($arg,)*), context: &MessageContext) -> R {
+                self($($arg,)* context).await
+            }
+        }
+    };
+}
+
+handler_impls!();
+handler_impls!(A);
+handler_impls!(A, B);
+
+// Why a message was not acked by Consumer::dispatch
+#[derive(Debug)]
+pub enum DispatchError {
+    // The payload does not decode as the topic's type, delivering it again cannot succeed
+    Decode(anyhow::Error),
+    // The handler returned an error, the message is nacked
+    Handler(anyhow::Error),
+}
+
+impl DispatchError {
+    pub fn decode<E: Into<anyhow::Error>>(err: E) -> Self {
+        DispatchError::Decode(err.into())
+    }
+}
+
+impl fmt::Display for DispatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DispatchError::Decode(err) => write!(f, "Failed to decode payload: {}", err),
+            DispatchError::Handler(err) => write!(f, "Handler failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for DispatchError {}
+
+// Runs a dispatch on the caller's thread, a panicking handler nacks its message instead of
+// unwinding through the worker
+pub(crate) fn catch_panic(
+    dispatch: impl FnOnce() -> Result<(), DispatchError>,
+) -> Result<(), DispatchError> {
+    panic::catch_unwind(AssertUnwindSafe(dispatch)).unwrap_or_else(|panic| Err(panicked(&panic)))
+}
+
+// A panicking batch handler nacks every message of the batch
+pub(crate) fn catch_panic_batch(
+    len: usize,
+    dispatch: impl FnOnce() -> Vec<Result<(), DispatchError>>,
+) -> Vec<Result<(), DispatchError>> {
+    panic::catch_unwind(AssertUnwindSafe(dispatch))
+        .unwrap_or_else(|panic| (0..len).map(|_| Err(panicked(&panic))).collect())
+}
+
+#[cfg(feature = "tokio")]
+pub(crate) async fn catch_panic_async(
+    dispatch: impl Future<Output = Result<(), DispatchError>>,
+) -> Result<(), DispatchError> {
+    let mut dispatch = std::pin::pin!(dispatch);
+    std::future::poll_fn(|cx| {
+        panic::catch_unwind(AssertUnwindSafe(|| dispatch.as_mut().poll(cx)))
+            .unwrap_or_else(|panic| std::task::Poll::Ready(Err(panicked(&panic))))
+    })
+    .await
+}
+
+// Runs the key function of a routed topic, a panicking one leaves the message without a key
+pub(crate) fn catch_panic_key(route_key: impl FnOnce() -> Option<u64>) -> Result<Option<u64>> {
+    panic::catch_unwind(AssertUnwindSafe(route_key))
+        .map_err(|panic| anyhow!("Key function panicked: {}", panic_message(&panic)))
+}
+
+fn panicked(panic: &Box<dyn Any + Send>) -> DispatchError {
+    DispatchError::Handler(anyhow!("Handler panicked: {}", panic_message(panic)))
+}
+
+pub(crate) fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause")
+}
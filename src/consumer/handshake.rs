@@ -0,0 +1,144 @@
+use anyhow::{Result, bail};
+
+use super::ConsumerConfig;
+use crate::frame::{self, FrameKind, Hello};
+
+// How the consumer resolves the topics announced by a producer
+pub struct TopicLookup<I, K> {
+    // Id of a topic of the consumer
+    pub topic_id: I,
+    // Whether an id belongs to a topic of the consumer
+    pub is_known: K,
+    // Topics the consumer doesn't know are accepted for its fallback handler
+    pub accept_unknown: bool,
+}
+
+// Checks the Hello frame and the topics it announces, returns the unknown ones that were accepted
+// with their id. The error is sent back in a Reject frame.
+pub fn accept_hello(
+    buf: &[u8],
+    config: &ConsumerConfig,
+    lookup: TopicLookup<impl Fn(&str) -> Option<u16>, impl Fn(u16) -> bool>,
+) -> Result<Vec<(String, u16)>> {
+    let frame = frame::decode(buf)?;
+    if frame.kind != FrameKind::Hello {
+        bail!("Expected a handshake, got a {:?} frame", frame.kind);
+    }
+
+    let hello: Hello = postcard::from_bytes(frame.payload)?;
+    check_topics(hello, config, lookup)
+}
+
+fn check_topics(
+    hello: Hello,
+    config: &ConsumerConfig,
+    lookup: TopicLookup<impl Fn(&str) -> Option<u16>, impl Fn(u16) -> bool>,
+) -> Result<Vec<(String, u16)>> {
+    let mut unknown = Vec::new();
+    for (name, id) in hello.topics {
+        if name.len() > config.max_topic_len {
+            bail!(
+                "Topic name of {} bytes exceeds the limit of {} bytes",
+                name.len(),
+                config.max_topic_len
+            );
+        }
+
+        match (lookup.topic_id)(&name) {
+            // Frames only carry the id, which must not be taken for one of the consumer's topics
+            None if lookup.accept_unknown && !(lookup.is_known)(id) => unknown.push((name, id)),
+            None => bail!("Unknown topic {}", name),
+            Some(expected) if expected != id => bail!(
+                "Topic id mismatch for {}: producer uses {}, consumer uses {}",
+                name,
+                id,
+                expected
+            ),
+            Some(_) => {}
+        }
+    }
+    Ok(unknown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::topic;
+
+    fn hello(topics: &[(&str, u16)]) -> Vec<u8> {
+        let hello = Hello {
+            topics: topics
+                .iter()
+                .map(|(name, id)| (name.to_string(), *id))
+                .collect(),
+        };
+        frame::encode(FrameKind::Hello, 0, &postcard::to_stdvec(&hello).unwrap())
+    }
+
+    // The consumer knows the topics "users" and "orders"
+    fn accept(buf: &[u8], accept_unknown: bool) -> Result<Vec<(String, u16)>> {
+        let known = [topic::id("users"), topic::id("orders")];
+        accept_hello(
+            buf,
+            &ConsumerConfig::default(),
+            TopicLookup {
+                topic_id: |name: &str| ["users", "orders"].contains(&name).then(|| topic::id(name)),
+                is_known: |id| known.contains(&id),
+                accept_unknown,
+            },
+        )
+    }
+
+    #[test]
+    fn known_topics_are_accepted() {
+        let buf = hello(&[
+            ("users", topic::id("users")),
+            ("orders", topic::id("orders")),
+        ]);
+        assert_eq!(accept(&buf, false).unwrap(), []);
+    }
+
+    #[test]
+    fn other_frames_are_rejected() {
+        let buf = frame::encode(FrameKind::Message, 0, &[]);
+        let err = accept(&buf, false).unwrap_err();
+        assert!(
+            err.to_string().starts_with("Expected a handshake"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn unknown_topics_are_rejected_without_a_fallback() {
+        let buf = hello(&[("users", topic::id("users")), ("other", topic::id("other"))]);
+        let err = accept(&buf, false).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown topic other");
+
+        assert_eq!(
+            accept(&buf, true).unwrap(),
+            [("other".to_string(), topic::id("other"))]
+        );
+    }
+
+    #[test]
+    fn ids_must_match_the_consumer() {
+        let buf = hello(&[("users", topic::id("users") ^ 1)]);
+        let err = accept(&buf, false).unwrap_err();
+        assert!(err.to_string().starts_with("Topic id mismatch"), "{}", err);
+    }
+
+    #[test]
+    fn unknown_topics_cannot_take_the_id_of_a_known_one() {
+        let buf = hello(&[("other", topic::id("orders"))]);
+        assert!(accept(&buf, true).is_err());
+    }
+
+    #[test]
+    fn long_topic_names_are_rejected() {
+        let name = "t".repeat(ConsumerConfig::default().max_topic_len + 1);
+        let buf = hello(&[(&name, topic::id(&name))]);
+        let err = accept(&buf, true).unwrap_err();
+        assert!(err.to_string().contains("exceeds the limit"), "{}", err);
+    }
+}
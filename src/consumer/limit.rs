@@ -0,0 +1,302 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use super::Consumer;
+use crate::topic::TopicEnum;
+
+// How often a worker checks again for a free slot of a topic at its concurrency cap
+const CONCURRENCY_POLL: Duration = Duration::from_millis(1);
+
+// Set per topic with #[rate_limit(per_sec = 100)] and #[max_concurrency(4)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TopicLimits {
+    // Messages handed to the handler per second across workers, in bursts of up to one second
+    pub per_sec: Option<u32>,
+    // Messages handled at the same time across workers
+    pub max_concurrency: Option<usize>,
+}
+
+// Limits of the topics of a consumer, shared by its workers
+pub struct Limits {
+    topics: HashMap<u16, Limiter>,
+}
+
+struct Limiter {
+    bucket: Option<Mutex<Bucket>>,
+    running: Option<(usize, AtomicUsize)>,
+}
+
+struct Bucket {
+    per_sec: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+// Slot of a message of a topic with a concurrency cap, freed when dropped
+pub struct Permit<'a> {
+    running: Option<&'a AtomicUsize>,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if let Some(running) = self.running {
+            running.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Limits {
+    pub fn new<C: Consumer<T>, T: TopicEnum>(consumer: &C) -> Self {
+        let now = Instant::now();
+        let topics = consumer
+            .topics()
+            .into_iter()
+            .filter_map(|topic| {
+                let limits = consumer.topic_limits(topic)?;
+                let limiter = Limiter {
+                    bucket: limits.per_sec.map(|per_sec| {
+                        let per_sec = per_sec.max(1) as f64;
+                        Mutex::new(Bucket {
+                            per_sec,
+                            tokens: per_sec,
+                            refilled: now,
+                        })
+                    }),
+                    running: limits
+                        .max_concurrency
+                        .map(|max| (max.max(1), AtomicUsize::new(0))),
+                };
+                Some((topic.id(), limiter))
+            })
+            .collect();
+        Self { topics }
+    }
+
+    // Takes a slot for a message of the topic, or tells when to try again
+    pub fn acquire(&self, topic: u16, now: Instant) -> Result<Permit<'_>, Instant> {
+        let Some(limiter) = self.topics.get(&topic) else {
+            return Ok(Permit { running: None });
+        };
+
+        let running = match &limiter.running {
+            Some((max, running)) => {
+                let taken = running.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                    (count < *max).then_some(count + 1)
+                });
+                if taken.is_err() {
+                    return Err(now + CONCURRENCY_POLL);
+                }
+                Some(running)
+            }
+            None => None,
+        };
+        // Built before the rate is checked so the slot is freed if the rate is exceeded
+        let permit = Permit { running };
+
+        if let Some(bucket) = &limiter.bucket {
+            bucket.lock().unwrap().take(now)?;
+        }
+        Ok(permit)
+    }
+}
+
+impl Bucket {
+    fn take(&mut self, now: Instant) -> Result<(), Instant> {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.per_sec);
+        self.refilled = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(now + Duration::from_secs_f64((1.0 - self.tokens) / self.per_sec))
+        }
+    }
+}
+
+// Messages held by a worker while their topic is over its limits, in the order they came. The
+// worker goes on with the messages of other topics meanwhile.
+pub struct Throttled<J> {
+    waiting: HashMap<u16, Waiting<J>>,
+}
+
+struct Waiting<J> {
+    jobs: VecDeque<J>,
+    due: Instant,
+}
+
+impl<J> Throttled<J> {
+    pub fn new() -> Self {
+        Self {
+            waiting: HashMap::new(),
+        }
+    }
+
+    // Whether jobs of the topic are held, new ones then have to wait behind them
+    pub fn is_waiting(&self, topic: u16) -> bool {
+        self.waiting.contains_key(&topic)
+    }
+
+    // Queues the job behind the ones of its topic, which must be waiting
+    pub fn push(&mut self, topic: u16, job: J) {
+        if let Some(waiting) = self.waiting.get_mut(&topic) {
+            waiting.jobs.push_back(job);
+        }
+    }
+
+    // Puts the job first in line for its topic, which is tried again at `due`
+    pub fn defer(&mut self, topic: u16, job: J, due: Instant) {
+        let waiting = self.waiting.entry(topic).or_insert_with(|| Waiting {
+            jobs: VecDeque::new(),
+            due,
+        });
+        waiting.jobs.push_front(job);
+        waiting.due = due;
+    }
+
+    pub fn pop(&mut self, topic: u16) -> Option<J> {
+        let waiting = self.waiting.get_mut(&topic)?;
+        let job = waiting.jobs.pop_front();
+        if waiting.jobs.is_empty() {
+            self.waiting.remove(&topic);
+        }
+        job
+    }
+
+    pub fn take_all(&mut self) -> Vec<J> {
+        self.waiting
+            .drain()
+            .flat_map(|(_, waiting)| waiting.jobs)
+            .collect()
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.waiting.values().map(|waiting| waiting.due).min()
+    }
+
+    pub fn due_topics(&self, now: Instant) -> Vec<u16> {
+        self.waiting
+            .iter()
+            .filter(|(_, waiting)| waiting.due <= now)
+            .map(|(topic, _)| *topic)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{consumer::ConsumerBuilder, topic};
+
+    fn limits(limits: TopicLimits) -> Limits {
+        let consumer = ConsumerBuilder::new()
+            .topic("users", |_: u32| {})
+            .limits("users", limits)
+            .build()
+            .unwrap();
+        Limits::new(&consumer)
+    }
+
+    #[test]
+    fn the_rate_allows_a_burst_then_refills() {
+        let limits = limits(TopicLimits {
+            per_sec: Some(10),
+            max_concurrency: None,
+        });
+        let users = topic::id("users");
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert!(limits.acquire(users, now).is_ok());
+        }
+        let due = limits.acquire(users, now).err().unwrap();
+        assert_eq!(due, now + Duration::from_millis(100));
+
+        assert!(limits.acquire(users, due).is_ok());
+        assert!(limits.acquire(users, due).is_err());
+        // Tokens don't pile up beyond one second of messages
+        let later = now + Duration::from_secs(60);
+        assert_eq!(
+            (0..20)
+                .filter(|_| limits.acquire(users, later).is_ok())
+                .count(),
+            10
+        );
+    }
+
+    #[test]
+    fn concurrency_is_capped_until_permits_are_dropped() {
+        let limits = limits(TopicLimits {
+            per_sec: None,
+            max_concurrency: Some(2),
+        });
+        let users = topic::id("users");
+        let now = Instant::now();
+        let first = limits.acquire(users, now).ok().unwrap();
+        let _second = limits.acquire(users, now).ok().unwrap();
+        assert_eq!(
+            limits.acquire(users, now).err(),
+            Some(now + CONCURRENCY_POLL)
+        );
+        drop(first);
+        assert!(limits.acquire(users, now).is_ok());
+    }
+
+    #[test]
+    fn a_rate_limited_message_frees_its_slot() {
+        let limits = limits(TopicLimits {
+            per_sec: Some(1),
+            max_concurrency: Some(1),
+        });
+        let users = topic::id("users");
+        let now = Instant::now();
+        drop(limits.acquire(users, now).ok().unwrap());
+        assert!(limits.acquire(users, now).is_err());
+        assert!(limits.acquire(users, now + Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn other_topics_are_not_limited() {
+        let limits = limits(TopicLimits {
+            per_sec: Some(1),
+            max_concurrency: Some(1),
+        });
+        let now = Instant::now();
+        let _permits = (0..10)
+            .map(|_| limits.acquire(topic::id("orders"), now).ok().unwrap())
+            .collect::<Vec<_>>();
+    }
+
+    #[test]
+    fn throttled_jobs_keep_their_order() {
+        let now = Instant::now();
+        let mut throttled = Throttled::new();
+        assert!(!throttled.is_waiting(1));
+        throttled.defer(1, "a", now);
+        assert!(throttled.is_waiting(1));
+        throttled.push(1, "b");
+        assert!(!throttled.is_waiting(2));
+        assert_eq!(throttled.due_topics(now), [1]);
+
+        // A job deferred again stays first in line
+        let first = throttled.pop(1).unwrap();
+        throttled.defer(1, first, now + Duration::from_secs(1));
+        assert_eq!(throttled.next_due(), Some(now + Duration::from_secs(1)));
+        assert!(throttled.due_topics(now).is_empty());
+        assert_eq!(throttled.pop(1), Some("a"));
+        assert_eq!(throttled.pop(1), Some("b"));
+        assert_eq!(throttled.pop(1), None);
+        assert_eq!(throttled.next_due(), None);
+
+        throttled.defer(1, "c", now);
+        throttled.push(1, "d");
+        assert_eq!(throttled.take_all(), ["c", "d"]);
+        assert!(!throttled.is_waiting(1));
+    }
+}
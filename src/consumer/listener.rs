@@ -0,0 +1,101 @@
+use std::{
+    io,
+    marker::PhantomData,
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+    thread,
+};
+
+use anyhow::{Result, bail};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+
+use super::{Consumer, ConsumerConfig, ConsumerHandle, DrainSummary, Shutdown, SocketOptions};
+use crate::topic::TopicEnum;
+
+// A consumer whose listener is already bound, so the actual address can be read before running
+pub struct BoundConsumer<C, T> {
+    consumer: C,
+    listener: TcpListener,
+    config: ConsumerConfig,
+    _phantom: PhantomData<T>,
+}
+
+impl<C: Consumer<T>, T: TopicEnum> BoundConsumer<C, T> {
+    pub fn new(consumer: C, config: ConsumerConfig) -> Result<Self> {
+        if config.workers == 0 {
+            bail!("ConsumerConfig::workers must be at least 1");
+        }
+
+        Ok(Self {
+            consumer,
+            listener: bind(&config)?,
+            config,
+            _phantom: PhantomData,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn run(self) -> Result<DrainSummary> {
+        self.consumer
+            .serve(self.listener, self.config, Arc::default())
+    }
+
+    pub fn spawn(self) -> Result<ConsumerHandle> {
+        let local_addr = self.local_addr()?;
+        let shutdown = Arc::new(Shutdown::default());
+        let serve_shutdown = shutdown.clone();
+
+        let thread = thread::spawn(move || {
+            self.consumer
+                .serve(self.listener, self.config, serve_shutdown)
+        });
+
+        Ok(ConsumerHandle::new(local_addr, shutdown, thread))
+    }
+}
+
+pub fn bind(config: &ConsumerConfig) -> Result<TcpListener> {
+    let options = &config.socket;
+    let socket = Socket::new(
+        Domain::for_address(config.addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+
+    socket.set_reuse_address(options.reuse_address)?;
+    if let Some(only_v6) = options.only_v6
+        && config.addr.is_ipv6()
+    {
+        socket.set_only_v6(only_v6)?;
+    }
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+
+    socket.bind(&config.addr.into())?;
+    socket.listen(options.backlog)?;
+
+    Ok(socket.into())
+}
+
+// Works on any TCP stream socket2 can borrow, mio's for the reactor and tokio's for the async runtime
+pub fn configure_stream<S>(stream: &S, options: &SocketOptions) -> Result<()>
+where
+    for<'s> SockRef<'s>: From<&'s S>,
+{
+    let socket = SockRef::from(stream);
+    socket.set_tcp_nodelay(options.nodelay)?;
+    if let Some(time) = options.keepalive {
+        socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+    }
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    Ok(())
+}
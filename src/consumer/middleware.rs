@@ -0,0 +1,82 @@
+use std::{sync::Arc, time::Duration};
+
+use super::{DispatchError, MessageContext};
+use crate::topic::TopicEnum;
+
+// Hooks run around every dispatch by the #[middleware] fields of a consumer, in declaration order.
+// An error from before_decode or after_decode stops the message before its handler runs. Each hook
+// is given the context of the message, like the handlers taking &MessageContext.
+pub trait Middleware<T: TopicEnum>: Send + Sync {
+    fn before_decode(
+        &self,
+        _topic: T,
+        _payload: &[u8],
+        _context: &MessageContext,
+    ) -> Result<(), DispatchError> {
+        Ok(())
+    }
+
+    fn after_decode(&self, _topic: T, _context: &MessageContext) -> Result<(), DispatchError> {
+        Ok(())
+    }
+
+    // Called with the outcome of every dispatch, including the ones stopped by a middleware
+    fn after_handler(
+        &self,
+        _topic: T,
+        _result: &Result<(), DispatchError>,
+        _elapsed: Duration,
+        _context: &MessageContext,
+    ) {
+    }
+}
+
+impl<T: TopicEnum, M: Middleware<T> + ?Sized> Middleware<T> for Box<M> {
+    fn before_decode(
+        &self,
+        topic: T,
+        payload: &[u8],
+        context: &MessageContext,
+    ) -> Result<(), DispatchError> {
+        (**self).before_decode(topic, payload, context)
+    }
+
+    fn after_decode(&self, topic: T, context: &MessageContext) -> Result<(), DispatchError> {
+        (**self).after_decode(topic, context)
+    }
+
+    fn after_handler(
+        &self,
+        topic: T,
+        result: &Result<(), DispatchError>,
+        elapsed: Duration,
+        context: &MessageContext,
+    ) {
+        (**self).after_handler(topic, result, elapsed, context)
+    }
+}
+
+impl<T: TopicEnum, M: Middleware<T> + ?Sized> Middleware<T> for Arc<M> {
+    fn before_decode(
+        &self,
+        topic: T,
+        payload: &[u8],
+        context: &MessageContext,
+    ) -> Result<(), DispatchError> {
+        (**self).before_decode(topic, payload, context)
+    }
+
+    fn after_decode(&self, topic: T, context: &MessageContext) -> Result<(), DispatchError> {
+        (**self).after_decode(topic, context)
+    }
+
+    fn after_handler(
+        &self,
+        topic: T,
+        result: &Result<(), DispatchError>,
+        elapsed: Duration,
+        context: &MessageContext,
+    ) {
+        (**self).after_handler(topic, result, elapsed, context)
+    }
+}
@@ -1,161 +1,224 @@
-use std::{
-    io::Read,
-    net::{TcpListener, TcpStream},
-    str::FromStr,
-    sync::{
-        Arc,
-        atomic::{AtomicBool, AtomicUsize, Ordering},
-        mpsc::{Sender, channel},
-    },
-    thread::{self, JoinHandle},
-};
-
-use anyhow::{Result, anyhow};
+#[cfg(feature = "tokio")]
+mod async_consumer;
+mod batch;
+mod builder;
+mod config;
+mod connection;
+mod context;
+mod credits;
+mod dedup;
+mod drain;
+mod handle;
+mod handler;
+mod handshake;
+mod limit;
+mod listener;
+mod middleware;
+mod pull;
+mod queue;
+mod reactor;
+mod retry;
+mod routing;
+mod worker;
+
+use std::{net::TcpListener, sync::Arc, thread};
+
+use anyhow::Result;
 pub use pusu_consumer_macro::consumer;
-use signal_hook::{consts::SIGINT, iterator::Signals};
-
-pub trait Consumer<T: FromStr>: Sync + Send + Sized + 'static {
-    fn run(self, port: u16) -> Result<()> {
-        let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
-        listener.set_nonblocking(true)?;
-        let nb_workers = 4;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
 
-        let mut senders = Vec::with_capacity(nb_workers);
-        let mut handles = Vec::with_capacity(nb_workers);
+pub use crate::frame::Headers;
+pub use crate::topic::DynamicTopic;
+use crate::topic::TopicEnum;
+
+#[cfg(feature = "tokio")]
+pub use async_consumer::AsyncConsumer;
+pub use batch::BatchPolicy;
+pub use builder::{ConsumerBuilder, DynamicConsumer};
+pub use config::{ConsumerConfig, Scheduling, SocketOptions};
+pub use context::MessageContext;
+pub use dedup::{DedupKey, DedupStore, FileDedup, MemoryDedup};
+pub use drain::DrainSummary;
+pub use handle::{ConsumerHandle, Shutdown};
+pub use handler::{
+    AsyncHandler, DispatchError, Handler, HandlerResult, WithContext, WithoutContext, join_results,
+};
+pub use limit::TopicLimits;
+pub use listener::BoundConsumer;
+pub use middleware::Middleware;
+pub use pull::PullConfig;
+use reactor::Reactor;
+pub use retry::{Backoff, RetryPolicy};
+pub use routing::key_hash;
+
+pub trait Consumer<T: TopicEnum>: Sync + Send + Sized + 'static {
+    fn run(self, port: u16) -> Result<DrainSummary> {
+        self.run_with_config(ConsumerConfig::with_port(port))
+    }
 
-        let load_counters: Vec<Arc<AtomicUsize>> = (0..nb_workers)
-            .map(|_| Arc::new(AtomicUsize::new(0)))
-            .collect();
+    fn run_with_config(self, config: ConsumerConfig) -> Result<DrainSummary> {
+        self.bind(config)?.run()
+    }
 
-        let running = Arc::new(AtomicBool::new(true));
+    fn bind(self, config: ConsumerConfig) -> Result<BoundConsumer<Self, T>> {
+        BoundConsumer::new(self, config)
+    }
 
-        let self_arc = Arc::new(self);
+    fn spawn(self, config: ConsumerConfig) -> Result<ConsumerHandle> {
+        self.bind(config)?.spawn()
+    }
 
-        for (worker_id, load_counter) in load_counters.iter().enumerate().take(nb_workers) {
-            let consumer_clone = self_arc.clone();
-            let (tx, handle) = consumer_clone.worker(worker_id, load_counter.clone());
-            senders.push(tx);
-            handles.push(handle);
+    // Serves connections until a shutdown is requested, by a ConsumerHandle or a signal, then
+    // drains the messages left within config.drain_timeout
+    fn serve(
+        self,
+        listener: TcpListener,
+        config: ConsumerConfig,
+        shutdown: Arc<Shutdown>,
+    ) -> Result<DrainSummary> {
+        let signals_handle = if config.handle_signals {
+            Some(watch_signals(&shutdown)?)
+        } else {
+            None
+        };
+
+        let reactor = Reactor::new(self, listener, config, shutdown)?;
+        println!("Listening on {}", reactor.local_addr()?);
+        let result = reactor.run();
+
+        if let Some(handle) = signals_handle {
+            handle.close();
         }
+        result
+    }
 
-        println!("Listening on 127.0.0.1:{}", port);
-
-        let running_clone = running.clone();
-
-        let join_handle = thread::spawn(move || {
-            loop {
-                if !running_clone.load(Ordering::Relaxed) {
-                    break;
-                }
-                match listener.accept() {
-                    Ok((stream, _)) => {
-                        let worker_idx = load_counters
-                            .iter()
-                            .enumerate()
-                            .min_by_key(|(_, counter)| counter.load(Ordering::Relaxed))
-                            .map(|(idx, _)| idx)
-                            .unwrap_or(0);
-
-                        if senders[worker_idx].send(stream).is_err() {
-                            eprintln!("Failed to send to worker {}", worker_idx);
-                            break;
-                        }
-                    }
-                    Err(e) => match e.kind() {
-                        std::io::ErrorKind::WouldBlock => {}
-                        _ => eprintln!("Error accepting connection: {}", e),
-                    },
-                }
-            }
-        });
-
-        let mut signals = Signals::new([SIGINT])?;
+    // Connects to a broker and processes the topics' messages from the group's committed offset,
+    // instead of listening for producers. Runs until SIGINT or SIGTERM if config.handle_signals.
+    fn pull(self, config: PullConfig) -> Result<()> {
+        self.pull_until(config, Arc::default())
+    }
 
-        if signals.forever().next().is_some() {
-            running.swap(false, Ordering::Relaxed);
-        }
+    fn pull_until(self, config: PullConfig, shutdown: Arc<Shutdown>) -> Result<()> {
+        let signals_handle = if config.handle_signals {
+            Some(watch_signals(&shutdown)?)
+        } else {
+            None
+        };
 
-        signals.handle().close();
-        let _ = join_handle.join();
-        for handle in handles {
-            let _ = handle.join();
-        }
+        let result = pull::run(&self, &config, &shutdown);
 
-        Ok(())
+        if let Some(handle) = signals_handle {
+            handle.close();
+        }
+        result
     }
 
-    fn worker(
-        self: Arc<Self>,
-        id: usize,
-        load_counter: Arc<AtomicUsize>,
-    ) -> (Sender<TcpStream>, JoinHandle<()>) {
-        let (tx, rx) = channel::<TcpStream>();
-
-        let handle = thread::spawn(move || {
-            while let Ok(stream) = rx.recv() {
-                load_counter.fetch_add(1, Ordering::Relaxed);
+    // Topics of the consumer, those of the generated enum unless they are only known at runtime
+    fn topics(&self) -> Vec<T> {
+        T::TOPICS
+            .iter()
+            .filter_map(|(_, id)| T::from_id(*id))
+            .collect()
+    }
 
-                if let Err(err) = self.accept(stream) {
-                    eprintln!("Error on worker {}: {}", id, err);
-                }
+    fn topic(&self, id: u16) -> Option<T> {
+        T::from_id(id)
+    }
 
-                load_counter.fetch_sub(1, Ordering::Relaxed);
-            }
-        });
-        (tx, handle)
+    fn topic_id(&self, name: &str) -> Option<u16> {
+        T::id_of(name)
     }
 
-    fn accept(&self, stream: TcpStream) -> Result<()> {
-        let mut buf_reader = std::io::BufReader::new(stream);
-        let mut buf = Vec::new();
-        buf_reader.read_to_end(&mut buf)?;
+    // Policy for messages nacked by the handler, set per topic with #[retry(...)]
+    fn retry_policy(&self, _topic: T) -> Option<RetryPolicy> {
+        None
+    }
 
-        if buf.len() < 2 {
-            anyhow::bail!("Buffer too small: expected at least 2 bytes for topic length");
-        }
+    // Topics routed with #[key(...)], whose messages with the same key go to the same worker
+    fn routes_by_key(&self, _topic: T) -> bool {
+        false
+    }
 
-        let topic_len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+    // Hash of the key of a message of a topic routed by key, taken from its payload or a header.
+    // Messages without one stay on the worker of their connection.
+    fn route_key(&self, _topic: T, _payload: &[u8], _context: &MessageContext) -> Option<u64> {
+        None
+    }
 
-        if buf.len() < 2 + topic_len {
-            anyhow::bail!(
-                "Buffer too small: expected {} bytes for topic, got {}",
-                2 + topic_len,
-                buf.len()
-            );
-        }
+    // Topics declared with batch(...) have their messages gathered by the worker and handed to
+    // dispatch_batch
+    fn batch_policy(&self, _topic: T) -> Option<BatchPolicy> {
+        None
+    }
 
-        let topic = std::str::from_utf8(&buf[2..2 + topic_len])?;
+    // Limits set per topic with #[rate_limit(...)] and #[max_concurrency(...)], enforced by the
+    // workers
+    fn topic_limits(&self, _topic: T) -> Option<TopicLimits> {
+        None
+    }
 
-        let payload_start = 2 + topic_len;
+    // Decodes the payloads and runs the topic's batch handler once, with one result per message
+    fn dispatch_batch(
+        &self,
+        topic: T,
+        payloads: &[&[u8]],
+        contexts: &[MessageContext],
+    ) -> Vec<Result<(), DispatchError>> {
+        payloads
+            .iter()
+            .zip(contexts)
+            .map(|(payload, context)| self.dispatch(topic, payload, context))
+            .collect()
+    }
 
-        if buf.len() < payload_start + 4 {
-            anyhow::bail!(
-                "Buffer too small: expected {} bytes for payload length",
-                payload_start + 4
-            );
-        }
+    // Set with #[fallback("handler")]: topics the consumer doesn't know are then accepted, their
+    // messages and the payloads that don't decode are given to `fallback` instead of being dropped
+    fn has_fallback(&self) -> bool {
+        false
+    }
 
-        let payload_len = u32::from_be_bytes([
-            buf[payload_start],
-            buf[payload_start + 1],
-            buf[payload_start + 2],
-            buf[payload_start + 3],
-        ]) as usize;
-
-        if buf.len() < payload_start + 4 + payload_len {
-            anyhow::bail!(
-                "Buffer too small: expected {} bytes for payload, got {}",
-                payload_start + 4 + payload_len,
-                buf.len()
-            );
-        }
+    fn fallback(&self, _topic: &str, _payload: &[u8]) -> Result<()> {
+        Ok(())
+    }
 
-        let topic_variant =
-            T::from_str(topic).map_err(|_| anyhow!("Error parsing str to topic enum variant"))?;
+    // Set with #[dead_letter("handler")]: messages nacked after their last attempt, or which don't
+    // decode, are given to `dead_letter` with the error they were given up with
+    fn dead_letter(
+        &self,
+        _topic: T,
+        _payload: &[u8],
+        _context: &MessageContext,
+        _err: &anyhow::Error,
+    ) -> Result<()> {
+        Ok(())
+    }
 
-        let payload_bytes = &buf[payload_start + 4..payload_start + 4 + payload_len];
-        self.dispatch(topic_variant, payload_bytes)
+    // Store of acked messages set with #[dedup], whose duplicates are acked without running
+    fn dedup_store(&self) -> Option<&dyn DedupStore> {
+        None
     }
 
-    fn dispatch(&self, topic: T, payload: &[u8]) -> Result<()>;
+    // Decodes the payload and runs the topic's handler, Ok acks the message
+    fn dispatch(
+        &self,
+        topic: T,
+        payload: &[u8],
+        context: &MessageContext,
+    ) -> Result<(), DispatchError>;
+}
+
+// Requests a shutdown on SIGINT or SIGTERM, until the returned handle is closed
+fn watch_signals(shutdown: &Arc<Shutdown>) -> Result<signal_hook::iterator::Handle> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    let handle = signals.handle();
+    let shutdown = shutdown.clone();
+    thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            shutdown.request();
+        }
+    });
+    Ok(handle)
 }
@@ -0,0 +1,305 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::{BufReader, BufWriter, Write},
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Result, anyhow, bail};
+
+use super::{
+    Consumer, DedupKey, DispatchError, MessageContext, Shutdown, dedup, handler,
+    limit::{Limits, Permit},
+    retry,
+};
+use crate::{
+    frame::{self, Batch, Fetch, FrameKind},
+    topic::TopicEnum,
+};
+
+#[derive(Clone, Debug)]
+pub struct PullConfig {
+    pub broker: String,
+    // Consumers of a group share their committed offsets
+    pub group: String,
+    pub batch_size: u32,
+    // Wait before fetching again once every topic is drained, or before reconnecting
+    pub poll_interval: Duration,
+    pub request_timeout: Duration,
+    pub max_frame_size: usize,
+    // Stop on SIGINT and SIGTERM, disable to only stop through the Shutdown given to pull_until
+    pub handle_signals: bool,
+}
+
+impl PullConfig {
+    pub fn new(broker: &str, group: &str) -> Self {
+        Self {
+            broker: broker.to_string(),
+            group: group.to_string(),
+            batch_size: 64,
+            poll_interval: Duration::from_millis(500),
+            request_timeout: Duration::from_secs(5),
+            max_frame_size: 4 * 1024 * 1024,
+            handle_signals: true,
+        }
+    }
+}
+
+struct BrokerConnection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    peer: SocketAddr,
+}
+
+impl BrokerConnection {
+    fn connect(config: &PullConfig) -> Result<Self> {
+        let stream = TcpStream::connect(&config.broker)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(config.request_timeout))?;
+
+        Ok(Self {
+            peer: stream.peer_addr()?,
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    fn fetch(&mut self, topic: u16, fetch: &Fetch, config: &PullConfig) -> Result<Batch> {
+        frame::write(
+            &mut self.writer,
+            FrameKind::Fetch,
+            topic,
+            &postcard::to_stdvec(fetch)?,
+        )?;
+        self.writer.flush()?;
+
+        let Some(buf) = frame::read(&mut self.reader, config.max_frame_size)? else {
+            bail!("Connection closed by broker");
+        };
+        let frame = frame::decode(&buf)?;
+        match frame.kind {
+            FrameKind::Batch => Ok(postcard::from_bytes(frame.payload)?),
+            FrameKind::Reject => {
+                bail!("Fetch rejected: {}", String::from_utf8_lossy(frame.payload))
+            }
+            kind => bail!("Unexpected {:?} frame from broker", kind),
+        }
+    }
+}
+
+// Fetches every topic in turn and processes each batch before fetching the next one, whose
+// request commits it. Offsets are committed after processing, so messages are delivered at least
+// once and a failed connection only delays them.
+pub fn run<C: Consumer<T>, T: TopicEnum>(
+    consumer: &C,
+    config: &PullConfig,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    let mut connection = None;
+    let mut commits = HashMap::new();
+    let limits = Limits::new(consumer);
+
+    while !shutdown.is_requested() {
+        if connection.is_none() {
+            match BrokerConnection::connect(config) {
+                Ok(connected) => {
+                    println!("Pulling from {}", connected.peer);
+                    connection = Some(connected);
+                }
+                Err(err) => {
+                    eprintln!("Error connecting to broker {}: {}", config.broker, err);
+                    thread::sleep(config.poll_interval);
+                    continue;
+                }
+            }
+        }
+        let Some(broker) = &mut connection else {
+            continue;
+        };
+
+        match pull_topics(consumer, &limits, broker, &mut commits, config, shutdown) {
+            Ok(true) => {}
+            Ok(false) => thread::sleep(config.poll_interval),
+            Err(err) => {
+                eprintln!("Broker {} failed: {}", config.broker, err);
+                connection = None;
+                thread::sleep(config.poll_interval);
+            }
+        }
+    }
+
+    // Commits the last processed batches
+    if let Some(connection) = &mut connection {
+        for (topic, offset) in commits {
+            let fetch = Fetch {
+                group: config.group.clone(),
+                commit: Some(offset),
+                max_messages: 0,
+                max_frame_size: max_frame_size(config),
+            };
+            if let Err(err) = connection.fetch(topic, &fetch, config) {
+                eprintln!(
+                    "Error committing offset {} of topic {}: {}",
+                    offset, topic, err
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+// Returns true if any message was processed
+fn pull_topics<C: Consumer<T>, T: TopicEnum>(
+    consumer: &C,
+    limits: &Limits,
+    connection: &mut BrokerConnection,
+    commits: &mut HashMap<u16, u64>,
+    config: &PullConfig,
+    shutdown: &Shutdown,
+) -> Result<bool> {
+    let mut processed = false;
+
+    for topic in consumer.topics() {
+        if shutdown.is_requested() {
+            break;
+        }
+        let id = topic.id();
+
+        let fetch = Fetch {
+            group: config.group.clone(),
+            commit: commits.get(&id).copied(),
+            max_messages: config.batch_size,
+            max_frame_size: max_frame_size(config),
+        };
+        let batch = connection.fetch(id, &fetch, config)?;
+        commits.remove(&id);
+
+        for (offset, message) in (batch.first..).zip(&batch.messages) {
+            deliver(consumer, limits, topic, offset, message, connection.peer);
+            commits.insert(id, offset + 1);
+            processed = true;
+        }
+    }
+    Ok(processed)
+}
+
+fn max_frame_size(config: &PullConfig) -> u32 {
+    u32::try_from(config.max_frame_size).unwrap_or(u32::MAX)
+}
+
+// Runs the handler with the topic's retry policy. A message that can't be read is committed
+// anyway, fetching it again would only fail the same way.
+fn deliver<C: Consumer<T>, T: TopicEnum>(
+    consumer: &C,
+    limits: &Limits,
+    topic: T,
+    offset: u64,
+    message: &[u8],
+    peer: SocketAddr,
+) {
+    let place = format_args!("broker {}", peer);
+    if message.is_empty() {
+        let err = anyhow!("Sent empty by the broker, it exceeds the max frame size");
+        return skip(consumer, topic, offset, message, err, &place);
+    }
+    let frame = match frame::decode(message) {
+        Ok(frame) => frame,
+        Err(err) => return skip(consumer, topic, offset, message, err, &place),
+    };
+    let received_at = SystemTime::now();
+
+    let mut attempt = 1;
+    while let Err(err) = dispatch(
+        consumer,
+        limits,
+        topic,
+        frame.payload,
+        peer,
+        received_at,
+        attempt,
+    ) {
+        let policy = consumer.retry_policy(topic);
+        match retry::next_attempt(topic, attempt, err, policy, &place) {
+            Ok(delay) => {
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(err) => {
+                let (context, body) =
+                    MessageContext::open_or_raw(topic, frame.payload, peer, received_at, attempt);
+                retry::dead_letter(consumer, topic, body, &context, &err, &place);
+                break;
+            }
+        }
+    }
+}
+
+// Hands a corrupt or oversized message to the fallback handler if there is one
+fn skip<C: Consumer<T>, T: TopicEnum>(
+    consumer: &C,
+    topic: T,
+    offset: u64,
+    message: &[u8],
+    err: anyhow::Error,
+    place: &dyn Display,
+) {
+    eprintln!(
+        "Skipping unreadable {} message {} on {}: {}",
+        topic.name(),
+        offset,
+        place,
+        err
+    );
+    if !consumer.has_fallback() {
+        return;
+    }
+    let result = handler::catch_panic(|| {
+        consumer
+            .fallback(topic.name(), message)
+            .map_err(DispatchError::Handler)
+    });
+    if let Err(err) = result {
+        eprintln!(
+            "Fallback for {} message {} failed on {}: {}",
+            topic.name(),
+            offset,
+            place,
+            err
+        );
+    }
+}
+
+fn dispatch<C: Consumer<T>, T: TopicEnum>(
+    consumer: &C,
+    limits: &Limits,
+    topic: T,
+    payload: &[u8],
+    peer: SocketAddr,
+    received_at: SystemTime,
+    attempt: u32,
+) -> Result<(), DispatchError> {
+    let (context, body) = MessageContext::open(topic, payload, peer, received_at, attempt)?;
+    let _permit = acquire(limits, topic.id());
+    let key = DedupKey::new(topic, &context);
+    let store = consumer.dedup_store();
+    if dedup::seen(store, &key) {
+        return Ok(());
+    }
+    let result = handler::catch_panic(|| consumer.dispatch(topic, body, &context));
+    if result.is_ok() {
+        dedup::record(store, key, context.topic);
+    }
+    result
+}
+
+// Pulled messages run one at a time, so only the rate limit of their topic can hold them back
+fn acquire(limits: &Limits, topic: u16) -> Permit<'_> {
+    loop {
+        match limits.acquire(topic, Instant::now()) {
+            Ok(permit) => return permit,
+            Err(due) => thread::sleep(due.saturating_duration_since(Instant::now())),
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread::{self, Thread},
+    time::Duration,
+};
+
+use crossbeam_deque::{Injector, Steal};
+
+use super::{Scheduling, worker::Task};
+
+// Tasks queued for each worker, shared by the reactor which pushes them and the workers. With work
+// stealing, a worker out of tasks takes the oldest one of the busiest worker, except for the tasks
+// pinned to their worker by a routing key.
+pub struct Queues<T> {
+    queues: Vec<Queue<T>>,
+    stealing: bool,
+    // Tasks of topics routed by key not routed yet, which can still be forwarded to any worker
+    routing: AtomicUsize,
+}
+
+struct Queue<T> {
+    tasks: Injector<Task<T>>,
+    pinned: Injector<Task<T>>,
+    // Set while the worker waits for a task, so the reactor can wake it up to steal one
+    idle: AtomicBool,
+    closed: AtomicBool,
+    // Set by the worker thread, so that other workers can wake it up when forwarding it a task
+    thread: Mutex<Option<Thread>>,
+}
+
+impl<T> Queues<T> {
+    pub fn new(workers: usize, scheduling: Scheduling) -> Self {
+        let queues = (0..workers)
+            .map(|_| Queue {
+                tasks: Injector::new(),
+                pinned: Injector::new(),
+                idle: AtomicBool::new(false),
+                closed: AtomicBool::new(false),
+                thread: Mutex::new(None),
+            })
+            .collect();
+        Self {
+            queues,
+            stealing: scheduling == Scheduling::WorkStealing,
+            routing: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.queues.len()
+    }
+
+    // Called by each worker thread when it starts
+    pub fn register(&self, worker: usize) {
+        *self.queues[worker].thread.lock().unwrap() = Some(thread::current());
+    }
+
+    pub fn len(&self, worker: usize) -> usize {
+        let queue = &self.queues[worker];
+        queue.tasks.len() + queue.pinned.len()
+    }
+
+    // Queues a task for the worker, returns an idle worker to wake up as well if another one can
+    // take it
+    pub fn push(&self, worker: usize, task: Task<T>, pinned: bool) -> Option<usize> {
+        let queue = &self.queues[worker];
+        if matches!(task, Task::Route(_)) {
+            self.routing.fetch_add(1, Ordering::SeqCst);
+        }
+        if pinned {
+            queue.pinned.push(task);
+            return None;
+        }
+        queue.tasks.push(task);
+
+        if !self.stealing || queue.idle.load(Ordering::SeqCst) {
+            return None;
+        }
+        self.queues
+            .iter()
+            .position(|queue| queue.idle.load(Ordering::SeqCst))
+    }
+
+    pub fn pop(&self, worker: usize) -> Option<Task<T>> {
+        let queue = &self.queues[worker];
+        if let Some(task) = steal(&queue.pinned).or_else(|| steal(&queue.tasks)) {
+            return Some(task);
+        }
+        if !self.stealing {
+            return None;
+        }
+
+        let mut victims = (0..self.queues.len())
+            .filter(|victim| *victim != worker)
+            .collect::<Vec<_>>();
+        victims.sort_by_key(|victim| std::cmp::Reverse(self.queues[*victim].tasks.len()));
+        victims
+            .into_iter()
+            .find_map(|victim| steal(&self.queues[victim].tasks))
+    }
+
+    // Hands a routed task over to the worker of its key
+    pub fn forward(&self, worker: usize, task: Task<T>) {
+        self.queues[worker].pinned.push(task);
+        self.routed();
+        self.unpark(worker);
+    }
+
+    // A task was routed, once none is left the closed workers can stop as nothing can be forwarded
+    // to them anymore
+    pub fn routed(&self) {
+        if self.routing.fetch_sub(1, Ordering::SeqCst) == 1 {
+            for worker in 0..self.queues.len() {
+                if self.is_closed(worker) {
+                    self.unpark(worker);
+                }
+            }
+        }
+    }
+
+    pub fn is_routing(&self) -> bool {
+        self.routing.load(Ordering::SeqCst) > 0
+    }
+
+    fn unpark(&self, worker: usize) {
+        if let Some(thread) = &*self.queues[worker].thread.lock().unwrap() {
+            thread.unpark();
+        }
+    }
+
+    // Parks the worker until a task is pushed, it is closed or the timeout is over
+    pub fn wait(&self, worker: usize, timeout: Option<Duration>) {
+        let queue = &self.queues[worker];
+        queue.idle.store(true, Ordering::SeqCst);
+        // Checked once idle, so a task pushed in between either is seen here or wakes it up. A
+        // closed worker still waits for the tasks other workers may forward it.
+        let stopping = self.is_closed(worker) && !self.is_routing();
+        if !self.has_tasks(worker) && !stopping {
+            match timeout {
+                Some(timeout) => thread::park_timeout(timeout),
+                None => thread::park(),
+            }
+        }
+        queue.idle.store(false, Ordering::SeqCst);
+    }
+
+    fn has_tasks(&self, worker: usize) -> bool {
+        if self.stealing {
+            self.queues.iter().enumerate().any(|(i, queue)| {
+                !queue.tasks.is_empty() || (i == worker && !queue.pinned.is_empty())
+            })
+        } else {
+            self.len(worker) > 0
+        }
+    }
+
+    // No task is pushed to the worker anymore, it stops once there is nothing left for it
+    pub fn close(&self, worker: usize) {
+        self.queues[worker].closed.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_closed(&self, worker: usize) -> bool {
+        self.queues[worker].closed.load(Ordering::SeqCst)
+    }
+}
+
+fn steal<T>(injector: &Injector<T>) -> Option<T> {
+    loop {
+        match injector.steal() {
+            Steal::Success(task) => return Some(task),
+            Steal::Empty => return None,
+            Steal::Retry => {}
+        }
+    }
+}
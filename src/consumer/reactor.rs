@@ -0,0 +1,452 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::Receiver,
+    },
+    time::{Instant, SystemTime},
+};
+
+use anyhow::{Error, Result, anyhow, bail};
+use mio::{Events, Interest, Poll, Token, Waker, event::Event, net::TcpListener};
+
+use super::{
+    Consumer, ConsumerConfig, Shutdown,
+    connection::{Connection, ConnectionTracker},
+    drain::{Drain, DrainSummary},
+    handshake::{self, TopicLookup},
+    limit::Limits,
+    listener,
+    queue::Queues,
+    worker::{Completions, Credit, Job, Task, UnknownJob, Worker, WorkerContext},
+};
+use crate::{
+    frame::{self, FrameKind},
+    topic::TopicEnum,
+};
+
+const LISTENER: Token = Token(0);
+// Shared by worker completions and shutdown requests
+const WAKER: Token = Token(1);
+const FIRST_CONNECTION: usize = 2;
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+// Single thread multiplexing accepts, reads, writes and timers of every connection. Decoded
+// messages are handed to the worker assigned to their connection, which runs the handler or
+// forwards the messages of topics routed by key to the worker of their key.
+pub struct Reactor<C, T> {
+    consumer: Arc<C>,
+    // Queues, limits and drain shared with the workers, the drain is started once a shutdown is
+    // requested
+    context: WorkerContext<C, T>,
+    poll: Poll,
+    listener: TcpListener,
+    config: ConsumerConfig,
+    shutdown: Arc<Shutdown>,
+    workers: Vec<Worker>,
+    completions: Receiver<Token>,
+    completions_pending: Arc<AtomicBool>,
+    // Handed to each job, which reports its completion when it is dropped
+    completions_sender: Completions,
+    connections: HashMap<Token, Connection>,
+    tracker: ConnectionTracker,
+    // Connections which asked for credits and got none because their worker was full
+    starved: HashSet<Token>,
+    next_token: usize,
+    next_deadline: Option<Instant>,
+    scratch: Vec<u8>,
+}
+
+impl<T: TopicEnum, C: Consumer<T>> Reactor<C, T> {
+    pub fn new(
+        consumer: C,
+        listener: std::net::TcpListener,
+        config: ConsumerConfig,
+        shutdown: Arc<Shutdown>,
+    ) -> Result<Self> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        shutdown.set_waker(waker.clone());
+
+        listener.set_nonblocking(true)?;
+        let mut listener = TcpListener::from_std(listener);
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)?;
+
+        let drain = Arc::new(Drain::default());
+        let (completions, receiver, pending) = Completions::new(waker, drain.clone());
+        let consumer = Arc::new(consumer);
+        let context = WorkerContext {
+            consumer: consumer.clone(),
+            queues: Arc::new(Queues::new(config.workers, config.scheduling)),
+            limits: Arc::new(Limits::new(&*consumer)),
+            drain,
+        };
+        let workers = (0..config.workers)
+            .map(|id| Worker::spawn(id, context.clone()))
+            .collect();
+
+        Ok(Self {
+            consumer,
+            context,
+            poll,
+            listener,
+            config,
+            shutdown,
+            workers,
+            completions: receiver,
+            completions_pending: pending,
+            completions_sender: completions,
+            connections: HashMap::new(),
+            tracker: ConnectionTracker::default(),
+            starved: HashSet::new(),
+            next_token: FIRST_CONNECTION,
+            next_deadline: None,
+            scratch: vec![0u8; READ_CHUNK_SIZE],
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Runs until a shutdown is requested, then closes every connection and lets the workers
+    // finish the messages already queued until the drain deadline
+    pub fn run(mut self) -> Result<DrainSummary> {
+        let mut events = Events::with_capacity(1024);
+        let mut result = Ok(());
+
+        while !self.shutdown.is_requested() {
+            let timeout = self
+                .next_deadline
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+
+            if let Err(err) = self.poll.poll(&mut events, timeout) {
+                if err.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                result = Err(err.into());
+                break;
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    WAKER => self.complete(),
+                    token => self.ready(token, event),
+                }
+            }
+
+            if self
+                .next_deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                self.poll_timers();
+            }
+        }
+
+        let drain = self.context.drain;
+        let queues = self.context.queues;
+        drain.start(self.config.drain_timeout);
+        self.connections.clear();
+        let threads = self
+            .workers
+            .into_iter()
+            .map(|worker| worker.stop(&queues))
+            .collect();
+        let summary = drain.wait(threads);
+        result.map(|()| summary)
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let (mut stream, peer) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    eprintln!("Error accepting connection: {}", err);
+                    return;
+                }
+            };
+
+            if !self
+                .tracker
+                .track(peer.ip(), self.config.max_connections_per_ip)
+            {
+                eprintln!("Connection limit reached for {}", peer.ip());
+                continue;
+            }
+
+            let token = Token(self.next_token);
+            self.next_token += 1;
+
+            let registered =
+                listener::configure_stream(&stream, &self.config.socket).and_then(|_| {
+                    self.poll
+                        .registry()
+                        .register(&mut stream, token, Interest::READABLE | Interest::WRITABLE)
+                        .map_err(Error::from)
+                });
+            if let Err(err) = registered {
+                eprintln!("Error setting up connection from {}: {}", peer, err);
+                self.tracker.release(peer.ip());
+                continue;
+            }
+
+            let connection = Connection::new(stream, peer);
+            self.schedule(connection.deadline(&self.config));
+            self.connections.insert(token, connection);
+        }
+    }
+
+    fn ready(&mut self, token: Token, event: &Event) {
+        match self.handle(token, event) {
+            Ok(true) => {
+                if let Some(connection) = self.connections.get(&token) {
+                    self.schedule(connection.deadline(&self.config));
+                }
+            }
+            Ok(false) => self.close(token),
+            Err(err) => self.close_with_error(token, err),
+        }
+    }
+
+    // Returns false once the peer closed the connection
+    fn handle(&mut self, token: Token, event: &Event) -> Result<bool> {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return Ok(true);
+        };
+
+        if event.is_writable() {
+            connection.flush()?;
+        }
+        if !event.is_readable() && !event.is_read_closed() {
+            return Ok(true);
+        }
+
+        let mut frames = Vec::new();
+        let open = connection.read(&self.config, &mut self.scratch, &mut frames)?;
+        for buf in frames {
+            self.on_frame(token, buf)?;
+        }
+        self.grant(token)?;
+        Ok(open)
+    }
+
+    fn on_frame(&mut self, token: Token, buf: Vec<u8>) -> Result<()> {
+        let connection = self
+            .connections
+            .get_mut(&token)
+            .ok_or_else(|| anyhow!("Connection closed"))?;
+
+        let Some(worker_id) = connection.worker() else {
+            let lookup = TopicLookup {
+                topic_id: |name: &str| self.consumer.topic_id(name),
+                is_known: |id| self.consumer.topic(id).is_some(),
+                accept_unknown: self.consumer.has_fallback(),
+            };
+            let unknown_topics = match handshake::accept_hello(&buf, &self.config, lookup) {
+                Ok(unknown_topics) => unknown_topics,
+                Err(err) => {
+                    connection.send(FrameKind::Reject, 0, err.to_string().as_bytes())?;
+                    return Err(err);
+                }
+            };
+
+            // Least loaded worker, connections then stick to it so their messages stay ordered
+            let (worker_id, worker) = self
+                .workers
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, worker)| worker.load.load(Ordering::Relaxed))
+                .ok_or_else(|| anyhow!("No worker available"))?;
+            return connection.welcome(
+                worker_id,
+                worker.load.clone(),
+                worker.wanting.clone(),
+                unknown_topics,
+                &self.config,
+            );
+        };
+
+        let frame = frame::decode(&buf)?;
+        match frame.kind {
+            FrameKind::Message => {}
+            // Credits are granted once the frames read are handled
+            FrameKind::Demand => {
+                connection.demand_credits();
+                return Ok(());
+            }
+            FrameKind::Return => {
+                connection.return_credits(frame.payload)?;
+                // Connections waiting for credits can take those given back
+                self.grant_all(HashSet::new());
+                return Ok(());
+            }
+            kind => bail!("Unexpected {:?} frame after handshake", kind),
+        }
+        let topic = match self.consumer.topic(frame.topic) {
+            Some(topic) => Ok(topic),
+            None => Err(connection
+                .unknown_topic(frame.topic)
+                .ok_or_else(|| anyhow!("Unknown topic id {}", frame.topic))?),
+        };
+        connection.consume_credit()?;
+        let credit = Credit::new(
+            token,
+            self.workers[worker_id].load.clone(),
+            connection.parked(),
+            self.completions_sender.clone(),
+        );
+        let peer = connection.peer();
+
+        let topic = match topic {
+            Ok(topic) => topic,
+            // Messages of unknown topics go to the fallback handler on the connection's worker
+            Err(name) => {
+                let task = Task::Unknown(UnknownJob {
+                    topic: name,
+                    frame: buf,
+                    credit,
+                });
+                return self.send_task(worker_id, task, false);
+            }
+        };
+
+        let job = Job {
+            topic,
+            frame: buf,
+            peer,
+            received_at: SystemTime::now(),
+            attempt: 1,
+            credit,
+        };
+        // The key is taken by the connection's worker rather than here, it may need the payload
+        // decoded. Messages waiting for it are pinned so that their order is kept.
+        if self.consumer.routes_by_key(topic) {
+            return self.send_task(worker_id, Task::Route(job), true);
+        }
+        self.send_task(worker_id, Task::Job(job), false)
+    }
+
+    // Messages routed by key are pinned to their worker, the others can be stolen by idle workers
+    // when scheduling allows it
+    fn send_task(&mut self, target: usize, task: Task<T>, pinned: bool) -> Result<()> {
+        let queues = &self.context.queues;
+        if queues.len(target) >= queue_size(&self.config) {
+            bail!("Queue of worker {} is full", target);
+        }
+
+        if let Some(idle) = queues.push(target, task, pinned) {
+            self.workers[idle].wake();
+        }
+        self.workers[target].wake();
+        Ok(())
+    }
+
+    // Grants the credits of connections whose worker released some load
+    fn complete(&mut self) {
+        self.completions_pending.store(false, Ordering::SeqCst);
+
+        let tokens = self.completions.try_iter().collect::<HashSet<_>>();
+        self.grant_all(tokens);
+    }
+
+    // Starved connections are tried again along with `tokens`
+    fn grant_all(&mut self, mut tokens: HashSet<Token>) {
+        tokens.extend(self.starved.drain());
+        for token in tokens {
+            if let Err(err) = self.grant(token) {
+                self.close_with_error(token, err);
+            }
+        }
+    }
+
+    fn grant(&mut self, token: Token) -> Result<()> {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return Ok(());
+        };
+
+        connection.grant_credits()?;
+        if !connection.is_starved() {
+            self.starved.remove(&token);
+        } else if self.starved.insert(token)
+            && let Some(worker) = connection.worker()
+        {
+            self.rebalance(worker);
+        }
+        Ok(())
+    }
+
+    // Revokes the credits of the connections of the worker holding more than their share, once
+    // returned they go to the starved ones
+    fn rebalance(&mut self, worker: usize) {
+        let mut failed = Vec::new();
+        for (token, connection) in &mut self.connections {
+            if connection.worker() == Some(worker)
+                && let Err(err) = connection.revoke_excess_credits()
+            {
+                failed.push((*token, err));
+            }
+        }
+        for (token, err) in failed {
+            self.close_with_error(token, err);
+        }
+    }
+
+    fn poll_timers(&mut self) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.next_deadline = None;
+
+        for (token, connection) in &mut self.connections {
+            match connection.poll_timers(now, &self.config) {
+                Ok(deadline) => {
+                    self.next_deadline = earliest(self.next_deadline, deadline);
+                }
+                Err(err) => expired.push((*token, err)),
+            }
+        }
+
+        for (token, err) in expired {
+            self.close_with_error(token, err);
+        }
+    }
+
+    fn schedule(&mut self, deadline: Option<Instant>) {
+        self.next_deadline = earliest(self.next_deadline, deadline);
+    }
+
+    fn close_with_error(&mut self, token: Token, err: Error) {
+        if let Some(connection) = self.connections.get(&token) {
+            eprintln!("Closing connection from {}: {}", connection.peer(), err);
+        }
+        self.close(token);
+    }
+
+    // Credits the connection still holds go back to its worker when it is dropped
+    fn close(&mut self, token: Token) {
+        if let Some(mut connection) = self.connections.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut connection.stream);
+            self.tracker.release(connection.peer().ip());
+        }
+        self.starved.remove(&token);
+    }
+}
+
+// Messages routed by key use the credits of their connection's worker, so any worker can be sent
+// the messages of every other one
+fn queue_size(config: &ConsumerConfig) -> usize {
+    config.worker_queue_size * config.workers
+}
+
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
@@ -0,0 +1,269 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    fmt::Display,
+    mem,
+    time::{Duration, Instant},
+};
+
+use super::{Consumer, DispatchError, MessageContext, handler};
+use crate::topic::TopicEnum;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backoff {
+    Fixed,
+    Linear,
+    Exponential,
+}
+
+// Set per topic with #[retry(max = 5, backoff = "exp", base_ms = 100)]
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    // Deliveries after the first one before the message is nacked for good
+    pub max_retries: u32,
+    pub backoff: Backoff,
+    pub base: Duration,
+}
+
+impl RetryPolicy {
+    // Delay before the given retry, starting at 1
+    pub fn delay(&self, retry: u32) -> Duration {
+        match self.backoff {
+            Backoff::Fixed => self.base,
+            Backoff::Linear => self.base.saturating_mul(retry),
+            Backoff::Exponential => self
+                .base
+                .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1))),
+        }
+    }
+}
+
+// Logs a delivery that was not acked, returns the delay before the next attempt if the policy
+// allows one, or the error the message is given up with. `place` says where the message was
+// processed, for the logs.
+pub fn next_attempt<T: TopicEnum>(
+    topic: T,
+    attempt: u32,
+    err: DispatchError,
+    policy: Option<RetryPolicy>,
+    place: &dyn Display,
+) -> Result<Duration, anyhow::Error> {
+    match err {
+        DispatchError::Decode(err) => {
+            eprintln!(
+                "Dropping undecodable {} message on {}: {}",
+                topic.name(),
+                place,
+                err
+            );
+            Err(err)
+        }
+        DispatchError::Handler(err) => {
+            if let Some(policy) = policy
+                && attempt <= policy.max_retries
+            {
+                let delay = policy.delay(attempt);
+                eprintln!(
+                    "Retrying {} message in {:?} after attempt {} failed on {}: {}",
+                    topic.name(),
+                    delay,
+                    attempt,
+                    place,
+                    err
+                );
+                return Ok(delay);
+            }
+
+            eprintln!(
+                "Nacked {} message on {} after {} attempts: {}",
+                topic.name(),
+                place,
+                attempt,
+                err
+            );
+            Err(err)
+        }
+    }
+}
+
+// Hands a message given up to the consumer's dead letter handler, which failing is only logged
+pub fn dead_letter<C: Consumer<T>, T: TopicEnum>(
+    consumer: &C,
+    topic: T,
+    body: &[u8],
+    context: &MessageContext,
+    err: &anyhow::Error,
+    place: &dyn Display,
+) {
+    let result = handler::catch_panic(|| {
+        consumer
+            .dead_letter(topic, body, context, err)
+            .map_err(DispatchError::Handler)
+    });
+    if let Err(err) = result {
+        eprintln!(
+            "Dead letter handler for {} message failed on {}: {}",
+            topic.name(),
+            place,
+            err
+        );
+    }
+}
+
+// Failed messages of a worker waiting for their next delivery, earliest first
+pub struct RetryQueue<J> {
+    heap: BinaryHeap<Delayed<J>>,
+    next_seq: u64,
+}
+
+impl<J> RetryQueue<J> {
+    pub fn new() -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+            next_seq: 0,
+        }
+    }
+
+    pub fn push(&mut self, due: Instant, job: J) {
+        self.heap.push(Delayed {
+            due,
+            seq: self.next_seq,
+            job,
+        });
+        self.next_seq += 1;
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.heap.peek().map(|delayed| delayed.due)
+    }
+
+    pub fn pop_due(&mut self, now: Instant) -> Option<J> {
+        if self.next_due()? > now {
+            return None;
+        }
+        self.heap.pop().map(|delayed| delayed.job)
+    }
+
+    // Every pending job, earliest first
+    pub fn take_all(&mut self) -> Vec<J> {
+        let mut delayed = mem::take(&mut self.heap).into_sorted_vec();
+        delayed.reverse();
+        delayed.into_iter().map(|delayed| delayed.job).collect()
+    }
+}
+
+struct Delayed<J> {
+    due: Instant,
+    // Keeps jobs due at the same instant in insertion order
+    seq: u64,
+    job: J,
+}
+
+impl<J> Delayed<J> {
+    fn key(&self) -> Reverse<(Instant, u64)> {
+        Reverse((self.due, self.seq))
+    }
+}
+
+impl<J> PartialEq for Delayed<J> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<J> Eq for Delayed<J> {}
+
+impl<J> PartialOrd for Delayed<J> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<J> Ord for Delayed<J> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::topic::DynamicTopic;
+
+    fn policy(backoff: Backoff) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            backoff,
+            base: Duration::from_millis(100),
+        }
+    }
+
+    #[test]
+    fn delays_follow_the_backoff() {
+        let delays = |backoff| {
+            (1..=4)
+                .map(|retry| policy(backoff).delay(retry).as_millis())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(delays(Backoff::Fixed), [100, 100, 100, 100]);
+        assert_eq!(delays(Backoff::Linear), [100, 200, 300, 400]);
+        assert_eq!(delays(Backoff::Exponential), [100, 200, 400, 800]);
+    }
+
+    #[test]
+    fn delays_saturate() {
+        let policy = policy(Backoff::Exponential);
+        assert_eq!(policy.delay(u32::MAX), policy.base * u32::MAX);
+        assert_eq!(policy.delay(0), policy.base);
+    }
+
+    #[test]
+    fn messages_are_retried_until_the_policy_gives_up() {
+        let topic = DynamicTopic::new("users");
+        let failed = || DispatchError::Handler(anyhow!("failed"));
+        let policy = Some(policy(Backoff::Linear));
+
+        for attempt in 1..=3 {
+            let delay = next_attempt(topic, attempt, failed(), policy, &"test").unwrap();
+            assert_eq!(delay, Duration::from_millis(100 * attempt as u64));
+        }
+        let err = next_attempt(topic, 4, failed(), policy, &"test").unwrap_err();
+        assert_eq!(err.to_string(), "failed");
+
+        assert!(next_attempt(topic, 1, failed(), None, &"test").is_err());
+        // Decoding again would fail the same way
+        let undecodable = DispatchError::decode(anyhow!("bad payload"));
+        assert!(next_attempt(topic, 1, undecodable, policy, &"test").is_err());
+    }
+
+    #[test]
+    fn retries_come_out_due_first_then_in_order() {
+        let now = Instant::now();
+        let mut queue = RetryQueue::new();
+        queue.push(now + Duration::from_millis(20), "late");
+        queue.push(now + Duration::from_millis(10), "first");
+        queue.push(now + Duration::from_millis(10), "second");
+        assert_eq!(queue.next_due(), Some(now + Duration::from_millis(10)));
+
+        assert_eq!(queue.pop_due(now), None);
+        let later = now + Duration::from_millis(15);
+        assert_eq!(queue.pop_due(later), Some("first"));
+        assert_eq!(queue.pop_due(later), Some("second"));
+        assert_eq!(queue.pop_due(later), None);
+        assert_eq!(queue.pop_due(now + Duration::from_millis(20)), Some("late"));
+        assert_eq!(queue.next_due(), None);
+    }
+
+    #[test]
+    fn pending_retries_are_taken_in_order() {
+        let now = Instant::now();
+        let mut queue = RetryQueue::new();
+        queue.push(now + Duration::from_millis(20), "late");
+        queue.push(now + Duration::from_millis(10), "first");
+        queue.push(now + Duration::from_millis(10), "second");
+        assert_eq!(queue.take_all(), ["first", "second", "late"]);
+        assert_eq!(queue.next_due(), None);
+    }
+}
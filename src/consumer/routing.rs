@@ -0,0 +1,8 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+// Hash of a routing key, stable for the lifetime of the process
+pub fn key_hash<K: Hash + ?Sized>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
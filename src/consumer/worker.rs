@@ -0,0 +1,615 @@
+use std::{
+    iter,
+    net::SocketAddr,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{Receiver, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::{Instant, SystemTime},
+};
+
+use anyhow::anyhow;
+use mio::{Token, Waker};
+
+use super::{
+    Consumer, DedupKey, DispatchError, MessageContext,
+    batch::Batches,
+    dedup,
+    drain::Drain,
+    handler,
+    limit::{Limits, Permit, Throttled},
+    queue::Queues,
+    retry::{self, RetryQueue},
+};
+use crate::{
+    frame::{self, Envelope},
+    topic::TopicEnum,
+};
+
+pub enum Task<T> {
+    Job(Job<T>),
+    // A message of a topic routed by key, queued on the worker of its connection which takes its
+    // key and forwards it to the worker of the key
+    Route(Job<T>),
+    Unknown(UnknownJob),
+}
+
+// A decoded message, the frame buffer is kept whole so handlers can borrow from the payload
+pub struct Job<T> {
+    pub topic: T,
+    pub frame: Vec<u8>,
+    pub peer: SocketAddr,
+    pub received_at: SystemTime,
+    // Deliveries to the handler so far, including this one
+    pub attempt: u32,
+    // Only held to be released when the job is dropped
+    #[allow(dead_code)]
+    pub credit: Credit,
+}
+
+// A message of a topic the consumer doesn't know, handed to its fallback handler once
+pub struct UnknownJob {
+    pub topic: Arc<str>,
+    pub frame: Vec<u8>,
+    #[allow(dead_code)]
+    pub credit: Credit,
+}
+
+// Credit of a message of the connection identified by `token`, taken out of the load of the worker
+// which granted it. It is released when dropped: once the message is processed, nacked for good
+// or lost with a worker that stopped.
+pub struct Credit {
+    token: Token,
+    load: Arc<AtomicUsize>,
+    // Messages of the connection held back by a limit
+    parked: Arc<AtomicUsize>,
+    is_parked: bool,
+    completions: Completions,
+}
+
+impl Credit {
+    pub fn new(
+        token: Token,
+        load: Arc<AtomicUsize>,
+        parked: Arc<AtomicUsize>,
+        completions: Completions,
+    ) -> Self {
+        completions.drain.hold();
+        Self {
+            token,
+            load,
+            parked,
+            is_parked: false,
+            completions,
+        }
+    }
+
+    // The message is held back by a limit: it leaves its place in the worker's load to the other
+    // connections and takes one in the credit window of its own
+    pub fn park(&mut self) {
+        if !self.is_parked {
+            self.is_parked = true;
+            self.parked.fetch_add(1, Ordering::Relaxed);
+            self.load.fetch_sub(1, Ordering::Relaxed);
+            self.completions.wake(self.token);
+        }
+    }
+
+    pub fn unpark(&mut self) {
+        if self.is_parked {
+            self.is_parked = false;
+            self.load.fetch_add(1, Ordering::Relaxed);
+            self.parked.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for Credit {
+    fn drop(&mut self) {
+        if self.is_parked {
+            self.parked.fetch_sub(1, Ordering::Relaxed);
+        } else {
+            self.load.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.completions.drain.release();
+        self.completions.wake(self.token);
+    }
+}
+
+impl<T: TopicEnum> Job<T> {
+    fn payload(&self) -> &[u8] {
+        &self.frame[frame::HEADER_LEN..self.frame.len() - frame::CRC_LEN]
+    }
+
+    fn open(&self) -> Result<(MessageContext, &[u8]), DispatchError> {
+        MessageContext::open(
+            self.topic,
+            self.payload(),
+            self.peer,
+            self.received_at,
+            self.attempt,
+        )
+    }
+
+    fn dispatch<C: Consumer<T>>(&self, consumer: &C) -> Result<(), DispatchError> {
+        let (context, body) = self.open()?;
+        let key = DedupKey::new(self.topic, &context);
+        let store = consumer.dedup_store();
+        if dedup::seen(store, &key) {
+            return Ok(());
+        }
+        let result = handler::catch_panic(|| consumer.dispatch(self.topic, body, &context));
+        if result.is_ok() {
+            dedup::record(store, key, context.topic);
+        }
+        result
+    }
+}
+
+impl UnknownJob {
+    fn run<C: Consumer<T>, T: TopicEnum>(&self, consumer: &C, place: &str) {
+        let payload = &self.frame[frame::HEADER_LEN..self.frame.len() - frame::CRC_LEN];
+        let body = Envelope::decode(payload).map_or(payload, |message| message.body);
+        let result = handler::catch_panic(|| {
+            consumer
+                .fallback(&self.topic, body)
+                .map_err(DispatchError::Handler)
+        });
+        if let Err(err) = result {
+            eprintln!(
+                "Fallback for {} message failed on {}: {}",
+                self.topic, place, err
+            );
+        }
+    }
+}
+
+// Runs the handler of a batch topic once for the jobs still to process, with one result per job
+fn dispatch_batch<C: Consumer<T>, T: TopicEnum>(
+    consumer: &C,
+    topic: T,
+    jobs: &[Job<T>],
+) -> Vec<Result<(), DispatchError>> {
+    let store = consumer.dedup_store();
+    let mut results = jobs.iter().map(|_| Ok(())).collect::<Vec<_>>();
+    let mut indices = Vec::new();
+    let mut keys = Vec::new();
+    let mut contexts = Vec::new();
+    let mut bodies = Vec::new();
+
+    for (i, job) in jobs.iter().enumerate() {
+        match job.open() {
+            Ok((context, body)) => {
+                let key = DedupKey::new(topic, &context);
+                if !dedup::seen(store, &key) {
+                    indices.push(i);
+                    keys.push(key);
+                    contexts.push(context);
+                    bodies.push(body);
+                }
+            }
+            Err(err) => results[i] = Err(err),
+        }
+    }
+    if bodies.is_empty() {
+        return results;
+    }
+
+    let outcomes = handler::catch_panic_batch(bodies.len(), || {
+        consumer.dispatch_batch(topic, &bodies, &contexts)
+    });
+    for ((i, key), result) in indices.into_iter().zip(keys).zip(outcomes) {
+        if result.is_ok() {
+            dedup::record(store, key, topic.name());
+        }
+        results[i] = result;
+    }
+    results
+}
+
+// Tells the reactor which connections had a message processed, so it can grant them new credits.
+// The reactor is only woken up once per batch of completions.
+#[derive(Clone)]
+pub struct Completions {
+    sender: Sender<Token>,
+    waker: Arc<Waker>,
+    pending: Arc<AtomicBool>,
+    drain: Arc<Drain>,
+}
+
+impl Completions {
+    pub fn new(waker: Arc<Waker>, drain: Arc<Drain>) -> (Self, Receiver<Token>, Arc<AtomicBool>) {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let pending = Arc::new(AtomicBool::new(false));
+        let completions = Self {
+            sender,
+            waker,
+            pending: pending.clone(),
+            drain,
+        };
+        (completions, receiver, pending)
+    }
+
+    // Lets the reactor grant credits again, capacity was freed on the worker
+    fn wake(&self, token: Token) {
+        if self.sender.send(token).is_ok() && !self.pending.swap(true, Ordering::SeqCst) {
+            let _ = self.waker.wake();
+        }
+    }
+}
+
+// Shared by the workers of a reactor
+pub struct WorkerContext<C, T> {
+    pub consumer: Arc<C>,
+    pub queues: Arc<Queues<T>>,
+    pub limits: Arc<Limits>,
+    pub drain: Arc<Drain>,
+}
+
+impl<C, T> Clone for WorkerContext<C, T> {
+    fn clone(&self) -> Self {
+        Self {
+            consumer: self.consumer.clone(),
+            queues: self.queues.clone(),
+            limits: self.limits.clone(),
+            drain: self.drain.clone(),
+        }
+    }
+}
+
+pub struct Worker {
+    id: usize,
+    // Credits granted to its connections plus their messages queued or in process, on any worker,
+    // except those held back by a limit
+    pub load: Arc<AtomicUsize>,
+    // Its connections which asked for credits, sharing its capacity
+    pub wanting: Arc<AtomicUsize>,
+    thread: JoinHandle<()>,
+}
+
+impl Worker {
+    pub fn spawn<C: Consumer<T>, T: TopicEnum>(id: usize, context: WorkerContext<C, T>) -> Self {
+        Self {
+            id,
+            load: Arc::new(AtomicUsize::new(0)),
+            wanting: Arc::new(AtomicUsize::new(0)),
+            thread: start(id, context),
+        }
+    }
+
+    // Tasks pushed to its queue since it last looked for one
+    pub fn wake(&self) {
+        self.thread.thread().unpark();
+    }
+
+    // Lets the worker finish its queued messages then stop, returns its thread to wait for
+    pub fn stop<T>(self, queues: &Queues<T>) -> JoinHandle<()> {
+        queues.close(self.id);
+        self.wake();
+        self.thread
+    }
+}
+
+// Handler panics are caught by Job::dispatch. Anything else panicking on the worker loses the
+// messages it held, which releases their credits, and the worker starts over with its queue.
+fn start<C: Consumer<T>, T: TopicEnum>(id: usize, context: WorkerContext<C, T>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        context.queues.register(id);
+        while let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| work(id, &context))) {
+            eprintln!(
+                "Worker {} panicked, restarting it: {}",
+                id,
+                handler::panic_message(&panic)
+            );
+        }
+    })
+}
+
+fn work<C: Consumer<T>, T: TopicEnum>(id: usize, context: &WorkerContext<C, T>) {
+    let WorkerContext {
+        consumer,
+        queues,
+        limits,
+        drain,
+    } = context;
+    let consumer = &**consumer;
+    let mut retries = RetryQueue::new();
+    let mut batches = Batches::new();
+    let mut throttled = Throttled::new();
+    let place = format!("worker {}", id);
+    let mut stopped = false;
+
+    loop {
+        let due = [batches.next_due(), throttled.next_due()]
+            .into_iter()
+            .flatten()
+            .min();
+        match next_job(
+            consumer,
+            queues,
+            id,
+            stopped,
+            &mut retries,
+            due,
+            drain.deadline(),
+        ) {
+            // A topic with messages held back keeps them in order
+            Next::Job(mut job) => {
+                let topic = job.topic.id();
+                if throttled.is_waiting(topic) {
+                    job.credit.park();
+                    throttled.push(topic, job);
+                } else {
+                    match limits.acquire(topic, Instant::now()) {
+                        Ok(permit) => {
+                            process(consumer, job, permit, &mut batches, &mut retries, &place)
+                        }
+                        Err(due) => {
+                            job.credit.park();
+                            throttled.defer(topic, job, due);
+                        }
+                    }
+                }
+            }
+            Next::Unknown(job) => job.run(consumer, &place),
+            Next::Due => {
+                for batch in batches.take_due(Instant::now()) {
+                    run_batch(consumer, batch, &mut retries, &place);
+                }
+                release(
+                    consumer,
+                    limits,
+                    &mut throttled,
+                    &mut batches,
+                    &mut retries,
+                    &place,
+                );
+            }
+            // Once the queue is empty, gathered messages are handed over right away while
+            // retries and throttled messages are still waited for
+            Next::Stop if !stopped => {
+                stopped = true;
+                for batch in batches.take_all() {
+                    run_batch(consumer, batch, &mut retries, &place);
+                }
+            }
+            Next::Stop => break,
+            Next::Deadline => {
+                let mut left = Vec::new();
+                let mut unknown = Vec::new();
+                for task in iter::from_fn(|| queues.pop(id)) {
+                    match task {
+                        Task::Job(job) => left.push(job),
+                        Task::Route(job) => {
+                            queues.routed();
+                            left.push(job);
+                        }
+                        Task::Unknown(job) => unknown.push(job),
+                    }
+                }
+                left.extend(retries.take_all());
+                left.extend(throttled.take_all());
+                left.extend(batches.take_all().into_iter().flatten());
+                if left.is_empty() && unknown.is_empty() {
+                    break;
+                }
+
+                eprintln!(
+                    "Nacking {} messages left on {} at the drain deadline",
+                    left.len() + unknown.len(),
+                    place
+                );
+                // Counted before their credit is released, so they are never seen as completed
+                drain.nacked(unknown.len());
+                drop(unknown);
+                let err = anyhow!("Not processed by the drain deadline");
+                for job in left {
+                    drain.nacked(1);
+                    dead_letter(consumer, &job, &err, &place);
+                }
+                break;
+            }
+        }
+    }
+}
+
+// Forwards a job to the worker of its key, returns it if it stays on this one. Messages without a
+// key or a valid envelope stay on the worker of their connection, as do those whose key function
+// panicked.
+fn route<C: Consumer<T>, T: TopicEnum>(
+    consumer: &C,
+    queues: &Queues<T>,
+    id: usize,
+    job: Job<T>,
+) -> Option<Job<T>> {
+    let key = job.open().ok().and_then(|(context, body)| {
+        handler::catch_panic_key(|| consumer.route_key(job.topic, body, &context)).unwrap_or_else(
+            |err| {
+                eprintln!("{} message on worker {}: {}", job.topic.name(), id, err);
+                None
+            },
+        )
+    });
+    match key.map(|key| (key % queues.workers() as u64) as usize) {
+        Some(target) if target != id => {
+            queues.forward(target, Task::Job(job));
+            None
+        }
+        _ => {
+            queues.routed();
+            Some(job)
+        }
+    }
+}
+
+// Gathers the job into the batch of its topic or dispatches it, the permit is held until then
+fn process<C: Consumer<T>, T: TopicEnum>(
+    consumer: &C,
+    job: Job<T>,
+    _permit: Permit<'_>,
+    batches: &mut Batches<Job<T>>,
+    retries: &mut RetryQueue<Job<T>>,
+    place: &str,
+) {
+    match consumer.batch_policy(job.topic) {
+        Some(policy) => {
+            if let Some(batch) = batches.push(job.topic.id(), job, policy) {
+                run_batch(consumer, batch, retries, place);
+            }
+        }
+        None => {
+            let result = job.dispatch(consumer);
+            settle(consumer, job, result, retries, place);
+        }
+    }
+}
+
+// Processes the held back messages of the topics due for another try, as long as their limits
+// allow it
+fn release<C: Consumer<T>, T: TopicEnum>(
+    consumer: &C,
+    limits: &Limits,
+    throttled: &mut Throttled<Job<T>>,
+    batches: &mut Batches<Job<T>>,
+    retries: &mut RetryQueue<Job<T>>,
+    place: &str,
+) {
+    for topic in throttled.due_topics(Instant::now()) {
+        while let Some(mut job) = throttled.pop(topic) {
+            match limits.acquire(topic, Instant::now()) {
+                Ok(permit) => {
+                    job.credit.unpark();
+                    process(consumer, job, permit, batches, retries, place)
+                }
+                Err(due) => {
+                    throttled.defer(topic, job, due);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn run_batch<C: Consumer<T>, T: TopicEnum>(
+    consumer: &C,
+    jobs: Vec<Job<T>>,
+    retries: &mut RetryQueue<Job<T>>,
+    place: &str,
+) {
+    let Some(topic) = jobs.first().map(|job| job.topic) else {
+        return;
+    };
+    let results = dispatch_batch(consumer, topic, &jobs);
+    for (job, result) in jobs.into_iter().zip(results) {
+        settle(consumer, job, result, retries, place);
+    }
+}
+
+// A failed job is scheduled again if its retry policy allows it, otherwise it is handed to the
+// dead letter handler and dropped, which releases its credit
+fn settle<C: Consumer<T>, T: TopicEnum>(
+    consumer: &C,
+    mut job: Job<T>,
+    result: Result<(), DispatchError>,
+    retries: &mut RetryQueue<Job<T>>,
+    place: &str,
+) {
+    let Err(err) = result else {
+        return;
+    };
+    let policy = consumer.retry_policy(job.topic);
+    // Retried messages keep their load, so their credits are not granted again
+    match retry::next_attempt(job.topic, job.attempt, err, policy, &place) {
+        Ok(delay) => {
+            job.attempt += 1;
+            retries.push(Instant::now() + delay, job);
+        }
+        Err(err) => dead_letter(consumer, &job, &err, place),
+    }
+}
+
+// The credit of the job is released once it is dropped after this
+fn dead_letter<C: Consumer<T>, T: TopicEnum>(
+    consumer: &C,
+    job: &Job<T>,
+    err: &anyhow::Error,
+    place: &str,
+) {
+    let (context, body) = MessageContext::open_or_raw(
+        job.topic,
+        job.payload(),
+        job.peer,
+        job.received_at,
+        job.attempt,
+    );
+    retry::dead_letter(consumer, job.topic, body, &context, err, &place);
+}
+
+enum Next<T> {
+    Job(Job<T>),
+    Unknown(UnknownJob),
+    // The linger time of a batch or the wait of a throttled topic is over
+    Due,
+    // The queue is closed and empty, or nothing is left pending once it was
+    Stop,
+    // The drain deadline passed, whatever is left is nacked
+    Deadline,
+}
+
+// Next message to process, retries come first once they are due. Once the queue is closed and
+// empty, the worker is `stopped` and only waits for its pending messages.
+fn next_job<C: Consumer<T>, T: TopicEnum>(
+    consumer: &C,
+    queues: &Queues<T>,
+    id: usize,
+    stopped: bool,
+    retries: &mut RetryQueue<Job<T>>,
+    due: Option<Instant>,
+    deadline: Option<Instant>,
+) -> Next<T> {
+    loop {
+        let now = Instant::now();
+        if deadline.is_some_and(|deadline| deadline <= now) {
+            return Next::Deadline;
+        }
+        if let Some(job) = retries.pop_due(now) {
+            return Next::Job(job);
+        }
+        if due.is_some_and(|due| due <= now) {
+            return Next::Due;
+        }
+
+        let due = match (retries.next_due(), due) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        // Read before looking at the queue, so a task forwarded before the last one was routed is
+        // seen
+        let routing = queues.is_routing();
+        match queues.pop(id) {
+            Some(Task::Job(job)) => return Next::Job(job),
+            Some(Task::Route(job)) => {
+                if let Some(job) = route(consumer, queues, id, job) {
+                    return Next::Job(job);
+                }
+                continue;
+            }
+            Some(Task::Unknown(job)) => return Next::Unknown(job),
+            None => {}
+        }
+
+        // The deadline only needs waking up for while something is pending
+        let wake = due.map(|due| deadline.map_or(due, |deadline| due.min(deadline)));
+        if queues.is_closed(id) && !routing {
+            match wake {
+                Some(wake) if stopped => thread::sleep(wake - now),
+                _ => return Next::Stop,
+            }
+        } else {
+            queues.wait(id, wake.map(|wake| wake - now));
+        }
+    }
+}
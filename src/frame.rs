@@ -0,0 +1,375 @@
+use std::io::{ErrorKind, Read, Write};
+
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+
+// Frame layout (big endian): kind u8 | topic u16 | payload_len u32 | payload | crc32c u32
+// The checksum covers every byte that precedes it.
+pub const HEADER_LEN: usize = 7;
+pub const CRC_LEN: usize = 4;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Hello = 0,
+    Welcome = 1,
+    Reject = 2,
+    Message = 3,
+    Heartbeat = 4,
+    // Payload is a u32 number of extra messages the producer may send
+    Credit = 5,
+    // Sent by pull consumers to a broker, which answers with a Batch
+    Fetch = 6,
+    Batch = 7,
+    // Sent by a producer out of credits, which are only granted to connections asking for them
+    Demand = 8,
+    // Sent by a consumer taking back the credits of an idle connection
+    Revoke = 9,
+    // Answer to a Revoke, the payload is the u32 number of credits the producer gave up
+    Return = 10,
+}
+
+impl TryFrom<u8> for FrameKind {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(FrameKind::Hello),
+            1 => Ok(FrameKind::Welcome),
+            2 => Ok(FrameKind::Reject),
+            3 => Ok(FrameKind::Message),
+            4 => Ok(FrameKind::Heartbeat),
+            5 => Ok(FrameKind::Credit),
+            6 => Ok(FrameKind::Fetch),
+            7 => Ok(FrameKind::Batch),
+            8 => Ok(FrameKind::Demand),
+            9 => Ok(FrameKind::Revoke),
+            10 => Ok(FrameKind::Return),
+            _ => Err(anyhow!("Unknown frame kind {}", value)),
+        }
+    }
+}
+
+// Payload of the Hello frame: the (name, id) pairs the producer will send on this connection
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hello {
+    pub topics: Vec<(String, u16)>,
+}
+
+// Payload of the Fetch frame, for the topic of the frame. The group's offset is first moved to
+// `commit` if set, then up to `max_messages` are returned from it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Fetch {
+    pub group: String,
+    pub commit: Option<u64>,
+    pub max_messages: u32,
+    // Largest frame the consumer reads, the broker returns fewer messages to stay within it
+    pub max_frame_size: u32,
+}
+
+// Payload of the Batch frame: Message frames in offset order, starting at offset `first`. A message
+// too large for the consumer's frame size is sent empty, for the consumer to skip it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Batch {
+    pub first: u64,
+    pub messages: Vec<Vec<u8>>,
+}
+
+// Name and value pairs sent along with a message
+pub type Headers = Vec<(String, String)>;
+
+// Payload of the Message frame (big endian): id u64 | producer u64 | headers_len u32 | headers | body
+// Headers are postcard encoded and left empty when there are none, the body is the encoded value.
+// Message ids are unique per producer, which picks a random id when it starts.
+pub struct Envelope<'a> {
+    pub id: u64,
+    pub producer: u64,
+    pub headers: &'a [u8],
+    pub body: &'a [u8],
+}
+
+pub const ENVELOPE_LEN: usize = 20;
+
+impl<'a> Envelope<'a> {
+    pub fn encode(id: u64, producer: u64, headers: &Headers, body: &[u8]) -> Result<Vec<u8>> {
+        let headers = if headers.is_empty() {
+            Vec::new()
+        } else {
+            postcard::to_stdvec(headers)?
+        };
+
+        let mut buf = Vec::with_capacity(ENVELOPE_LEN + headers.len() + body.len());
+        buf.extend(&id.to_be_bytes());
+        buf.extend(&producer.to_be_bytes());
+        buf.extend(&(headers.len() as u32).to_be_bytes());
+        buf.extend(&headers);
+        buf.extend(body);
+        Ok(buf)
+    }
+
+    pub fn decode(payload: &'a [u8]) -> Result<Self> {
+        if payload.len() < ENVELOPE_LEN {
+            bail!(
+                "Message too small: expected at least {} bytes, got {}",
+                ENVELOPE_LEN,
+                payload.len()
+            );
+        }
+
+        let (id, rest) = payload.split_at(8);
+        let (producer, rest) = rest.split_at(8);
+        let (headers_len, rest) = rest.split_at(4);
+        let headers_len = u32::from_be_bytes(headers_len.try_into()?) as usize;
+        if rest.len() < headers_len {
+            bail!(
+                "Message headers of {} bytes exceed the {} bytes left",
+                headers_len,
+                rest.len()
+            );
+        }
+
+        let (headers, body) = rest.split_at(headers_len);
+        Ok(Envelope {
+            id: u64::from_be_bytes(id.try_into()?),
+            producer: u64::from_be_bytes(producer.try_into()?),
+            headers,
+            body,
+        })
+    }
+
+    pub fn headers(&self) -> Result<Headers> {
+        if self.headers.is_empty() {
+            return Ok(Headers::new());
+        }
+        Ok(postcard::from_bytes(self.headers)?)
+    }
+}
+
+pub struct Frame<'a> {
+    pub kind: FrameKind,
+    pub topic: u16,
+    pub payload: &'a [u8],
+}
+
+pub fn encode(kind: FrameKind, topic: u16, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + payload.len() + CRC_LEN);
+
+    buf.push(kind as u8);
+    buf.extend(&topic.to_be_bytes());
+    buf.extend(&(payload.len() as u32).to_be_bytes());
+    buf.extend(payload);
+
+    let crc = crc32c::crc32c(&buf);
+    buf.extend(&crc.to_be_bytes());
+    buf
+}
+
+pub fn write<W: Write>(writer: &mut W, kind: FrameKind, topic: u16, payload: &[u8]) -> Result<()> {
+    writer.write_all(&encode(kind, topic, payload))?;
+    Ok(())
+}
+
+pub fn decode(buf: &[u8]) -> Result<Frame<'_>> {
+    if buf.len() < HEADER_LEN + CRC_LEN {
+        bail!(
+            "Buffer too small: expected at least {} bytes for a frame, got {}",
+            HEADER_LEN + CRC_LEN,
+            buf.len()
+        );
+    }
+
+    let (body, crc) = buf.split_at(buf.len() - CRC_LEN);
+    let expected = u32::from_be_bytes([crc[0], crc[1], crc[2], crc[3]]);
+    let computed = crc32c::crc32c(body);
+
+    if expected != computed {
+        bail!(
+            "Checksum mismatch: frame carries {:#010x}, computed {:#010x}",
+            expected,
+            computed
+        );
+    }
+
+    let kind = FrameKind::try_from(body[0])?;
+    let topic = u16::from_be_bytes([body[1], body[2]]);
+    let payload_len = u32::from_be_bytes([body[3], body[4], body[5], body[6]]) as usize;
+
+    if body.len() != HEADER_LEN + payload_len {
+        bail!(
+            "Frame size mismatch: expected {} bytes for payload, got {}",
+            payload_len,
+            body.len() - HEADER_LEN
+        );
+    }
+
+    Ok(Frame {
+        kind,
+        topic,
+        payload: &body[HEADER_LEN..],
+    })
+}
+
+// Reads one frame, checking its length prefix against the limit before allocating.
+// Returns None if the reader is at EOF before the first byte of a frame, a stream closed anywhere
+// after it is truncated and fails.
+pub fn read<R: Read>(reader: &mut R, max_frame_size: usize) -> Result<Option<Vec<u8>>> {
+    let mut header = [0u8; HEADER_LEN];
+    let mut read = 0;
+    while read < HEADER_LEN {
+        match reader.read(&mut header[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => bail!(truncated_header(read)),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    let mut buf = vec![0u8; size(&header, max_frame_size)?];
+    buf[..HEADER_LEN].copy_from_slice(&header);
+    reader.read_exact(&mut buf[HEADER_LEN..])?;
+
+    Ok(Some(buf))
+}
+
+fn truncated_header(read: usize) -> String {
+    format!(
+        "Connection closed after {} of the {} bytes of a frame header",
+        read, HEADER_LEN
+    )
+}
+
+// Total size of the frame starting with `header`, checked against the limit
+pub fn size(header: &[u8], max_frame_size: usize) -> Result<usize> {
+    let payload_len = u32::from_be_bytes([header[3], header[4], header[5], header[6]]) as usize;
+
+    let frame_size = HEADER_LEN + payload_len + CRC_LEN;
+    if frame_size > max_frame_size {
+        bail!(
+            "Frame size {} exceeds the limit of {} bytes",
+            frame_size,
+            max_frame_size
+        );
+    }
+    Ok(frame_size)
+}
+
+#[cfg(feature = "tokio")]
+pub async fn read_async<R: tokio::io::AsyncRead + Unpin>(
+    reader: &mut R,
+    max_frame_size: usize,
+) -> Result<Option<Vec<u8>>> {
+    use tokio::io::AsyncReadExt;
+
+    let mut header = [0u8; HEADER_LEN];
+    let mut read = 0;
+    while read < HEADER_LEN {
+        match reader.read(&mut header[read..]).await {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => bail!(truncated_header(read)),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    let mut buf = vec![0u8; size(&header, max_frame_size)?];
+    buf[..HEADER_LEN].copy_from_slice(&header);
+    reader.read_exact(&mut buf[HEADER_LEN..]).await?;
+
+    Ok(Some(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn frames_round_trip() {
+        let buf = encode(FrameKind::Message, 42, b"payload");
+        let frame = decode(&buf).unwrap();
+        assert_eq!(frame.kind, FrameKind::Message);
+        assert_eq!(frame.topic, 42);
+        assert_eq!(frame.payload, b"payload");
+    }
+
+    #[test]
+    fn corrupt_frames_fail_their_checksum() {
+        let mut buf = encode(FrameKind::Message, 1, b"payload");
+        buf[HEADER_LEN] ^= 1;
+        let err = decode(&buf).err().unwrap();
+        assert!(err.to_string().starts_with("Checksum mismatch"), "{}", err);
+
+        let mut buf = encode(FrameKind::Message, 1, b"payload");
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        assert!(decode(&buf).is_err());
+    }
+
+    #[test]
+    fn short_and_mislabeled_frames_are_rejected() {
+        assert!(decode(&[0; HEADER_LEN]).is_err());
+
+        // A length prefix that doesn't match the payload, with a valid checksum
+        let mut buf = encode(FrameKind::Message, 1, b"payload");
+        buf.truncate(buf.len() - CRC_LEN);
+        buf[6] += 1;
+        let crc = crc32c::crc32c(&buf);
+        buf.extend(&crc.to_be_bytes());
+        assert!(decode(&buf).is_err());
+
+        let mut buf = encode(FrameKind::Message, 1, b"");
+        buf.truncate(buf.len() - CRC_LEN);
+        buf[0] = 200;
+        let crc = crc32c::crc32c(&buf);
+        buf.extend(&crc.to_be_bytes());
+        assert!(decode(&buf).is_err());
+    }
+
+    #[test]
+    fn oversized_frames_are_refused_before_reading_them() {
+        let buf = encode(FrameKind::Message, 1, &[0; 100]);
+        let mut reader = Cursor::new(&buf);
+        let err = read(&mut reader, 50).err().unwrap();
+        assert!(err.to_string().contains("exceeds the limit"), "{}", err);
+        assert_eq!(reader.position(), HEADER_LEN as u64);
+
+        let mut reader = Cursor::new(&buf);
+        assert_eq!(read(&mut reader, buf.len()).unwrap(), Some(buf.clone()));
+    }
+
+    #[test]
+    fn eof_is_only_clean_between_frames() {
+        assert_eq!(read(&mut Cursor::new(&[]), 1024).unwrap(), None);
+
+        let buf = encode(FrameKind::Heartbeat, 0, &[]);
+        for len in 1..buf.len() {
+            assert!(
+                read(&mut Cursor::new(&buf[..len]), 1024).is_err(),
+                "{}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn envelopes_carry_ids_and_headers() {
+        let headers = vec![("trace".to_string(), "abc".to_string())];
+        let payload = Envelope::encode(7, 9, &headers, b"body").unwrap();
+        let envelope = Envelope::decode(&payload).unwrap();
+        assert_eq!((envelope.id, envelope.producer), (7, 9));
+        assert_eq!(envelope.headers().unwrap(), headers);
+        assert_eq!(envelope.body, b"body");
+
+        let payload = Envelope::encode(1, 2, &Headers::new(), b"").unwrap();
+        assert_eq!(payload.len(), ENVELOPE_LEN);
+        assert!(Envelope::decode(&payload[..ENVELOPE_LEN - 1]).is_err());
+
+        // Headers longer than what is left
+        let mut payload = Envelope::encode(1, 2, &headers, b"").unwrap();
+        payload[19] += 1;
+        assert!(Envelope::decode(&payload).is_err());
+    }
+}
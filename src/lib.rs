@@ -1,3 +1,6 @@
+pub mod frame;
+pub mod topic;
+
 #[cfg(feature = "broker")]
 pub mod broker;
 
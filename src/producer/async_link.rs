@@ -0,0 +1,219 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use anyhow::{Result, anyhow, bail};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{
+        TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    sync::{Mutex, Semaphore},
+    task::JoinSet,
+    time,
+};
+
+use super::ProducerConfig;
+use crate::frame::{self, FrameKind, Hello};
+
+const MAX_CONTROL_FRAME_SIZE: usize = 64 * 1024;
+
+// Async counterpart of Link: the monitor and heartbeats run as tasks on the caller's runtime and
+// credits are the permits of a semaphore, closed when the connection fails.
+pub struct AsyncLink {
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    alive: Arc<AtomicBool>,
+    credits: Arc<Semaphore>,
+    // Senders waiting for credits since their Demand
+    waiting: Arc<AtomicUsize>,
+    config: ProducerConfig,
+    // Aborted with the link
+    _tasks: JoinSet<()>,
+}
+
+impl AsyncLink {
+    pub async fn connect(
+        addr: &str,
+        topic: &str,
+        topic_id: u16,
+        config: &ProducerConfig,
+        available: Arc<AtomicBool>,
+    ) -> Result<Self> {
+        let (reader, writer) = handshake(addr, topic, topic_id, config).await?;
+        let writer = Arc::new(Mutex::new(writer));
+        let alive = Arc::new(AtomicBool::new(true));
+        let credits = Arc::new(Semaphore::new(0));
+        let waiting = Arc::new(AtomicUsize::new(0));
+        let mut tasks = JoinSet::new();
+
+        if let Some(interval) = config.heartbeat_interval {
+            let writer = writer.clone();
+            tasks.spawn(async move {
+                let mut ticks = time::interval_at(time::Instant::now() + interval, interval);
+                loop {
+                    ticks.tick().await;
+                    let heartbeat = frame::encode(FrameKind::Heartbeat, 0, &[]);
+                    if writer.lock().await.write_all(&heartbeat).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+
+        let monitor_alive = alive.clone();
+        let monitor_writer = writer.clone();
+        let monitor_credits = credits.clone();
+        let monitor_waiting = waiting.clone();
+        let monitor_config = config.clone();
+        let addr = addr.to_string();
+        tasks.spawn(async move {
+            if let Err(err) = monitor(
+                reader,
+                &monitor_writer,
+                &monitor_credits,
+                &monitor_waiting,
+                &monitor_config,
+            )
+            .await
+                && monitor_alive.load(Ordering::Relaxed)
+            {
+                eprintln!("Receiver {} failed: {}", addr, err);
+            }
+            if monitor_alive.swap(false, Ordering::Relaxed) {
+                available.store(false, Ordering::Relaxed);
+            }
+            // Wake up senders waiting for credits that will never come
+            monitor_credits.close();
+        });
+
+        Ok(Self {
+            writer,
+            alive,
+            credits,
+            waiting,
+            config: config.clone(),
+            _tasks: tasks,
+        })
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Relaxed)
+    }
+
+    // Asks the consumer for credits when out of them and waits for one, then sends the message
+    pub async fn send_message(&self, topic: u16, payload: &[u8]) -> Result<()> {
+        // Counted as waiting before the Demand, so a Revoke handled after it asks again
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        let acquired = self.acquire_credit().await;
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+        acquired?;
+
+        let message = frame::encode(FrameKind::Message, topic, payload);
+        self.writer.lock().await.write_all(&message).await?;
+        Ok(())
+    }
+
+    async fn acquire_credit(&self) -> Result<()> {
+        if self.credits.available_permits() == 0 && self.is_alive() {
+            let demand = frame::encode(FrameKind::Demand, 0, &[]);
+            self.writer.lock().await.write_all(&demand).await?;
+        }
+        let timeout = self.config.credit_timeout;
+        time::timeout(timeout, self.credits.acquire())
+            .await
+            .map_err(|_| anyhow!("No credit granted within {:?}", timeout))?
+            .map_err(|_| anyhow!("Connection closed while waiting for credits"))?
+            .forget();
+        Ok(())
+    }
+}
+
+impl Drop for AsyncLink {
+    fn drop(&mut self) {
+        self.alive.store(false, Ordering::Relaxed);
+    }
+}
+
+async fn handshake(
+    addr: &str,
+    topic: &str,
+    topic_id: u16,
+    config: &ProducerConfig,
+) -> Result<(OwnedReadHalf, OwnedWriteHalf)> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+
+    let hello = Hello {
+        topics: vec![(topic.to_string(), topic_id)],
+    };
+    let hello = frame::encode(FrameKind::Hello, 0, &postcard::to_stdvec(&hello)?);
+    stream.write_all(&hello).await?;
+
+    let buf = time::timeout(
+        config.handshake_timeout,
+        frame::read_async(&mut stream, MAX_CONTROL_FRAME_SIZE),
+    )
+    .await
+    .map_err(|_| anyhow!("Handshake with {} timed out", addr))??;
+    let Some(buf) = buf else {
+        bail!("Connection to {} closed during handshake", addr);
+    };
+
+    let frame = frame::decode(&buf)?;
+    match frame.kind {
+        FrameKind::Welcome => Ok(stream.into_split()),
+        FrameKind::Reject => bail!(
+            "Handshake with {} rejected: {}",
+            addr,
+            String::from_utf8_lossy(frame.payload)
+        ),
+        kind => bail!("Unexpected {:?} frame during handshake with {}", kind, addr),
+    }
+}
+
+async fn monitor(
+    mut reader: OwnedReadHalf,
+    writer: &Mutex<OwnedWriteHalf>,
+    credits: &Semaphore,
+    waiting: &AtomicUsize,
+    config: &ProducerConfig,
+) -> Result<()> {
+    let silence = config
+        .heartbeat_interval
+        .map(|interval| interval * config.heartbeat_misses);
+
+    loop {
+        let read = frame::read_async(&mut reader, MAX_CONTROL_FRAME_SIZE);
+        let buf = match silence {
+            Some(timeout) => time::timeout(timeout, read)
+                .await
+                .map_err(|_| anyhow!("missed {} heartbeats", config.heartbeat_misses))??,
+            None => read.await?,
+        };
+        let Some(buf) = buf else {
+            bail!("connection closed by peer");
+        };
+
+        let frame = frame::decode(&buf)?;
+        match frame.kind {
+            FrameKind::Heartbeat => {}
+            FrameKind::Credit => {
+                let granted = u32::from_be_bytes(frame.payload.try_into()?);
+                credits.add_permits(granted as usize);
+            }
+            // Gives up the credits not taken yet, messages which already took one are still sent.
+            // Senders still waiting ask again, their Demand was answered by the revoked credits.
+            FrameKind::Revoke => {
+                let returned = credits.forget_permits(usize::MAX) as u32;
+                let mut frames = frame::encode(FrameKind::Return, 0, &returned.to_be_bytes());
+                if waiting.load(Ordering::SeqCst) > 0 {
+                    frames.extend(frame::encode(FrameKind::Demand, 0, &[]));
+                }
+                writer.lock().await.write_all(&frames).await?;
+            }
+            kind => bail!("unexpected {:?} frame from consumer", kind),
+        }
+    }
+}
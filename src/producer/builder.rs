@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+
+use anyhow::{Result, anyhow, bail};
+use serde::Serialize;
+
+use super::{BrokerStatus, Headers, ProducerConfig, Receivers};
+use crate::topic;
+
+// Registers topics and their receivers at runtime, for services whose topics are not known at
+// compile time. Topic ids are derived from the names like those of #[producer] structs.
+#[derive(Default)]
+pub struct ProducerBuilder {
+    config: ProducerConfig,
+    topics: Vec<String>,
+    receivers: Vec<(String, usize, String)>,
+}
+
+impl ProducerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn config(mut self, config: ProducerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn topic(mut self, name: &str) -> Self {
+        self.topics.push(name.to_string());
+        self
+    }
+
+    // Registers the topic as well if it was not
+    pub fn receiver(mut self, name: &str, id: usize, addr: &str) -> Self {
+        if !self.topics.iter().any(|topic| topic == name) {
+            self.topics.push(name.to_string());
+        }
+        self.receivers
+            .push((name.to_string(), id, addr.to_string()));
+        self
+    }
+
+    // Fails on topics registered twice or whose ids collide, which #[producer] rejects at compile
+    // time
+    pub fn build(self) -> Result<DynamicProducer> {
+        let mut producer = DynamicProducer {
+            config: self.config,
+            topics: HashMap::new(),
+        };
+        for name in self.topics {
+            producer.add_topic(&name)?;
+        }
+        for (name, id, addr) in self.receivers {
+            producer.add_receiver(&name, id, &addr)?;
+        }
+        Ok(producer)
+    }
+}
+
+struct Topic {
+    id: u16,
+    receivers: Receivers<()>,
+}
+
+pub struct DynamicProducer {
+    config: ProducerConfig,
+    topics: HashMap<String, Topic>,
+}
+
+impl DynamicProducer {
+    pub fn add_topic(&mut self, name: &str) -> Result<()> {
+        let id = topic::id(name);
+        if let Some((other, _)) = self.topics.iter().find(|(_, topic)| topic.id == id) {
+            if other == name {
+                bail!("Topic {} is registered twice", name);
+            }
+            bail!("Topics {} and {} have the same id {}", other, name, id);
+        }
+        self.topics.insert(
+            name.to_string(),
+            Topic {
+                id,
+                receivers: Receivers::with_config(self.config.clone()),
+            },
+        );
+        Ok(())
+    }
+
+    pub fn add_receiver(&mut self, name: &str, id: usize, addr: &str) -> Result<()> {
+        self.topic(name)?.receivers.add_receiver(id, addr);
+        Ok(())
+    }
+
+    pub fn remove_receiver(&mut self, name: &str, id: usize) -> Result<()> {
+        self.topic(name)?.receivers.remove_receiver(id);
+        Ok(())
+    }
+
+    pub fn statuses(&self, name: &str) -> Option<Vec<(usize, BrokerStatus)>> {
+        self.topics
+            .get(name)
+            .map(|topic| topic.receivers.statuses())
+    }
+
+    pub fn send<V: Serialize>(&mut self, name: &str, payload: &V) -> Result<()> {
+        self.send_with_headers(name, payload, &Headers::new())
+    }
+
+    pub fn send_with_headers<V: Serialize>(
+        &mut self,
+        name: &str,
+        payload: &V,
+        headers: &Headers,
+    ) -> Result<()> {
+        self.send_raw_with_headers(name, &postcard::to_stdvec(payload)?, headers)
+    }
+
+    pub fn send_raw(&mut self, name: &str, payload_bytes: &[u8]) -> Result<()> {
+        self.send_raw_with_headers(name, payload_bytes, &Headers::new())
+    }
+
+    pub fn send_raw_with_headers(
+        &mut self,
+        name: &str,
+        payload_bytes: &[u8],
+        headers: &Headers,
+    ) -> Result<()> {
+        let topic = self.topic(name)?;
+        topic
+            .receivers
+            .send_raw_with_headers(name, topic.id, payload_bytes, headers)
+    }
+
+    fn topic(&mut self, name: &str) -> Result<&mut Topic> {
+        self.topics
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("Unknown topic {}", name))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl DynamicProducer {
+    pub async fn send_async<V: Serialize>(&mut self, name: &str, payload: &V) -> Result<()> {
+        self.send_with_headers_async(name, payload, &Headers::new())
+            .await
+    }
+
+    pub async fn send_with_headers_async<V: Serialize>(
+        &mut self,
+        name: &str,
+        payload: &V,
+        headers: &Headers,
+    ) -> Result<()> {
+        let payload_bytes = postcard::to_stdvec(payload)?;
+        self.send_raw_with_headers_async(name, &payload_bytes, headers)
+            .await
+    }
+
+    pub async fn send_raw_async(&mut self, name: &str, payload_bytes: &[u8]) -> Result<()> {
+        self.send_raw_with_headers_async(name, payload_bytes, &Headers::new())
+            .await
+    }
+
+    pub async fn send_raw_with_headers_async(
+        &mut self,
+        name: &str,
+        payload_bytes: &[u8],
+        headers: &Headers,
+    ) -> Result<()> {
+        let topic = self.topic(name)?;
+        topic
+            .receivers
+            .send_raw_with_headers_async(name, topic.id, payload_bytes, headers)
+            .await
+    }
+}
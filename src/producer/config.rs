@@ -0,0 +1,22 @@
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct ProducerConfig {
+    pub handshake_timeout: Duration,
+    // Heartbeats are sent every interval, a receiver silent for `heartbeat_misses` intervals is FAILED
+    pub heartbeat_interval: Option<Duration>,
+    pub heartbeat_misses: u32,
+    // How long a send waits for the consumer to grant credits before failing
+    pub credit_timeout: Duration,
+}
+
+impl Default for ProducerConfig {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: Duration::from_secs(5),
+            heartbeat_interval: Some(Duration::from_secs(5)),
+            heartbeat_misses: 3,
+            credit_timeout: Duration::from_secs(30),
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    io::ErrorKind,
+    mem,
+    net::{Shutdown, TcpStream},
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+
+use super::ProducerConfig;
+use crate::frame::{self, FrameKind, Hello};
+
+const MAX_CONTROL_FRAME_SIZE: usize = 64 * 1024;
+
+// A handshaken connection to a consumer, watched by a monitor thread that exchanges heartbeats,
+// collects credits and clears `available` as soon as the peer closes or goes silent.
+pub struct Link {
+    writer: Arc<Mutex<TcpStream>>,
+    alive: Arc<AtomicBool>,
+    credits: Arc<(Mutex<Credits>, Condvar)>,
+    credit_timeout: Duration,
+}
+
+// Credits granted by the consumer, and whether a sender is waiting for some since its Demand
+#[derive(Default)]
+struct Credits {
+    available: u32,
+    waiting: bool,
+}
+
+impl Link {
+    pub fn connect(
+        addr: &str,
+        topic: &str,
+        topic_id: u16,
+        config: &ProducerConfig,
+        available: Arc<AtomicBool>,
+    ) -> Result<Self> {
+        let stream = handshake(addr, topic, topic_id, config)?;
+        let reader = stream.try_clone()?;
+        let writer = Arc::new(Mutex::new(stream));
+        let alive = Arc::new(AtomicBool::new(true));
+        let credits = Arc::new((Mutex::new(Credits::default()), Condvar::new()));
+
+        let monitor_writer = writer.clone();
+        let monitor_alive = alive.clone();
+        let monitor_credits = credits.clone();
+        let credit_timeout = config.credit_timeout;
+        let config = config.clone();
+        let addr = addr.to_string();
+
+        thread::spawn(move || {
+            if let Err(err) = monitor(
+                &reader,
+                &monitor_writer,
+                &monitor_alive,
+                &monitor_credits,
+                &config,
+            ) && monitor_alive.load(Ordering::Relaxed)
+            {
+                eprintln!("Receiver {} failed: {}", addr, err);
+            }
+            if monitor_alive.swap(false, Ordering::Relaxed) {
+                available.store(false, Ordering::Relaxed);
+            }
+            let _ = reader.shutdown(Shutdown::Both);
+            // Wake up a sender waiting for credits that will never come
+            monitor_credits.1.notify_all();
+        });
+
+        Ok(Self {
+            writer,
+            alive,
+            credits,
+            credit_timeout,
+        })
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Relaxed)
+    }
+
+    // Asks the consumer for credits when out of them and waits for one, then sends the message
+    pub fn send_message(&self, topic: u16, payload: &[u8]) -> Result<()> {
+        let (credits, granted) = &*self.credits;
+        let mut credits = credits.lock().unwrap();
+        if credits.available == 0 && self.is_alive() {
+            frame::write(&mut *self.writer.lock().unwrap(), FrameKind::Demand, 0, &[])?;
+            credits.waiting = true;
+        }
+        let (mut credits, timeout) = granted
+            .wait_timeout_while(credits, self.credit_timeout, |credits| {
+                credits.available == 0 && self.is_alive()
+            })
+            .unwrap();
+        credits.waiting = false;
+
+        if !self.is_alive() {
+            bail!("Connection closed while waiting for credits");
+        }
+        if timeout.timed_out() {
+            bail!("No credit granted within {:?}", self.credit_timeout);
+        }
+        credits.available -= 1;
+        drop(credits);
+
+        let mut writer = self.writer.lock().unwrap();
+        frame::write(&mut *writer, FrameKind::Message, topic, payload)
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        self.alive.store(false, Ordering::Relaxed);
+        if let Ok(writer) = self.writer.lock() {
+            let _ = writer.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+fn handshake(addr: &str, topic: &str, topic_id: u16, config: &ProducerConfig) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+
+    let hello = Hello {
+        topics: vec![(topic.to_string(), topic_id)],
+    };
+    frame::write(
+        &mut stream,
+        FrameKind::Hello,
+        0,
+        &postcard::to_stdvec(&hello)?,
+    )?;
+
+    stream.set_read_timeout(Some(config.handshake_timeout))?;
+    let Some(buf) = frame::read(&mut stream, MAX_CONTROL_FRAME_SIZE)? else {
+        bail!("Connection to {} closed during handshake", addr);
+    };
+    stream.set_read_timeout(None)?;
+
+    let frame = frame::decode(&buf)?;
+    match frame.kind {
+        FrameKind::Welcome => Ok(stream),
+        FrameKind::Reject => bail!(
+            "Handshake with {} rejected: {}",
+            addr,
+            String::from_utf8_lossy(frame.payload)
+        ),
+        kind => bail!("Unexpected {:?} frame during handshake with {}", kind, addr),
+    }
+}
+
+fn monitor(
+    mut reader: &TcpStream,
+    writer: &Mutex<TcpStream>,
+    alive: &AtomicBool,
+    credits: &(Mutex<Credits>, Condvar),
+    config: &ProducerConfig,
+) -> Result<()> {
+    let mut last_received = Instant::now();
+    let mut last_sent = Instant::now();
+
+    while alive.load(Ordering::Relaxed) {
+        let now = Instant::now();
+        let dead_deadline = config
+            .heartbeat_interval
+            .map(|interval| last_received + interval * config.heartbeat_misses);
+        let heartbeat_deadline = config
+            .heartbeat_interval
+            .map(|interval| last_sent + interval);
+
+        if dead_deadline.is_some_and(|deadline| now >= deadline) {
+            bail!("missed {} heartbeats", config.heartbeat_misses);
+        }
+        if heartbeat_deadline.is_some_and(|deadline| now >= deadline) {
+            frame::write(&mut *writer.lock().unwrap(), FrameKind::Heartbeat, 0, &[])?;
+            last_sent = now;
+            continue;
+        }
+
+        let wait = [dead_deadline, heartbeat_deadline]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|deadline| deadline - now);
+
+        reader.set_read_timeout(wait)?;
+        match reader.peek(&mut [0u8; 1]) {
+            Ok(0) => bail!("connection closed by peer"),
+            Ok(_) => {}
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                ) =>
+            {
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        reader.set_read_timeout(dead_deadline.map(|deadline| deadline - now))?;
+        let Some(buf) = frame::read(&mut reader, MAX_CONTROL_FRAME_SIZE)? else {
+            bail!("connection closed by peer");
+        };
+        last_received = Instant::now();
+
+        let frame = frame::decode(&buf)?;
+        match frame.kind {
+            FrameKind::Heartbeat => {}
+            FrameKind::Credit => {
+                let granted = u32::from_be_bytes(frame.payload.try_into()?);
+                credits.0.lock().unwrap().available += granted;
+                credits.1.notify_all();
+            }
+            // Gives up the credits not taken yet, messages which already took one are still sent.
+            // A sender still waiting asks again, its Demand was answered by the revoked credits.
+            FrameKind::Revoke => {
+                let mut credits = credits.0.lock().unwrap();
+                let returned = mem::take(&mut credits.available);
+                let mut writer = writer.lock().unwrap();
+                frame::write(&mut *writer, FrameKind::Return, 0, &returned.to_be_bytes())?;
+                if credits.waiting {
+                    frame::write(&mut *writer, FrameKind::Demand, 0, &[])?;
+                }
+            }
+            kind => bail!("unexpected {:?} frame from consumer", kind),
+        }
+    }
+    Ok(())
+}
@@ -1,12 +1,34 @@
+#[cfg(feature = "tokio")]
+mod async_link;
+mod builder;
+mod config;
+mod link;
+
 use postcard;
-use std::{io::Write, marker::PhantomData, net::TcpStream};
+use std::{
+    hash::{BuildHasher, RandomState},
+    marker::PhantomData,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+};
 
-use anyhow::{Result, bail};
+use anyhow::{Result, anyhow};
 use serde::Serialize;
 
 pub use pusu_producer_macro::producer;
 
-#[derive(PartialEq, Clone, Copy)]
+#[cfg(feature = "tokio")]
+use async_link::AsyncLink;
+pub use builder::{DynamicProducer, ProducerBuilder};
+pub use config::ProducerConfig;
+use link::Link;
+
+use crate::frame::Envelope;
+pub use crate::frame::Headers;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum BrokerStatus {
     AVAILABLE,
     FAILED,
@@ -15,7 +37,12 @@ pub enum BrokerStatus {
 pub struct Receiver<T> {
     id: usize,
     addr: String,
-    status: BrokerStatus,
+    config: ProducerConfig,
+    // Shared with the link's monitor thread, which clears it when the peer goes silent
+    available: Arc<AtomicBool>,
+    link: Option<Link>,
+    #[cfg(feature = "tokio")]
+    async_link: Option<AsyncLink>,
     _phantom: PhantomData<T>,
 }
 
@@ -24,42 +51,172 @@ impl<T> Clone for Receiver<T> {
         Self {
             id: self.id,
             addr: self.addr.clone(),
-            status: self.status,
+            config: self.config.clone(),
+            available: Arc::new(AtomicBool::new(self.available.load(Ordering::Relaxed))),
+            link: None,
+            #[cfg(feature = "tokio")]
+            async_link: None,
             _phantom: PhantomData,
         }
     }
 }
 
-impl<T: Serialize> Receiver<T> {
+impl<T> Receiver<T> {
     pub fn new(id: usize, addr: &str) -> Self {
+        Self::with_config(id, addr, ProducerConfig::default())
+    }
+
+    pub fn with_config(id: usize, addr: &str, config: ProducerConfig) -> Self {
         Self {
             id,
             addr: addr.to_string(),
-            status: BrokerStatus::AVAILABLE,
+            config,
+            available: Arc::new(AtomicBool::new(true)),
+            link: None,
+            #[cfg(feature = "tokio")]
+            async_link: None,
             _phantom: PhantomData,
         }
     }
 
-    pub fn send(&self, topic: &str, payload: &T) -> Result<()> {
-        if let Ok(mut stream) = TcpStream::connect(&self.addr) {
-            let mut buf = Vec::new();
-            let topic_bytes = topic.as_bytes();
+    pub fn status(&self) -> BrokerStatus {
+        if self.available.load(Ordering::Relaxed) {
+            BrokerStatus::AVAILABLE
+        } else {
+            BrokerStatus::FAILED
+        }
+    }
+
+    // Sends an already encoded payload, used for encodings other than postcard
+    pub fn send_raw(&mut self, topic: &str, topic_id: u16, payload_bytes: &[u8]) -> Result<()> {
+        let message = Envelope::encode(
+            next_message_id(),
+            producer_id(),
+            &Headers::new(),
+            payload_bytes,
+        )?;
+        self.send_message(topic, topic_id, &message)
+    }
+
+    // Sends a payload already wrapped in its envelope
+    fn send_message(&mut self, topic: &str, topic_id: u16, message: &[u8]) -> Result<()> {
+        let result = self
+            .connect(topic, topic_id)
+            .and_then(|link| link.send_message(topic_id, message));
+
+        if result.is_err() {
+            self.link = None;
+        }
+        self.record(result)
+    }
+
+    fn record(&self, result: Result<()>) -> Result<()> {
+        self.available.store(result.is_ok(), Ordering::Relaxed);
+        result
+    }
+
+    fn connect(&mut self, topic: &str, topic_id: u16) -> Result<&Link> {
+        if self.link.as_ref().is_some_and(|link| !link.is_alive()) {
+            self.link = None;
+        }
+
+        match &mut self.link {
+            Some(link) => Ok(link),
+            link => {
+                // A new connection gets a fresh status flag so a late monitor thread of the
+                // previous connection cannot flip it back to FAILED
+                self.available = Arc::new(AtomicBool::new(true));
+                Ok(link.insert(Link::connect(
+                    &self.addr,
+                    topic,
+                    topic_id,
+                    &self.config,
+                    self.available.clone(),
+                )?))
+            }
+        }
+    }
+}
+
+impl<T: Serialize> Receiver<T> {
+    pub fn send(&mut self, topic: &str, topic_id: u16, payload: &T) -> Result<()> {
+        self.send_raw(topic, topic_id, &postcard::to_stdvec(payload)?)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T> Receiver<T> {
+    pub async fn send_raw_async(
+        &mut self,
+        topic: &str,
+        topic_id: u16,
+        payload_bytes: &[u8],
+    ) -> Result<()> {
+        let message = Envelope::encode(
+            next_message_id(),
+            producer_id(),
+            &Headers::new(),
+            payload_bytes,
+        )?;
+        self.send_message_async(topic, topic_id, &message).await
+    }
+
+    async fn send_message_async(
+        &mut self,
+        topic: &str,
+        topic_id: u16,
+        message: &[u8],
+    ) -> Result<()> {
+        let result = match self.connect_async(topic, topic_id).await {
+            Ok(link) => link.send_message(topic_id, message).await,
+            Err(err) => Err(err),
+        };
 
-            buf.extend(&(topic_bytes.len() as u16).to_be_bytes());
-            buf.extend(topic_bytes);
+        if result.is_err() {
+            self.async_link = None;
+        }
+        self.record(result)
+    }
 
-            let payload_bytes = postcard::to_stdvec(payload)?;
-            buf.extend(&(payload_bytes.len() as u32).to_be_bytes());
-            buf.extend(payload_bytes);
+    async fn connect_async(&mut self, topic: &str, topic_id: u16) -> Result<&AsyncLink> {
+        if self
+            .async_link
+            .as_ref()
+            .is_some_and(|link| !link.is_alive())
+        {
+            self.async_link = None;
+        }
 
-            stream.write_all(&buf)?;
+        match &mut self.async_link {
+            Some(link) => Ok(link),
+            link => {
+                self.available = Arc::new(AtomicBool::new(true));
+                Ok(link.insert(
+                    AsyncLink::connect(
+                        &self.addr,
+                        topic,
+                        topic_id,
+                        &self.config,
+                        self.available.clone(),
+                    )
+                    .await?,
+                ))
+            }
         }
-        Ok(())
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T: Serialize> Receiver<T> {
+    pub async fn send_async(&mut self, topic: &str, topic_id: u16, payload: &T) -> Result<()> {
+        self.send_raw_async(topic, topic_id, &postcard::to_stdvec(payload)?)
+            .await
     }
 }
 
 pub struct Receivers<T> {
     i: usize,
+    config: ProducerConfig,
     receivers: Vec<Receiver<T>>,
 }
 
@@ -68,6 +225,7 @@ impl<T> Default for Receivers<T> {
     fn default() -> Self {
         Self {
             i: Default::default(),
+            config: Default::default(),
             receivers: Default::default(),
         }
     }
@@ -77,6 +235,13 @@ impl<T> Receivers<T> {
     pub fn new() -> Self {
         Self::default()
     }
+
+    pub fn with_config(config: ProducerConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
 }
 
 pub trait ReceiverDispatch<T> {
@@ -84,34 +249,150 @@ pub trait ReceiverDispatch<T> {
 }
 
 impl<T: Serialize> Receivers<T> {
-    pub fn send(&mut self, topic: &str, payload: &T) -> Result<()> {
-        let len = self.receivers.len();
-        if len == 0 {
-            bail!("No broker available")
-        }
+    pub fn send(&mut self, topic: &str, topic_id: u16, payload: &T) -> Result<()> {
+        self.send_with_headers(topic, topic_id, payload, &Headers::new())
+    }
 
-        let mut i = self.i;
+    pub fn send_with_headers(
+        &mut self,
+        topic: &str,
+        topic_id: u16,
+        payload: &T,
+        headers: &Headers,
+    ) -> Result<()> {
+        self.send_raw_with_headers(topic, topic_id, &postcard::to_stdvec(payload)?, headers)
+    }
+}
 
-        loop {
-            i += 1 % len;
-            if let Some(broker) = self.receivers.get(i)
-                && broker.status == BrokerStatus::AVAILABLE
-            {
-                broker.send(topic, payload)?;
-                self.i = i;
-                break;
-            } else if i == self.i {
-                bail!("No broker available")
+impl<T> Receivers<T> {
+    pub fn send_raw(&mut self, topic: &str, topic_id: u16, payload_bytes: &[u8]) -> Result<()> {
+        self.send_raw_with_headers(topic, topic_id, payload_bytes, &Headers::new())
+    }
+
+    // Round robin over available receivers, failed ones are only retried when none is left. The
+    // message keeps its id when it is sent to another receiver.
+    pub fn send_raw_with_headers(
+        &mut self,
+        topic: &str,
+        topic_id: u16,
+        payload_bytes: &[u8],
+        headers: &Headers,
+    ) -> Result<()> {
+        let message = Envelope::encode(next_message_id(), producer_id(), headers, payload_bytes)?;
+        let mut last_err = None;
+
+        for i in self.send_order() {
+            match self.receivers[i].send_message(topic, topic_id, &message) {
+                Ok(()) => {
+                    self.i = i;
+                    return Ok(());
+                }
+                Err(err) => last_err = Some(err),
             }
         }
-        Ok(())
+
+        Err(last_err.unwrap_or_else(|| anyhow!("No broker available")))
+    }
+
+    fn send_order(&self) -> Vec<usize> {
+        let len = self.receivers.len();
+        [BrokerStatus::AVAILABLE, BrokerStatus::FAILED]
+            .into_iter()
+            .flat_map(|status| {
+                (1..=len)
+                    .map(move |offset| (self.i + offset) % len)
+                    .filter(move |&i| self.receivers[i].status() == status)
+            })
+            .collect()
     }
 
     pub fn add_receiver(&mut self, id: usize, addr: &str) {
-        self.receivers.push(Receiver::new(id, addr));
+        self.receivers
+            .push(Receiver::with_config(id, addr, self.config.clone()));
     }
 
     pub fn remove_receiver(&mut self, id: usize) {
-        self.receivers.retain(|b| b.id == id);
+        self.receivers.retain(|b| b.id != id);
+    }
+
+    pub fn statuses(&self) -> Vec<(usize, BrokerStatus)> {
+        self.receivers
+            .iter()
+            .map(|receiver| (receiver.id, receiver.status()))
+            .collect()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T> Receivers<T> {
+    pub async fn send_raw_async(
+        &mut self,
+        topic: &str,
+        topic_id: u16,
+        payload_bytes: &[u8],
+    ) -> Result<()> {
+        self.send_raw_with_headers_async(topic, topic_id, payload_bytes, &Headers::new())
+            .await
+    }
+
+    pub async fn send_raw_with_headers_async(
+        &mut self,
+        topic: &str,
+        topic_id: u16,
+        payload_bytes: &[u8],
+        headers: &Headers,
+    ) -> Result<()> {
+        let message = Envelope::encode(next_message_id(), producer_id(), headers, payload_bytes)?;
+        let mut last_err = None;
+
+        for i in self.send_order() {
+            match self.receivers[i]
+                .send_message_async(topic, topic_id, &message)
+                .await
+            {
+                Ok(()) => {
+                    self.i = i;
+                    return Ok(());
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| anyhow!("No broker available")))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T: Serialize> Receivers<T> {
+    pub async fn send_async(&mut self, topic: &str, topic_id: u16, payload: &T) -> Result<()> {
+        self.send_with_headers_async(topic, topic_id, payload, &Headers::new())
+            .await
+    }
+
+    pub async fn send_with_headers_async(
+        &mut self,
+        topic: &str,
+        topic_id: u16,
+        payload: &T,
+        headers: &Headers,
+    ) -> Result<()> {
+        let payload_bytes = postcard::to_stdvec(payload)?;
+        self.send_raw_with_headers_async(topic, topic_id, &payload_bytes, headers)
+            .await
     }
 }
+
+// Random for each process, sent with every message so consumers can tell producers apart
+fn producer_id() -> u64 {
+    static ID: OnceLock<u64> = OnceLock::new();
+    *ID.get_or_init(|| RandomState::new().hash_one(1u8))
+}
+
+// Ids start at a random value for each process, then count up
+fn next_message_id() -> u64 {
+    static BASE: OnceLock<u64> = OnceLock::new();
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    let base = *BASE.get_or_init(|| RandomState::new().hash_one(0u8));
+    base.wrapping_add(NEXT.fetch_add(1, Ordering::Relaxed))
+}
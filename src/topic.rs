@@ -0,0 +1,91 @@
+use std::{collections::BTreeSet, sync::Mutex};
+
+// Implemented by the topic enums generated by the consumer and producer macros
+pub trait TopicEnum: Sized + Copy + Send + Sync + 'static {
+    const TOPICS: &'static [(&'static str, u16)];
+
+    fn id(&self) -> u16;
+
+    fn name(&self) -> &'static str;
+
+    fn from_id(id: u16) -> Option<Self>;
+
+    fn id_of(name: &str) -> Option<u16> {
+        Self::TOPICS
+            .iter()
+            .find(|(topic, _)| *topic == name)
+            .map(|(_, id)| *id)
+    }
+}
+
+// Stable topic id: 32 bits FNV-1a of the topic name folded to 16 bits.
+// Used as the enum discriminant, so two colliding topics fail to compile.
+pub const fn id(name: &str) -> u16 {
+    let bytes = name.as_bytes();
+    let mut hash: u32 = 0x811c9dc5;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u32;
+        hash = hash.wrapping_mul(0x01000193);
+        i += 1;
+    }
+    ((hash >> 16) ^ (hash & 0xffff)) as u16
+}
+
+// Topic registered at runtime with ConsumerBuilder. Its name is interned, so it can be handed out
+// as &'static str like the topics of generated enums.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DynamicTopic {
+    id: u16,
+    name: &'static str,
+}
+
+// Names of the runtime topics, each one leaked once however many times it is registered
+static NAMES: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+
+impl DynamicTopic {
+    pub fn new(name: &str) -> Self {
+        let mut names = NAMES.lock().unwrap();
+        let name = match names.get(name) {
+            Some(name) => *name,
+            None => {
+                let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+                names.insert(name);
+                name
+            }
+        };
+        Self { id: id(name), name }
+    }
+}
+
+// Runtime topics are only known by their consumer, which looks them up through Consumer::topic
+// and Consumer::topic_id instead of TOPICS and from_id
+impl TopicEnum for DynamicTopic {
+    const TOPICS: &'static [(&'static str, u16)] = &[];
+
+    fn id(&self) -> u16 {
+        self.id
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn from_id(_id: u16) -> Option<Self> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runtime_topic_names_are_interned() {
+        let first = DynamicTopic::new("interned");
+        let second = DynamicTopic::new(&String::from("interned"));
+        assert_eq!(first, second);
+        assert!(std::ptr::eq(first.name(), second.name()));
+        assert_eq!(first.id(), id("interned"));
+    }
+}
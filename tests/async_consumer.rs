@@ -0,0 +1,141 @@
+#![cfg(feature = "tokio")]
+
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use pusu::{
+    consumer::{AsyncConsumer, ConsumerConfig, DrainSummary, MessageContext, consumer},
+    producer::producer,
+    topic::TopicEnum,
+};
+use tokio::{net::TcpListener, sync::oneshot, task::JoinHandle, time};
+
+// An AsyncConsumer served on a free port of the test runtime until shutdown
+struct Serving {
+    addr: String,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<Result<DrainSummary>>,
+}
+
+impl Serving {
+    async fn start<C: AsyncConsumer<T>, T: TopicEnum>(consumer: C) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        let (stop, stopped) = oneshot::channel();
+        let config = ConsumerConfig {
+            handle_signals: false,
+            ..Default::default()
+        };
+        let task = tokio::spawn(consumer.serve(listener, config, async {
+            let _ = stopped.await;
+        }));
+        Ok(Self { addr, stop, task })
+    }
+
+    async fn shutdown(self) -> Result<DrainSummary> {
+        let _ = self.stop.send(());
+        self.task.await?
+    }
+}
+
+async fn wait_for(count: impl Fn() -> usize, expected: usize) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while count() < expected && Instant::now() < deadline {
+        time::sleep(Duration::from_millis(5)).await;
+    }
+}
+
+static ORDERS: Mutex<Vec<(u32, u32)>> = Mutex::new(Vec::new());
+
+// Odd orders fail on their first attempt
+async fn order(order: u32, context: &MessageContext) -> Result<()> {
+    time::sleep(Duration::from_millis(1)).await;
+    if order % 2 == 1 && context.attempt == 1 {
+        bail!("order {} failed", order);
+    }
+    ORDERS.lock().unwrap().push((order, context.attempt));
+    Ok(())
+}
+
+#[consumer(async)]
+struct Shop {
+    #[topic("order")]
+    #[retry(max = 2, backoff = "fixed", base_ms = 10)]
+    orders: u32,
+}
+
+#[producer(async)]
+struct ShopProducer {
+    orders: u32,
+}
+
+#[tokio::test]
+async fn async_handlers_are_retried() -> Result<()> {
+    let serving = Serving::start(Shop {}).await?;
+    let mut producer = ShopProducer::new();
+    producer.orders.add_receiver(1, &serving.addr);
+    for i in 0..10 {
+        producer.produce_orders(i).await?;
+    }
+    wait_for(|| ORDERS.lock().unwrap().len(), 10).await;
+
+    assert_eq!(serving.shutdown().await?, DrainSummary::default());
+    let mut orders = ORDERS.lock().unwrap().clone();
+    orders.sort();
+    let expected = (0..10)
+        .map(|order| (order, if order % 2 == 1 { 2 } else { 1 }))
+        .collect::<Vec<_>>();
+    assert_eq!(orders, expected);
+    Ok(())
+}
+
+static STARTED: AtomicUsize = AtomicUsize::new(0);
+static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+async fn slow(_: u32) {
+    STARTED.fetch_add(1, Ordering::SeqCst);
+    time::sleep(Duration::from_millis(50)).await;
+    FINISHED.fetch_add(1, Ordering::SeqCst);
+}
+
+#[consumer(async)]
+struct Slow {
+    #[topic("slow")]
+    jobs: u32,
+}
+
+#[producer(async)]
+struct SlowProducer {
+    jobs: u32,
+}
+
+#[tokio::test]
+async fn queued_messages_are_handled_before_shutting_down() -> Result<()> {
+    let serving = Serving::start(Slow {}).await?;
+    let mut producer = SlowProducer::new();
+    producer.jobs.add_receiver(1, &serving.addr);
+    for i in 0..5 {
+        producer.produce_jobs(i).await?;
+    }
+    wait_for(|| STARTED.load(Ordering::SeqCst), 1).await;
+    // Leaves the connection time to queue the messages sent after the first one
+    time::sleep(Duration::from_millis(20)).await;
+
+    let summary = serving.shutdown().await?;
+    assert_eq!(FINISHED.load(Ordering::SeqCst), 5);
+    assert_eq!(
+        summary,
+        DrainSummary {
+            completed: 5,
+            nacked: 0,
+            unfinished: 0,
+        }
+    );
+    Ok(())
+}
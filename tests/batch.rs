@@ -0,0 +1,92 @@
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use pusu::{
+    consumer::{Consumer, ConsumerConfig, ConsumerHandle, consumer},
+    producer::ProducerBuilder,
+};
+
+// Batches handled by each consumer with the time they were handed over
+static QUICK: Mutex<Vec<(Vec<u32>, Instant)>> = Mutex::new(Vec::new());
+static SLOW: Mutex<Vec<Vec<u32>>> = Mutex::new(Vec::new());
+
+fn quick(jobs: Vec<u32>) {
+    QUICK.lock().unwrap().push((jobs, Instant::now()));
+}
+
+fn slow(jobs: Vec<u32>) {
+    SLOW.lock().unwrap().push(jobs);
+}
+
+#[consumer]
+struct Quick {
+    #[topic("quick", batch(max = 4, linger_ms = 100))]
+    quick: u32,
+}
+
+#[consumer]
+struct Slow {
+    #[topic("slow", batch(max = 100, linger_ms = 60_000))]
+    slow: u32,
+}
+
+fn config() -> ConsumerConfig {
+    ConsumerConfig {
+        workers: 1,
+        handle_signals: false,
+        ..Default::default()
+    }
+}
+
+fn send(handle: &ConsumerHandle, topic: &str, count: u32) -> Result<()> {
+    let mut producer = ProducerBuilder::new()
+        .receiver(topic, 1, &handle.local_addr().to_string())
+        .build()?;
+    for job in 0..count {
+        producer.send(topic, &job)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn full_batches_are_handed_over_at_once_and_the_rest_after_the_linger_time() -> Result<()> {
+    let handle = Quick {}.spawn(config())?;
+    let sent = Instant::now();
+    send(&handle, "quick", 10)?;
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while QUICK.lock().unwrap().len() < 3 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    handle.shutdown();
+    handle.join()?;
+
+    let batches = QUICK.lock().unwrap();
+    let jobs = batches
+        .iter()
+        .map(|(jobs, _)| jobs.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(jobs, [vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![8, 9]]);
+    assert!(batches[1].1 < sent + Duration::from_millis(100));
+    assert!(batches[2].1 >= sent + Duration::from_millis(100));
+    Ok(())
+}
+
+#[test]
+fn gathered_batches_are_handed_over_on_shutdown() -> Result<()> {
+    let handle = Slow {}.spawn(config())?;
+    send(&handle, "slow", 3)?;
+    // Leaves the reactor time to queue the messages
+    thread::sleep(Duration::from_millis(50));
+    assert!(SLOW.lock().unwrap().is_empty());
+
+    handle.shutdown();
+    let summary = handle.join()?;
+    assert_eq!(*SLOW.lock().unwrap(), [vec![0, 1, 2]]);
+    assert_eq!((summary.completed, summary.nacked), (3, 0));
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use pusu::{
+    consumer::{Consumer, ConsumerBuilder, ConsumerConfig, ConsumerHandle},
+    producer::{DynamicProducer, ProducerBuilder, ProducerConfig},
+};
+
+// More connections than a worker has room for at a full credit window each
+const TOPICS: usize = 8;
+
+fn spawn(received: &Arc<AtomicUsize>, config: ConsumerConfig) -> Result<ConsumerHandle> {
+    let mut builder = ConsumerBuilder::new();
+    for i in 0..TOPICS {
+        let received = received.clone();
+        builder = builder.topic(&format!("t{}", i), move |_: u32| {
+            received.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    builder.build()?.spawn(ConsumerConfig {
+        workers: 1,
+        handle_signals: false,
+        ..config
+    })
+}
+
+fn producer(handle: &ConsumerHandle, topic: usize) -> Result<DynamicProducer> {
+    ProducerBuilder::new()
+        .config(ProducerConfig {
+            credit_timeout: Duration::from_secs(3),
+            ..Default::default()
+        })
+        .receiver(&format!("t{}", topic), 1, &handle.local_addr().to_string())
+        .build()
+}
+
+fn wait_for(received: &AtomicUsize, count: usize) -> usize {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while received.load(Ordering::SeqCst) < count && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    received.load(Ordering::SeqCst)
+}
+
+#[test]
+fn idle_connections_leave_credits_to_new_ones() -> Result<()> {
+    let received = Arc::new(AtomicUsize::new(0));
+    // Without idle revocation, only the rebalancing of a starved worker frees credits
+    let handle = spawn(
+        &received,
+        ConsumerConfig {
+            credit_idle_timeout: None,
+            ..Default::default()
+        },
+    )?;
+
+    // Producers stay connected, holding the credits they didn't use
+    let mut producers = Vec::new();
+    for topic in 0..TOPICS {
+        let mut producer = producer(&handle, topic)?;
+        producer.send(&format!("t{}", topic), &(topic as u32))?;
+        producers.push(producer);
+    }
+    assert_eq!(wait_for(&received, TOPICS), TOPICS);
+
+    handle.shutdown();
+    handle.join()?;
+    Ok(())
+}
+
+#[test]
+fn idle_credits_are_revoked() -> Result<()> {
+    let received = Arc::new(AtomicUsize::new(0));
+    let handle = spawn(
+        &received,
+        ConsumerConfig {
+            credit_idle_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        },
+    )?;
+
+    let mut first = producer(&handle, 0)?;
+    first.send("t0", &0u32)?;
+    assert_eq!(wait_for(&received, 1), 1);
+    // Once revoked, the next message asks for credits again
+    thread::sleep(Duration::from_millis(200));
+    first.send("t0", &1u32)?;
+    assert_eq!(wait_for(&received, 2), 2);
+
+    handle.shutdown();
+    handle.join()?;
+    Ok(())
+}
+
+#[test]
+fn busy_connections_share_a_worker() -> Result<()> {
+    const MESSAGES: usize = 500;
+    let received = Arc::new(AtomicUsize::new(0));
+    let handle = spawn(&received, ConsumerConfig::default())?;
+
+    thread::scope(|scope| {
+        let senders = (0..TOPICS)
+            .map(|topic| {
+                let mut producer = producer(&handle, topic)?;
+                Ok(scope.spawn(move || -> Result<()> {
+                    for i in 0..MESSAGES {
+                        producer.send(&format!("t{}", topic), &(i as u32))?;
+                    }
+                    Ok(())
+                }))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        senders
+            .into_iter()
+            .try_for_each(|sender| sender.join().unwrap())
+    })?;
+    assert_eq!(wait_for(&received, TOPICS * MESSAGES), TOPICS * MESSAGES);
+
+    handle.shutdown();
+    handle.join()?;
+    Ok(())
+}
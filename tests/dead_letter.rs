@@ -0,0 +1,129 @@
+use std::{
+    net::TcpStream,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use pusu::consumer::{
+    Backoff, Consumer, ConsumerBuilder, ConsumerConfig, MessageContext, RetryPolicy,
+};
+use pusu::producer::ProducerBuilder;
+use pusu::{
+    frame::{self, FrameKind, Hello},
+    topic,
+};
+
+#[test]
+fn messages_out_of_retries_are_dead_lettered() -> Result<()> {
+    let dead = Arc::new(Mutex::new(Vec::new()));
+    let dead_letters = dead.clone();
+    let handle = ConsumerBuilder::new()
+        .topic("orders", |order: u32| -> Result<()> {
+            bail!("order {} rejected", order)
+        })
+        .retry(
+            "orders",
+            RetryPolicy {
+                max_retries: 2,
+                backoff: Backoff::Fixed,
+                base: Duration::from_millis(1),
+            },
+        )
+        .dead_letter(
+            move |topic: &str, payload: &[u8], context: &MessageContext, err: &anyhow::Error| {
+                let order = postcard::from_bytes::<u32>(payload).unwrap();
+                dead_letters.lock().unwrap().push((
+                    topic.to_string(),
+                    order,
+                    context.attempt,
+                    err.to_string(),
+                ));
+            },
+        )
+        .build()?
+        .spawn(ConsumerConfig {
+            workers: 1,
+            handle_signals: false,
+            ..Default::default()
+        })?;
+
+    let mut producer = ProducerBuilder::new()
+        .receiver("orders", 1, &handle.local_addr().to_string())
+        .build()?;
+    producer.send("orders", &7u32)?;
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while dead.lock().unwrap().is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(
+        *dead.lock().unwrap(),
+        [("orders".to_string(), 7, 3, "order 7 rejected".to_string())]
+    );
+
+    handle.shutdown();
+    handle.join()?;
+    Ok(())
+}
+
+#[test]
+fn messages_with_a_broken_envelope_are_dead_lettered_raw() -> Result<()> {
+    let dead = Arc::new(Mutex::new(Vec::new()));
+    let dead_letters = dead.clone();
+    let handle = ConsumerBuilder::new()
+        .topic("orders", |_: u32| {})
+        .dead_letter(
+            move |_: &str, payload: &[u8], context: &MessageContext, err: &anyhow::Error| {
+                dead_letters.lock().unwrap().push((
+                    payload.to_vec(),
+                    context.id,
+                    context.headers.len(),
+                    err.to_string(),
+                ));
+            },
+        )
+        .build()?
+        .spawn(ConsumerConfig {
+            workers: 1,
+            handle_signals: false,
+            ..Default::default()
+        })?;
+
+    // A producer sending a payload too short for an envelope
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+    let hello = Hello {
+        topics: vec![("orders".to_string(), topic::id("orders"))],
+    };
+    frame::write(
+        &mut stream,
+        FrameKind::Hello,
+        0,
+        &postcard::to_stdvec(&hello)?,
+    )?;
+    let welcome = frame::read(&mut stream, 1024)?.unwrap();
+    assert_eq!(frame::decode(&welcome)?.kind, FrameKind::Welcome);
+    frame::write(&mut stream, FrameKind::Demand, 0, &[])?;
+    while frame::decode(&frame::read(&mut stream, 1024)?.unwrap())?.kind != FrameKind::Credit {}
+    frame::write(
+        &mut stream,
+        FrameKind::Message,
+        topic::id("orders"),
+        b"broken",
+    )?;
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while dead.lock().unwrap().is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    let dead = dead.lock().unwrap();
+    assert_eq!(dead.len(), 1);
+    let (payload, id, headers, err) = &dead[0];
+    assert_eq!((payload.as_slice(), *id, *headers), (&b"broken"[..], 0, 0));
+    assert!(err.starts_with("Message too small"), "{}", err);
+
+    handle.shutdown();
+    handle.join()?;
+    Ok(())
+}
@@ -0,0 +1,246 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use pusu::{
+    consumer::{
+        Backoff, Consumer, ConsumerBuilder, ConsumerConfig, ConsumerHandle, DrainSummary,
+        MessageContext, RetryPolicy,
+    },
+    producer::ProducerBuilder,
+};
+
+// Handlers that take `delay` for each message, on a single worker
+fn spawn(
+    started: &Arc<AtomicUsize>,
+    delay: Duration,
+    drain_timeout: Duration,
+) -> Result<ConsumerHandle> {
+    let started = started.clone();
+    ConsumerBuilder::new()
+        .topic("jobs", move |_: u32| {
+            started.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(delay);
+        })
+        .build()?
+        .spawn(ConsumerConfig {
+            workers: 1,
+            handle_signals: false,
+            drain_timeout: Some(drain_timeout),
+            ..Default::default()
+        })
+}
+
+fn send(handle: &ConsumerHandle, count: u32) -> Result<()> {
+    let mut producer = ProducerBuilder::new()
+        .receiver("jobs", 1, &handle.local_addr().to_string())
+        .build()?;
+    for i in 0..count {
+        producer.send("jobs", &i)?;
+    }
+    Ok(())
+}
+
+fn wait_for(counter: &AtomicUsize, count: usize) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while counter.load(Ordering::SeqCst) < count && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    // Leaves the reactor time to queue the messages sent after it
+    thread::sleep(Duration::from_millis(20));
+}
+
+#[test]
+fn queued_messages_are_completed_before_the_deadline() -> Result<()> {
+    let started = Arc::new(AtomicUsize::new(0));
+    let handle = spawn(&started, Duration::from_millis(50), Duration::from_secs(5))?;
+    send(&handle, 3)?;
+    wait_for(&started, 1);
+
+    handle.shutdown();
+    let summary = handle.join()?;
+    assert_eq!(
+        summary,
+        DrainSummary {
+            completed: 3,
+            nacked: 0,
+            unfinished: 0,
+        }
+    );
+    assert_eq!(started.load(Ordering::SeqCst), 3);
+    Ok(())
+}
+
+#[test]
+fn workers_still_running_at_the_deadline_are_left_unfinished() -> Result<()> {
+    let started = Arc::new(AtomicUsize::new(0));
+    let handle = spawn(
+        &started,
+        Duration::from_millis(500),
+        Duration::from_millis(50),
+    )?;
+    send(&handle, 3)?;
+    wait_for(&started, 1);
+
+    let stopping = Instant::now();
+    handle.shutdown();
+    let summary = handle.join()?;
+    assert!(stopping.elapsed() < Duration::from_millis(400));
+    assert_eq!(
+        summary,
+        DrainSummary {
+            completed: 0,
+            nacked: 0,
+            unfinished: 3,
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn retries_left_at_the_deadline_are_nacked() -> Result<()> {
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let dead = Arc::new(Mutex::new(Vec::new()));
+    let dead_letters = dead.clone();
+    let handle = ConsumerBuilder::new()
+        .topic("jobs", {
+            let attempts = attempts.clone();
+            move |job: u32| -> Result<()> {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                if job == 0 {
+                    bail!("job {} failed", job);
+                }
+                Ok(())
+            }
+        })
+        .retry(
+            "jobs",
+            RetryPolicy {
+                max_retries: 3,
+                backoff: Backoff::Fixed,
+                base: Duration::from_secs(10),
+            },
+        )
+        .dead_letter(
+            move |_: &str, payload: &[u8], context: &MessageContext, err: &anyhow::Error| {
+                let job = postcard::from_bytes::<u32>(payload).unwrap();
+                dead_letters
+                    .lock()
+                    .unwrap()
+                    .push((job, context.attempt, err.to_string()));
+            },
+        )
+        .build()?
+        .spawn(ConsumerConfig {
+            workers: 1,
+            handle_signals: false,
+            drain_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        })?;
+    send(&handle, 3)?;
+    wait_for(&attempts, 3);
+
+    handle.shutdown();
+    let summary = handle.join()?;
+    // The other two were acked before the shutdown
+    assert_eq!(
+        summary,
+        DrainSummary {
+            completed: 0,
+            nacked: 1,
+            unfinished: 0,
+        }
+    );
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    // Waiting for its second attempt, without having run out of retries
+    assert_eq!(
+        *dead.lock().unwrap(),
+        [(0, 2, "Not processed by the drain deadline".to_string())]
+    );
+    Ok(())
+}
+
+#[cfg(feature = "tokio")]
+mod async_consumer {
+    use std::sync::atomic::AtomicU32;
+
+    use pusu::consumer::{AsyncConsumer, consumer};
+    use tokio::{net::TcpListener, sync::oneshot, time};
+
+    use super::*;
+
+    static ATTEMPTS: AtomicU32 = AtomicU32::new(0);
+    static DEAD: Mutex<Vec<(u32, u32, String)>> = Mutex::new(Vec::new());
+
+    async fn failing(job: u32) -> Result<()> {
+        ATTEMPTS.fetch_add(1, Ordering::SeqCst);
+        if job == 0 {
+            bail!("job {} failed", job);
+        }
+        Ok(())
+    }
+
+    async fn dead_letter(_: &str, payload: &[u8], context: &MessageContext, err: &anyhow::Error) {
+        let job = postcard::from_bytes::<u32>(payload).unwrap();
+        DEAD.lock()
+            .unwrap()
+            .push((job, context.attempt, err.to_string()));
+    }
+
+    #[consumer(async)]
+    #[dead_letter("dead_letter")]
+    struct Failing {
+        #[topic("failing")]
+        #[retry(max = 3, backoff = "fixed", base_ms = 10000)]
+        jobs: u32,
+    }
+
+    #[tokio::test]
+    async fn retries_left_at_the_deadline_are_nacked() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?.to_string();
+        let (stop, stopped) = oneshot::channel();
+        let config = ConsumerConfig {
+            handle_signals: false,
+            drain_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let serving = tokio::spawn(Failing {}.serve(listener, config, async {
+            let _ = stopped.await;
+        }));
+
+        let mut producer = ProducerBuilder::new().receiver("jobs", 1, &addr).build()?;
+        for i in 0..3u32 {
+            producer.send_async("jobs", &i).await?;
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while ATTEMPTS.load(Ordering::SeqCst) < 3 && Instant::now() < deadline {
+            time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let stopping = Instant::now();
+        let _ = stop.send(());
+        let summary = serving.await??;
+        // The retry is not waited for past the deadline
+        assert!(stopping.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            summary,
+            DrainSummary {
+                completed: 0,
+                nacked: 1,
+                unfinished: 0,
+            }
+        );
+        assert_eq!(
+            *DEAD.lock().unwrap(),
+            [(0, 2, "Not processed by the drain deadline".to_string())]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use pusu::{
+    consumer::{
+        Backoff, Consumer, ConsumerBuilder, ConsumerConfig, ConsumerHandle, MessageContext,
+        RetryPolicy, consumer,
+    },
+    producer::ProducerBuilder,
+};
+
+fn config() -> ConsumerConfig {
+    ConsumerConfig {
+        workers: 1,
+        handle_signals: false,
+        ..Default::default()
+    }
+}
+
+// What reached each handler of a consumer
+#[derive(Default)]
+struct Seen {
+    handled: Mutex<Vec<u32>>,
+    fallback: Mutex<Vec<(String, Vec<u8>)>>,
+    dead: Mutex<Vec<String>>,
+}
+
+impl Seen {
+    const fn new() -> Self {
+        Self {
+            handled: Mutex::new(Vec::new()),
+            fallback: Mutex::new(Vec::new()),
+            dead: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn handle(&self, job: u32) -> Result<()> {
+        if job == 0 {
+            bail!("job {} failed", job);
+        }
+        self.handled.lock().unwrap().push(job);
+        Ok(())
+    }
+
+    fn fall_back(&self, topic: &str, payload: &[u8]) {
+        self.fallback
+            .lock()
+            .unwrap()
+            .push((topic.to_string(), payload.to_vec()));
+    }
+
+    fn dead_letter(&self, topic: &str) {
+        self.dead.lock().unwrap().push(topic.to_string());
+    }
+}
+
+// Sends a message of a topic the consumer doesn't know, one that doesn't decode, one that fails
+// and one that is handled
+fn check_fallback(handle: &ConsumerHandle, seen: &Seen) -> Result<()> {
+    let addr = handle.local_addr().to_string();
+    let mut producer = ProducerBuilder::new()
+        .receiver("jobs", 1, &addr)
+        .receiver("orders", 1, &addr)
+        .build()?;
+    producer.send("orders", &7u32)?;
+    producer.send_raw("jobs", &[])?;
+    producer.send("jobs", &0u32)?;
+    producer.send("jobs", &5u32)?;
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let is_done = || {
+        seen.fallback.lock().unwrap().len() == 2
+            && seen.dead.lock().unwrap().len() == 1
+            && seen.handled.lock().unwrap().len() == 1
+    };
+    while !is_done() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    // Gives a nacked fallback message time to show up at the dead letter handler
+    thread::sleep(Duration::from_millis(50));
+
+    let mut fallback = seen.fallback.lock().unwrap().clone();
+    fallback.sort();
+    assert_eq!(
+        fallback,
+        [
+            ("jobs".to_string(), Vec::new()),
+            ("orders".to_string(), vec![7])
+        ]
+    );
+    // Only the message whose handler failed is nacked
+    assert_eq!(*seen.dead.lock().unwrap(), ["jobs"]);
+    assert_eq!(*seen.handled.lock().unwrap(), [5]);
+    Ok(())
+}
+
+static SEEN: Seen = Seen::new();
+
+fn job(job: u32) -> Result<()> {
+    SEEN.handle(job)
+}
+
+fn fallback(topic: &str, payload: &[u8]) {
+    SEEN.fall_back(topic, payload);
+}
+
+fn dead_letter(topic: &str, _: &[u8], _: &MessageContext, _: &anyhow::Error) {
+    SEEN.dead_letter(topic);
+}
+
+#[consumer]
+#[fallback("fallback")]
+#[dead_letter("dead_letter")]
+struct Jobs {
+    #[topic("job")]
+    #[retry(max = 1, backoff = "fixed", base_ms = 1)]
+    jobs: u32,
+}
+
+#[test]
+fn unknown_and_undecodable_messages_reach_the_fallback() -> Result<()> {
+    let handle = Jobs {}.spawn(config())?;
+    check_fallback(&handle, &SEEN)?;
+
+    handle.shutdown();
+    handle.join()?;
+    Ok(())
+}
+
+#[test]
+fn builder_fallbacks_take_unknown_and_undecodable_messages() -> Result<()> {
+    let seen = Arc::new(Seen::default());
+    let handle = ConsumerBuilder::new()
+        .topic("jobs", {
+            let seen = seen.clone();
+            move |job: u32| seen.handle(job)
+        })
+        .retry(
+            "jobs",
+            RetryPolicy {
+                max_retries: 1,
+                backoff: Backoff::Fixed,
+                base: Duration::from_millis(1),
+            },
+        )
+        .fallback({
+            let seen = seen.clone();
+            move |topic: &str, payload: &[u8]| seen.fall_back(topic, payload)
+        })
+        .dead_letter({
+            let seen = seen.clone();
+            move |topic: &str, _: &[u8], _: &MessageContext, _: &anyhow::Error| {
+                seen.dead_letter(topic)
+            }
+        })
+        .build()?
+        .spawn(config())?;
+    check_fallback(&handle, &seen)?;
+
+    handle.shutdown();
+    handle.join()?;
+    Ok(())
+}
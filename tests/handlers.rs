@@ -0,0 +1,186 @@
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, bail};
+use pusu::{
+    consumer::{Consumer, ConsumerConfig, ConsumerHandle, MessageContext, consumer},
+    producer::ProducerBuilder,
+};
+
+fn config() -> ConsumerConfig {
+    ConsumerConfig {
+        workers: 1,
+        handle_signals: false,
+        ..Default::default()
+    }
+}
+
+fn send(handle: &ConsumerHandle, topic: &str, jobs: impl IntoIterator<Item = u32>) -> Result<()> {
+    let mut producer = ProducerBuilder::new()
+        .receiver(topic, 1, &handle.local_addr().to_string())
+        .build()?;
+    for job in jobs {
+        producer.send(topic, &job)?;
+    }
+    Ok(())
+}
+
+fn wait_for<V>(values: &Mutex<Vec<V>>, count: usize) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while values.lock().unwrap().len() < count && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+// Handler name, job and attempt of every call
+static CALLS: Mutex<Vec<(&str, u32, u32)>> = Mutex::new(Vec::new());
+static DEAD: Mutex<Vec<(u32, String)>> = Mutex::new(Vec::new());
+
+fn audit(job: u32, context: &MessageContext) {
+    CALLS.lock().unwrap().push(("audit", job, context.attempt));
+}
+
+// Fails job 1 once and job 2 every time
+fn index(job: u32, context: &MessageContext) -> Result<()> {
+    CALLS.lock().unwrap().push(("index", job, context.attempt));
+    if job == 2 || (job == 1 && context.attempt == 1) {
+        bail!("index failed for {}", job);
+    }
+    Ok(())
+}
+
+fn notify(job: u32, context: &MessageContext) {
+    CALLS.lock().unwrap().push(("notify", job, context.attempt));
+}
+
+fn dead_letter(_: &str, payload: &[u8], _: &MessageContext, err: &anyhow::Error) {
+    let job = postcard::from_bytes::<u32>(payload).unwrap();
+    DEAD.lock().unwrap().push((job, err.to_string()));
+}
+
+#[consumer]
+#[dead_letter("dead_letter")]
+struct Sequential {
+    #[topic("audit", "index")]
+    #[topic("notify")]
+    #[retry(max = 1, backoff = "fixed", base_ms = 10)]
+    users: u32,
+}
+
+#[test]
+fn handlers_run_in_turn_and_are_retried_together() -> Result<()> {
+    let handle = Sequential {}.spawn(config())?;
+    send(&handle, "users", 0..3)?;
+    wait_for(&DEAD, 1);
+    wait_for(&CALLS, 15);
+
+    handle.shutdown();
+    handle.join()?;
+    let calls = CALLS.lock().unwrap().clone();
+    let calls_of = |job| {
+        calls
+            .iter()
+            .filter(|(_, called, _)| *called == job)
+            .map(|(handler, _, attempt)| (*handler, *attempt))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(calls_of(0), [("audit", 1), ("index", 1), ("notify", 1)]);
+    // The others run again with the one that failed
+    let twice = [
+        ("audit", 1),
+        ("index", 1),
+        ("notify", 1),
+        ("audit", 2),
+        ("index", 2),
+        ("notify", 2),
+    ];
+    assert_eq!(calls_of(1), twice);
+    assert_eq!(calls_of(2), twice);
+    // A single failing handler nacks the message with its own error
+    assert_eq!(
+        *DEAD.lock().unwrap(),
+        [(2, "index failed for 2".to_string())]
+    );
+    Ok(())
+}
+
+static PARALLEL_CALLS: Mutex<Vec<(&str, u32, u32)>> = Mutex::new(Vec::new());
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+static OVERLAPPED: AtomicUsize = AtomicUsize::new(0);
+
+// Waits a while for the other handler of the message to be running too, which only resets the
+// count once both recorded their call
+fn meet() {
+    RUNNING.fetch_add(1, Ordering::SeqCst);
+    let deadline = Instant::now() + Duration::from_millis(500);
+    while Instant::now() < deadline {
+        if RUNNING.load(Ordering::SeqCst) >= 2 {
+            OVERLAPPED.fetch_add(1, Ordering::SeqCst);
+            return;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn resize(job: u32, context: &MessageContext) {
+    meet();
+    let mut calls = PARALLEL_CALLS.lock().unwrap();
+    calls.push(("resize", job, context.attempt));
+    if calls.len().is_multiple_of(2) {
+        RUNNING.store(0, Ordering::SeqCst);
+    }
+}
+
+// Fails the first attempt of job 1
+fn upload(job: u32, context: &MessageContext) -> Result<()> {
+    meet();
+    let mut calls = PARALLEL_CALLS.lock().unwrap();
+    calls.push(("upload", job, context.attempt));
+    if calls.len().is_multiple_of(2) {
+        RUNNING.store(0, Ordering::SeqCst);
+    }
+    if job == 1 && context.attempt == 1 {
+        bail!("upload failed for {}", job);
+    }
+    Ok(())
+}
+
+#[consumer]
+struct Parallel {
+    #[parallel]
+    #[topic("resize", "upload")]
+    #[retry(max = 1, backoff = "fixed", base_ms = 10)]
+    images: u32,
+}
+
+#[test]
+fn parallel_handlers_run_at_the_same_time_and_are_retried_together() -> Result<()> {
+    let handle = Parallel {}.spawn(config())?;
+    send(&handle, "images", 0..2)?;
+    wait_for(&PARALLEL_CALLS, 6);
+
+    handle.shutdown();
+    handle.join()?;
+    let mut calls = PARALLEL_CALLS.lock().unwrap().clone();
+    calls.sort();
+    assert_eq!(
+        calls,
+        [
+            ("resize", 0, 1),
+            ("resize", 1, 1),
+            ("resize", 1, 2),
+            ("upload", 0, 1),
+            ("upload", 1, 1),
+            ("upload", 1, 2),
+        ]
+    );
+    // Both handlers of each of the three dispatches saw the other one running
+    assert_eq!(OVERLAPPED.load(Ordering::SeqCst), 6);
+    Ok(())
+}
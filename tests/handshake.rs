@@ -0,0 +1,58 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use pusu::{
+    consumer::{Consumer, ConsumerBuilder, ConsumerConfig},
+    producer::ProducerBuilder,
+};
+
+#[test]
+fn producers_of_unknown_topics_are_rejected() -> Result<()> {
+    let received = Arc::new(AtomicU32::new(0));
+    let handle = ConsumerBuilder::new()
+        .topic("users", {
+            let received = received.clone();
+            move |id: u32| {
+                received.store(id, Ordering::SeqCst);
+            }
+        })
+        .build()?
+        .spawn(ConsumerConfig {
+            handle_signals: false,
+            ..Default::default()
+        })?;
+    let addr = handle.local_addr().to_string();
+
+    let mut producer = ProducerBuilder::new()
+        .receiver("orders", 1, &addr)
+        .build()?;
+    let err = producer.send("orders", &7u32).err().unwrap();
+    assert!(
+        format!("{:#}", err).contains(&format!(
+            "Handshake with {} rejected: Unknown topic orders",
+            addr
+        )),
+        "{:#}",
+        err
+    );
+
+    // The consumer keeps serving the topics it knows
+    let mut producer = ProducerBuilder::new().receiver("users", 1, &addr).build()?;
+    producer.send("users", &7u32)?;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while received.load(Ordering::SeqCst) != 7 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(received.load(Ordering::SeqCst), 7);
+
+    handle.shutdown();
+    handle.join()?;
+    Ok(())
+}
@@ -0,0 +1,96 @@
+use std::{
+    net::TcpListener,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use pusu::{
+    consumer::{Consumer, ConsumerBuilder, ConsumerConfig},
+    frame::{self, FrameKind},
+    producer::{BrokerStatus, DynamicProducer, ProducerBuilder, ProducerConfig},
+};
+
+fn producer(addr: &str) -> Result<DynamicProducer> {
+    ProducerBuilder::new()
+        .config(ProducerConfig {
+            heartbeat_interval: Some(Duration::from_millis(50)),
+            heartbeat_misses: 4,
+            ..Default::default()
+        })
+        .receiver("jobs", 1, addr)
+        .build()
+}
+
+// Waits for the receiver to leave `status`, returns the one it has then
+fn wait_while(producer: &DynamicProducer, status: BrokerStatus, timeout: Duration) -> BrokerStatus {
+    let deadline = Instant::now() + timeout;
+    let current = || producer.statuses("jobs").unwrap()[0].1;
+    while current() == status && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    current()
+}
+
+// Welcomes one producer and grants it credits, then never writes again while keeping the
+// connection open until the producer closes it
+fn silent_peer() -> Result<(String, JoinHandle<()>)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let peer = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let hello = frame::read(&mut stream, 1024).unwrap().unwrap();
+        assert_eq!(frame::decode(&hello).unwrap().kind, FrameKind::Hello);
+        frame::write(&mut stream, FrameKind::Welcome, 0, &[]).unwrap();
+        frame::write(&mut stream, FrameKind::Credit, 0, &16u32.to_be_bytes()).unwrap();
+        while let Ok(Some(_)) = frame::read(&mut stream, 1024) {}
+    });
+    Ok((addr, peer))
+}
+
+#[test]
+fn receivers_fail_once_their_peer_goes_silent() -> Result<()> {
+    let (addr, peer) = silent_peer()?;
+    let mut producer = producer(&addr)?;
+    producer.send("jobs", &1u32)?;
+    let sent = Instant::now();
+    assert_eq!(
+        producer.statuses("jobs"),
+        Some(vec![(1, BrokerStatus::AVAILABLE)])
+    );
+
+    let status = wait_while(&producer, BrokerStatus::AVAILABLE, Duration::from_secs(5));
+    assert_eq!(status, BrokerStatus::FAILED);
+    // Four heartbeat intervals without hearing from the peer
+    assert!(sent.elapsed() >= Duration::from_millis(190));
+
+    drop(producer);
+    peer.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn receivers_stay_available_while_their_peer_sends_heartbeats() -> Result<()> {
+    let handle = ConsumerBuilder::new()
+        .topic("jobs", |_: u32| {})
+        .build()?
+        .spawn(ConsumerConfig {
+            handle_signals: false,
+            heartbeat_interval: Some(Duration::from_millis(50)),
+            ..Default::default()
+        })?;
+    let mut producer = producer(&handle.local_addr().to_string())?;
+    producer.send("jobs", &1u32)?;
+
+    // Twice as long as the silence that fails a receiver
+    let status = wait_while(
+        &producer,
+        BrokerStatus::AVAILABLE,
+        Duration::from_millis(400),
+    );
+    assert_eq!(status, BrokerStatus::AVAILABLE);
+
+    handle.shutdown();
+    handle.join()?;
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    thread::{self, ThreadId},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use pusu::{
+    consumer::{Consumer, ConsumerConfig, MessageContext, consumer},
+    producer::ProducerBuilder,
+};
+use serde::{Deserialize, Serialize};
+
+const WORKERS: usize = 4;
+const KEYS: u32 = 8;
+const MESSAGES: u32 = 200;
+
+#[derive(Serialize, Deserialize)]
+struct Order {
+    account: u32,
+    seq: u32,
+}
+
+// Thread and sequence number of every message handled, per key
+static HANDLED: Mutex<Vec<(String, ThreadId, u32)>> = Mutex::new(Vec::new());
+
+fn order(order: Order) {
+    record(format!("account {}", order.account), order.seq);
+}
+
+fn account(order: &Order) -> u32 {
+    order.account
+}
+
+fn tick(context: &MessageContext) {
+    let tenant = context.header("tenant").unwrap_or_default();
+    let seq = context.header("seq").unwrap_or_default().parse().unwrap();
+    record(format!("tenant {}", tenant), seq);
+}
+
+fn record(key: String, seq: u32) {
+    HANDLED
+        .lock()
+        .unwrap()
+        .push((key, thread::current().id(), seq));
+}
+
+#[consumer]
+struct Router {
+    #[key("account")]
+    #[topic("order")]
+    order: Order,
+    #[key(header = "tenant")]
+    #[topic("tick")]
+    tick: (),
+}
+
+#[test]
+fn messages_with_the_same_key_run_in_order_on_one_worker() -> Result<()> {
+    let handle = Router {}.spawn(ConsumerConfig {
+        addr: "127.0.0.1:0".parse()?,
+        workers: WORKERS,
+        handle_signals: false,
+        ..Default::default()
+    })?;
+    let addr = handle.local_addr().to_string();
+
+    // One producer per topic, so their messages arrive on the same worker before being routed
+    let mut orders = ProducerBuilder::new().receiver("order", 1, &addr).build()?;
+    let mut ticks = ProducerBuilder::new().receiver("tick", 1, &addr).build()?;
+    for seq in 0..MESSAGES {
+        let key = seq % KEYS;
+        orders.send("order", &Order { account: key, seq })?;
+        let headers = vec![
+            ("tenant".to_string(), key.to_string()),
+            ("seq".to_string(), seq.to_string()),
+        ];
+        ticks.send_with_headers("tick", &(), &headers)?;
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while HANDLED.lock().unwrap().len() < 2 * MESSAGES as usize && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    handle.shutdown();
+    handle.join()?;
+
+    let mut keys = HashMap::<String, Vec<(ThreadId, u32)>>::new();
+    for (key, thread, seq) in HANDLED.lock().unwrap().drain(..) {
+        keys.entry(key).or_default().push((thread, seq));
+    }
+    assert_eq!(keys.len(), 2 * KEYS as usize);
+    for (key, handled) in &keys {
+        assert_eq!(handled.len(), (MESSAGES / KEYS) as usize, "{}", key);
+        assert!(
+            handled.iter().all(|(thread, _)| *thread == handled[0].0),
+            "{}",
+            key
+        );
+        assert!(
+            handled.windows(2).all(|pair| pair[0].1 < pair[1].1),
+            "{}",
+            key
+        );
+    }
+    // Keys are spread over the workers, not all run on the worker of the connection
+    let threads = keys.values().map(|handled| handled[0].0);
+    assert!(threads.collect::<HashSet<_>>().len() > 1);
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use pusu::{
+    consumer::{Consumer, ConsumerBuilder, ConsumerConfig, TopicLimits},
+    producer::{ProducerBuilder, ProducerConfig},
+};
+
+fn wait_for(counter: &AtomicUsize, count: usize) -> usize {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while counter.load(Ordering::SeqCst) < count && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    counter.load(Ordering::SeqCst)
+}
+
+#[test]
+fn throttled_topics_do_not_stall_the_others_of_their_worker() -> Result<()> {
+    let slow = Arc::new(AtomicUsize::new(0));
+    let fast = Arc::new(AtomicUsize::new(0));
+    let handle = ConsumerBuilder::new()
+        .topic("slow", {
+            let slow = slow.clone();
+            move |_: u32| {
+                slow.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+        .topic("fast", {
+            let fast = fast.clone();
+            move |_: u32| {
+                fast.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+        .limits(
+            "slow",
+            TopicLimits {
+                per_sec: Some(1),
+                max_concurrency: None,
+            },
+        )
+        .build()?
+        .spawn(ConsumerConfig {
+            workers: 1,
+            worker_queue_size: 8,
+            credit_window: 8,
+            handle_signals: false,
+            drain_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        })?;
+    let addr = handle.local_addr().to_string();
+    let producer = |topic: &str| {
+        ProducerBuilder::new()
+            .config(ProducerConfig {
+                credit_timeout: Duration::from_secs(2),
+                ..Default::default()
+            })
+            .receiver(topic, 1, &addr)
+            .build()
+    };
+
+    // Sends faster than its limit until it runs out of credits
+    let mut slow_producer = producer("slow")?;
+    let flooding = thread::spawn(move || {
+        for i in 0..20u32 {
+            if slow_producer.send("slow", &i).is_err() {
+                break;
+            }
+        }
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let mut fast_producer = producer("fast")?;
+    for i in 0..50u32 {
+        fast_producer.send("fast", &i)?;
+    }
+    assert_eq!(wait_for(&fast, 50), 50);
+    // A burst of one message, the others are still held back
+    assert!(slow.load(Ordering::SeqCst) <= 2);
+
+    handle.shutdown();
+    handle.join()?;
+    flooding.join().unwrap();
+    Ok(())
+}
@@ -0,0 +1,221 @@
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow, bail};
+use pusu::{
+    consumer::{
+        Consumer, ConsumerBuilder, ConsumerConfig, ConsumerHandle, DispatchError, MessageContext,
+        Middleware, consumer,
+    },
+    producer::ProducerBuilder,
+    topic::TopicEnum,
+};
+
+fn config() -> ConsumerConfig {
+    ConsumerConfig {
+        workers: 1,
+        handle_signals: false,
+        ..Default::default()
+    }
+}
+
+// Records each hook it runs. It rejects the "blocked" topic before decoding and the "checked" one
+// after, so with two recorders the hook of the second one never runs for them.
+struct Recorder {
+    name: &'static str,
+    log: &'static Mutex<Vec<String>>,
+}
+
+impl Recorder {
+    fn record(&self, hook: &str) {
+        self.log
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", self.name, hook));
+    }
+}
+
+impl<T: TopicEnum> Middleware<T> for Recorder {
+    fn before_decode(
+        &self,
+        topic: T,
+        _payload: &[u8],
+        _context: &MessageContext,
+    ) -> Result<(), DispatchError> {
+        self.record("before_decode");
+        if topic.name() == "blocked" {
+            return Err(DispatchError::decode(anyhow!("blocked by {}", self.name)));
+        }
+        Ok(())
+    }
+
+    fn after_decode(&self, topic: T, _context: &MessageContext) -> Result<(), DispatchError> {
+        self.record("after_decode");
+        if topic.name() == "checked" {
+            return Err(DispatchError::Handler(anyhow!("checked by {}", self.name)));
+        }
+        Ok(())
+    }
+
+    fn after_handler(
+        &self,
+        _topic: T,
+        result: &Result<(), DispatchError>,
+        _elapsed: Duration,
+        _context: &MessageContext,
+    ) {
+        match result {
+            Ok(()) => self.record("after_handler ok"),
+            Err(err) => self.record(&format!("after_handler {}", err)),
+        }
+    }
+}
+
+fn handled(log: &Mutex<Vec<String>>) {
+    log.lock().unwrap().push("handler".to_string());
+}
+
+// Sends one message of the topic and returns the hooks it went through
+fn send(handle: &ConsumerHandle, log: &Mutex<Vec<String>>, topic: &str) -> Result<Vec<String>> {
+    log.lock().unwrap().clear();
+    let mut producer = ProducerBuilder::new()
+        .receiver(topic, 1, &handle.local_addr().to_string())
+        .build()?;
+    producer.send(topic, &0u32)?;
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let is_done = |log: &[String]| {
+        log.iter()
+            .any(|hook| hook.starts_with("second after_handler"))
+    };
+    while !is_done(&log.lock().unwrap()) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    Ok(log.lock().unwrap().clone())
+}
+
+fn check_hooks(handle: &ConsumerHandle, log: &Mutex<Vec<String>>) -> Result<()> {
+    assert_eq!(
+        send(handle, log, "jobs")?,
+        [
+            "first before_decode",
+            "second before_decode",
+            "first after_decode",
+            "second after_decode",
+            "handler",
+            "first after_handler ok",
+            "second after_handler ok",
+        ]
+    );
+    assert_eq!(
+        send(handle, log, "blocked")?,
+        [
+            "first before_decode",
+            "first after_handler Failed to decode payload: blocked by first",
+            "second after_handler Failed to decode payload: blocked by first",
+        ]
+    );
+    assert_eq!(
+        send(handle, log, "checked")?,
+        [
+            "first before_decode",
+            "second before_decode",
+            "first after_decode",
+            "first after_handler Handler failed: checked by first",
+            "second after_handler Handler failed: checked by first",
+        ]
+    );
+    assert_eq!(
+        send(handle, log, "failing")?,
+        [
+            "first before_decode",
+            "second before_decode",
+            "first after_decode",
+            "second after_decode",
+            "handler",
+            "first after_handler Handler failed: job failed",
+            "second after_handler Handler failed: job failed",
+        ]
+    );
+    Ok(())
+}
+
+static MACRO_LOG: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+fn job(_: u32) {
+    handled(&MACRO_LOG);
+}
+
+fn fail(_: u32) -> Result<()> {
+    handled(&MACRO_LOG);
+    bail!("job failed")
+}
+
+#[consumer]
+struct Guarded {
+    #[middleware]
+    first: Recorder,
+    #[middleware]
+    second: Recorder,
+    #[topic("job")]
+    jobs: u32,
+    #[topic("job")]
+    blocked: u32,
+    #[topic("job")]
+    checked: u32,
+    #[topic("fail")]
+    failing: u32,
+}
+
+#[test]
+fn middleware_fields_run_in_order_around_the_handler() -> Result<()> {
+    let handle = Guarded {
+        first: Recorder {
+            name: "first",
+            log: &MACRO_LOG,
+        },
+        second: Recorder {
+            name: "second",
+            log: &MACRO_LOG,
+        },
+    }
+    .spawn(config())?;
+    check_hooks(&handle, &MACRO_LOG)?;
+
+    handle.shutdown();
+    handle.join()?;
+    Ok(())
+}
+
+static BUILDER_LOG: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+#[test]
+fn builder_middlewares_run_in_order_around_the_handler() -> Result<()> {
+    let job = |_: u32| handled(&BUILDER_LOG);
+    let handle = ConsumerBuilder::new()
+        .topic("jobs", job)
+        .topic("blocked", job)
+        .topic("checked", job)
+        .topic("failing", |_: u32| -> Result<()> {
+            handled(&BUILDER_LOG);
+            bail!("job failed")
+        })
+        .middleware(Recorder {
+            name: "first",
+            log: &BUILDER_LOG,
+        })
+        .middleware(Recorder {
+            name: "second",
+            log: &BUILDER_LOG,
+        })
+        .build()?
+        .spawn(config())?;
+    check_hooks(&handle, &BUILDER_LOG)?;
+
+    handle.shutdown();
+    handle.join()?;
+    Ok(())
+}
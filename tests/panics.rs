@@ -0,0 +1,167 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use pusu::{
+    consumer::{
+        Backoff, Consumer, ConsumerBuilder, ConsumerConfig, ConsumerHandle, DedupKey, DedupStore,
+        MessageContext, RetryPolicy, consumer,
+    },
+    producer::ProducerBuilder,
+};
+
+fn config() -> ConsumerConfig {
+    ConsumerConfig {
+        workers: 1,
+        handle_signals: false,
+        ..Default::default()
+    }
+}
+
+fn send(handle: &ConsumerHandle, topic: &str, jobs: impl IntoIterator<Item = u32>) -> Result<()> {
+    let mut producer = ProducerBuilder::new()
+        .receiver(topic, 1, &handle.local_addr().to_string())
+        .build()?;
+    for job in jobs {
+        producer.send(topic, &job)?;
+    }
+    Ok(())
+}
+
+fn wait_for<V>(handled: &Mutex<Vec<V>>, count: usize) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while handled.lock().unwrap().len() < count && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn panicking_handlers_nack_their_message() -> Result<()> {
+    let handled = Arc::new(Mutex::new(Vec::new()));
+    let handle = ConsumerBuilder::new()
+        .topic("jobs", {
+            let handled = handled.clone();
+            move |job: u32, context: &MessageContext| {
+                if job == 0 && context.attempt == 1 {
+                    panic!("job 0 panicked");
+                }
+                handled.lock().unwrap().push((job, context.attempt));
+            }
+        })
+        .retry(
+            "jobs",
+            RetryPolicy {
+                max_retries: 1,
+                backoff: Backoff::Fixed,
+                base: Duration::from_millis(20),
+            },
+        )
+        .build()?
+        .spawn(config())?;
+    send(&handle, "jobs", 0..3)?;
+    wait_for(&handled, 3);
+
+    handle.shutdown();
+    handle.join()?;
+    // The other messages run while the panicked one waits for its retry
+    assert_eq!(*handled.lock().unwrap(), [(1, 1), (2, 1), (0, 2)]);
+    Ok(())
+}
+
+// Panics on its first lookup, which happens on the worker outside of any handler
+#[derive(Default)]
+struct PanickingStore {
+    panicked: AtomicBool,
+}
+
+impl DedupStore for PanickingStore {
+    fn contains(&self, _key: &DedupKey) -> bool {
+        if !self.panicked.swap(true, Ordering::SeqCst) {
+            panic!("dedup store failed");
+        }
+        false
+    }
+
+    fn insert(&self, _key: DedupKey) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn workers_start_over_after_a_panic_outside_a_handler() -> Result<()> {
+    let handled = Arc::new(Mutex::new(Vec::new()));
+    let store = Arc::new(PanickingStore::default());
+    let handle = ConsumerBuilder::new()
+        .topic("jobs", {
+            let handled = handled.clone();
+            move |job: u32| handled.lock().unwrap().push(job)
+        })
+        .dedup(store.clone())
+        .build()?
+        .spawn(config())?;
+    send(&handle, "jobs", 0..3)?;
+    // The messages queued behind the panicked one are taken without any new message arriving
+    wait_for(&handled, 2);
+    assert!(store.panicked.load(Ordering::SeqCst));
+    assert_eq!(*handled.lock().unwrap(), [1, 2]);
+
+    // The message held by the worker when it panicked is lost, its credit is not
+    send(&handle, "jobs", 3..100)?;
+    wait_for(&handled, 99);
+
+    handle.shutdown();
+    handle.join()?;
+    assert_eq!(*handled.lock().unwrap(), (1..100).collect::<Vec<_>>());
+    Ok(())
+}
+
+static ROUTED: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+fn routed(job: u32) {
+    ROUTED.lock().unwrap().push(job);
+}
+
+fn shard(job: &u32) -> u32 {
+    if *job == 0 {
+        panic!("no shard for job 0");
+    }
+    *job
+}
+
+#[consumer]
+struct Sharded {
+    #[key("shard")]
+    #[topic("routed")]
+    routed: u32,
+}
+
+#[test]
+fn panicking_key_functions_keep_the_message_and_let_the_drain_finish() -> Result<()> {
+    let handle = Sharded {}.spawn(ConsumerConfig {
+        workers: 2,
+        drain_timeout: None,
+        ..config()
+    })?;
+    send(&handle, "routed", 0..4)?;
+    wait_for(&ROUTED, 4);
+
+    // Without a deadline, a routing count left behind by the panic would keep the workers forever
+    handle.shutdown();
+    let stopping = Instant::now();
+    while !handle.is_finished() && stopping.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert!(handle.is_finished());
+    handle.join()?;
+
+    let mut routed = ROUTED.lock().unwrap().clone();
+    routed.sort();
+    assert_eq!(routed, [0, 1, 2, 3]);
+    Ok(())
+}
@@ -0,0 +1,141 @@
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use pusu::{
+    consumer::{Consumer, ConsumerConfig, ConsumerHandle, consumer},
+    producer::ProducerBuilder,
+};
+use serde::{Deserialize, Serialize};
+
+fn config() -> ConsumerConfig {
+    ConsumerConfig {
+        workers: 1,
+        handle_signals: false,
+        ..Default::default()
+    }
+}
+
+fn wait_for<V>(handled: &Mutex<Vec<V>>, count: usize) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while handled.lock().unwrap().len() < count && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserRef<'a> {
+    name: &'a str,
+    tags: Vec<&'a str>,
+}
+
+static USERS: Mutex<Vec<(String, Vec<String>)>> = Mutex::new(Vec::new());
+
+fn user(user: UserRef<'_>) {
+    let tags = user.tags.iter().map(|tag| tag.to_string()).collect();
+    USERS.lock().unwrap().push((user.name.to_string(), tags));
+}
+
+#[consumer]
+struct Users {
+    #[topic("user")]
+    users: UserRef<'a>,
+}
+
+fn send_users(handle: &ConsumerHandle) -> Result<()> {
+    let mut producer = ProducerBuilder::new()
+        .receiver("users", 1, &handle.local_addr().to_string())
+        .build()?;
+    for name in ["ada", "grace"] {
+        producer.send(
+            "users",
+            &UserRef {
+                name,
+                tags: vec!["admin", name],
+            },
+        )?;
+    }
+    Ok(())
+}
+
+#[test]
+fn borrowed_payloads_are_decoded_from_the_frame() -> Result<()> {
+    let handle = Users {}.spawn(config())?;
+    send_users(&handle)?;
+    wait_for(&USERS, 2);
+
+    handle.shutdown();
+    handle.join()?;
+    let users = USERS.lock().unwrap().clone();
+    let tags = |name: &str| vec!["admin".to_string(), name.to_string()];
+    assert_eq!(
+        users,
+        [
+            ("ada".to_string(), tags("ada")),
+            ("grace".to_string(), tags("grace"))
+        ]
+    );
+    Ok(())
+}
+
+#[cfg(feature = "rkyv")]
+mod archived {
+    use pusu::producer::producer;
+
+    use super::*;
+
+    #[derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)]
+    struct Big {
+        id: u32,
+        data: Vec<u8>,
+    }
+
+    static BIG: Mutex<Vec<(u32, usize, u8)>> = Mutex::new(Vec::new());
+
+    fn big(big: &rkyv::Archived<Big>) {
+        let last = big.data.last().copied().unwrap_or_default();
+        BIG.lock()
+            .unwrap()
+            .push((big.id.to_native(), big.data.len(), last));
+    }
+
+    #[consumer]
+    struct BigConsumer {
+        #[archived]
+        #[topic("big")]
+        bigs: Big,
+    }
+
+    #[producer]
+    struct BigProducer {
+        #[archived]
+        bigs: Big,
+    }
+
+    #[test]
+    fn archived_topics_are_accessed_in_place() -> Result<()> {
+        let handle = BigConsumer {}.spawn(config())?;
+        let mut producer = BigProducer::new();
+        producer
+            .bigs
+            .add_receiver(1, &handle.local_addr().to_string());
+        for id in 0..3 {
+            producer.produce_bigs(Big {
+                id,
+                data: vec![id as u8; 1000 + id as usize],
+            })?;
+        }
+        wait_for(&BIG, 3);
+
+        handle.shutdown();
+        handle.join()?;
+        assert_eq!(
+            *BIG.lock().unwrap(),
+            [(0, 1000, 0), (1, 1001, 1), (2, 1002, 2)]
+        );
+        Ok(())
+    }
+}
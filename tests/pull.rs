@@ -0,0 +1,154 @@
+use std::{
+    net::TcpListener,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use pusu::{
+    broker::broker,
+    consumer::{Consumer, PullConfig, Shutdown, consumer},
+};
+
+// Sizes of the blobs handled, and of the raw messages given to the fallback handler
+static HANDLED: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+static SKIPPED: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+#[broker]
+struct Store {
+    blob: Vec<u8>,
+}
+
+fn blob(blob: Vec<u8>) {
+    HANDLED.lock().unwrap().push(blob.len());
+}
+
+fn skipped(_topic: &str, message: &[u8]) {
+    SKIPPED.lock().unwrap().push(message.len());
+}
+
+#[consumer]
+#[fallback("skipped")]
+struct Reader {
+    #[topic("blob")]
+    blob: Vec<u8>,
+}
+
+#[test]
+fn unreadable_messages_are_skipped_and_committed() -> Result<()> {
+    const MAX_FRAME_SIZE: usize = 64 * 1024;
+    let store = Arc::new(Mutex::new(Store::new()));
+    {
+        let mut store = store.lock().unwrap();
+        // Two of the medium ones fit in a frame, the large one never does
+        for size in [10, 30_000, 30_000, 30_000, 200_000, 20, 40] {
+            store.blob.publish(vec![7; size])?;
+        }
+        // The last one fails its checksum
+        let last = store.blob.queue.back_mut().unwrap();
+        last.payload[20] ^= 0xff;
+    }
+
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    thread::spawn({
+        let store = store.clone();
+        move || pusu::broker::serve(store, addr)
+    });
+
+    let shutdown = Arc::new(Shutdown::default());
+    let puller = thread::spawn({
+        let shutdown = shutdown.clone();
+        move || {
+            Reader {}.pull_until(
+                PullConfig {
+                    batch_size: 4,
+                    poll_interval: Duration::from_millis(10),
+                    max_frame_size: MAX_FRAME_SIZE,
+                    handle_signals: false,
+                    ..PullConfig::new(&addr.to_string(), "readers")
+                },
+                shutdown,
+            )
+        }
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while SKIPPED.lock().unwrap().len() < 2 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    shutdown.request();
+    puller.join().unwrap()?;
+
+    assert_eq!(*HANDLED.lock().unwrap(), [10, 30_000, 30_000, 30_000, 20]);
+    let skipped = SKIPPED.lock().unwrap();
+    assert_eq!(skipped.len(), 2);
+    // The large message arrives empty, the corrupt one whole
+    assert_eq!(skipped[0], 0);
+    assert!(skipped[1] > 40);
+    // Committed past both of them when stopping
+    assert_eq!(
+        store.lock().unwrap().blob.committed.get("readers"),
+        Some(&7)
+    );
+    Ok(())
+}
+
+static TICKS: Mutex<Vec<Instant>> = Mutex::new(Vec::new());
+
+#[broker]
+struct Clock {
+    tick: u32,
+}
+
+fn tick(_: u32) {
+    TICKS.lock().unwrap().push(Instant::now());
+}
+
+#[consumer]
+struct Ticker {
+    #[rate_limit(per_sec = 20)]
+    #[topic("tick")]
+    tick: u32,
+}
+
+#[test]
+fn pulled_messages_wait_for_the_rate_limit() -> Result<()> {
+    let clock = Arc::new(Mutex::new(Clock::new()));
+    for i in 0..30 {
+        clock.lock().unwrap().tick.publish(i)?;
+    }
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    thread::spawn({
+        let clock = clock.clone();
+        move || pusu::broker::serve(clock, addr)
+    });
+
+    let shutdown = Arc::new(Shutdown::default());
+    let puller = thread::spawn({
+        let shutdown = shutdown.clone();
+        move || {
+            Ticker {}.pull_until(
+                PullConfig {
+                    poll_interval: Duration::from_millis(10),
+                    handle_signals: false,
+                    ..PullConfig::new(&addr.to_string(), "tickers")
+                },
+                shutdown,
+            )
+        }
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while TICKS.lock().unwrap().len() < 30 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    shutdown.request();
+    puller.join().unwrap()?;
+
+    // A second's worth at once, then one every 50ms
+    let ticks = TICKS.lock().unwrap();
+    assert_eq!(ticks.len(), 30);
+    assert!(ticks[29] - ticks[0] >= Duration::from_millis(450));
+    Ok(())
+}